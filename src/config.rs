@@ -1,26 +1,148 @@
+use crate::roles::Role;
 use clap::Parser;
 use config::{Config, ConfigError, File};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct DatabaseConfig {
     pub connection_string: String,
     pub pool_size: usize,
+    // Admin override letting query endpoints run statements that modify data
+    #[serde(default)]
+    pub allow_writes: bool,
+    // Memory kept for repeated /api/query results, 0 turns the cache off
+    #[serde(default = "default_result_cache_mb")]
+    pub result_cache_mb: u64,
+    // Queries running longer than this are interrupted, 0 lets them run forever
+    #[serde(default = "default_query_timeout_secs")]
+    pub query_timeout_secs: u64,
+    // Most rows a single query response carries, 0 removes the cap
+    #[serde(default = "default_max_result_rows")]
+    pub max_result_rows: usize,
+}
+
+fn default_result_cache_mb() -> u64 {
+    256
+}
+
+fn default_query_timeout_secs() -> u64 {
+    300
+}
+
+fn default_max_result_rows() -> usize {
+    1_000_000
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct WebConfig {
     pub host: String,
     pub port: u16,
+    // Largest upload accepted, other request bodies keep a small fixed limit
+    #[serde(default = "default_max_upload_mb")]
+    pub max_upload_mb: u64,
+}
+
+fn default_max_upload_mb() -> u64 {
+    250
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "local-llm"), allow(dead_code))] // The local model settings go unread without the local backend
 pub struct LlmConfig {
-    pub backend: String, // "local", "remote", or "ollama"
+    pub backend: String, // "local", "remote", "ollama", or "anthropic"
     pub model: String,   // Model name
     pub api_key: Option<String>,
     pub api_url: Option<String>,
+    // Settings for the in-process "local" backend
+    pub model_path: Option<String>,     // GGUF file, defaults to `model`
+    pub tokenizer_path: Option<String>, // tokenizer.json, defaults to one next to the model
+    pub context_length: Option<usize>,
+    pub threads: Option<usize>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u64>,
+    pub max_attempts: Option<usize>, // Generate/validate cycles before an NL query gives up
+    pub cache_ttl_hours: Option<u64>, // How long generated SQL is reused, 0 turns the cache off
+    pub structured_output: Option<bool>, // Ask the "remote" backend for JSON, off for servers that ignore response formats
+    pub timeout_secs: Option<u64>, // How long to wait for this provider before trying the next
+    pub prompts_dir: Option<String>, // Directory of <name>.jinja prompt templates, see README
+    // Providers tried in order when the one above fails, their own max_attempts, cache and fallbacks are ignored
+    #[serde(default)]
+    pub fallbacks: Vec<LlmConfig>,
+}
+
+impl LlmConfig {
+    /// Every configured provider in the order they are tried, each without its fallbacks
+    pub fn providers(&self) -> Vec<LlmConfig> {
+        std::iter::once(self)
+            .chain(&self.fallbacks)
+            .map(|provider| LlmConfig {
+                fallbacks: Vec::new(),
+                ..provider.clone()
+            })
+            .collect()
+    }
+
+    /// The configured providers reordered so the first using `backend` comes first, running `model`
+    pub fn with_active(&self, backend: &str, model: &str) -> Option<LlmConfig> {
+        let mut providers = self.providers();
+        let position = providers.iter().position(|provider| provider.backend == backend)?;
+
+        let mut active = providers.remove(position);
+        active.model = model.to_string();
+        active.max_attempts = self.max_attempts;
+        active.cache_ttl_hours = self.cache_ttl_hours;
+        active.fallbacks = providers;
+        Some(active)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct IngestConfig {
+    // Expand nested JSON objects into one column per field
+    #[serde(default)]
+    pub flatten_json: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AuthConfig {
+    // Require an API key on every /api route
+    #[serde(default)]
+    pub enabled: bool,
+    // Admin key stored on first start, one is generated into the data directory if this is unset
+    pub bootstrap_key: Option<String>,
+    // Browser sign-in through an OpenID Connect provider, API keys keep working alongside it
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // Must point at /auth/callback on this server and be registered with the provider
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_session_hours")]
+    pub session_hours: u64,
+    // Roles every signed-in user gets, by subject ("*" for all subjects)
+    #[serde(default)]
+    pub default_roles: HashMap<String, Role>,
+    // Extra roles keyed by the user's email or one of their groups
+    #[serde(default)]
+    pub role_mappings: HashMap<String, HashMap<String, Role>>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["email".to_string(), "profile".to_string()]
+}
+
+fn default_session_hours() -> u64 {
+    8
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,6 +150,10 @@ pub struct AppConfig {
     pub database: DatabaseConfig,
     pub web: WebConfig,
     pub llm: LlmConfig,
+    #[serde(default)]
+    pub ingest: IngestConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
     pub data_dir: String,
 }
 
@@ -101,17 +227,38 @@ impl Default for AppConfig {
             database: DatabaseConfig {
                 connection_string: "nl-cube.db".to_string(),
                 pool_size: 5,
+                allow_writes: false,
+                result_cache_mb: default_result_cache_mb(),
+                query_timeout_secs: default_query_timeout_secs(),
+                max_result_rows: default_max_result_rows(),
             },
             web: WebConfig {
                 host: "127.0.0.1".to_string(),
                 port: 3000,
+                max_upload_mb: default_max_upload_mb(),
             },
             llm: LlmConfig {
                 backend: "local".to_string(),
                 model: "sqlcoder".to_string(),
                 api_key: None,
                 api_url: None,
+                model_path: None,
+                tokenizer_path: None,
+                context_length: None,
+                threads: None,
+                temperature: None,
+                top_p: None,
+                max_tokens: None,
+                seed: None,
+                max_attempts: None,
+                cache_ttl_hours: None,
+                structured_output: None,
+                timeout_secs: None,
+                prompts_dir: None,
+                fallbacks: Vec::new(),
             },
+            ingest: IngestConfig::default(),
+            auth: AuthConfig::default(),
             data_dir: "data".to_string(),
         }
     }
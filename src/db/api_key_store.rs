@@ -0,0 +1,182 @@
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::roles::Role;
+use duckdb::{params, Row};
+use r2d2::Pool;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::info;
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const KEY_COLUMNS: &str = "id, name, key_prefix, roles, created_at, last_used_at";
+
+// How far behind a key's last_used_at may fall before it is written again
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+/// A stored API key, the key itself is only ever shown when it is created
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    // First characters of the key so users can tell their keys apart
+    pub key_prefix: String,
+    pub roles: HashMap<String, Role>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+/// Persists hashed API keys in the main NL-Cube database
+pub struct ApiKeyStore {
+    pool: Pool<DuckDBConnectionManager>,
+}
+
+impl ApiKeyStore {
+    pub fn new(pool: Pool<DuckDBConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    /// Create the api_keys table if this is a fresh database
+    pub fn init(&self) -> StoreResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                id VARCHAR PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                key_hash VARCHAR NOT NULL UNIQUE,
+                key_prefix VARCHAR NOT NULL,
+                roles VARCHAR NOT NULL,
+                created_at VARCHAR NOT NULL,
+                last_used_at VARCHAR
+            );",
+        )?;
+
+        info!("API key store initialized");
+        Ok(())
+    }
+
+    pub fn list(&self) -> StoreResult<Vec<ApiKey>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys ORDER BY created_at",
+            KEY_COLUMNS
+        ))?;
+
+        let keys = stmt
+            .query_map([], key_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(keys)
+    }
+
+    /// Store a new key, returning it along with the secret to hand to the user
+    pub fn create(&self, name: &str, roles: HashMap<String, Role>) -> StoreResult<(ApiKey, String)> {
+        let secret = format!(
+            "nlc_{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        let key = self.insert(name, &secret, roles)?;
+        Ok((key, secret))
+    }
+
+    /// Store a key whose secret was chosen elsewhere, such as the configured bootstrap key
+    pub fn insert(&self, name: &str, secret: &str, roles: HashMap<String, Role>) -> StoreResult<ApiKey> {
+        let conn = self.pool.get()?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let key_prefix: String = secret.chars().take(8).collect();
+        let now = chrono::Utc::now().to_rfc3339();
+
+        conn.execute(
+            "INSERT INTO api_keys (id, name, key_hash, key_prefix, roles, created_at, last_used_at)
+             VALUES (?, ?, ?, ?, ?, ?, NULL)",
+            params![
+                id,
+                name,
+                hash_key(secret),
+                key_prefix,
+                serde_json::to_string(&roles)?,
+                now
+            ],
+        )?;
+
+        info!("Created API key {} ({})", name, id);
+
+        Ok(ApiKey {
+            id,
+            name: name.to_string(),
+            key_prefix,
+            roles,
+            created_at: now,
+            last_used_at: None,
+        })
+    }
+
+    /// Look up the key matching `secret` and record that it was used, to within a minute
+    pub fn authenticate(&self, secret: &str) -> StoreResult<Option<ApiKey>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_keys WHERE key_hash = ?",
+            KEY_COLUMNS
+        ))?;
+
+        let mut rows = stmt.query_map([hash_key(secret)], key_from_row)?;
+        let Some(mut key) = rows.next().transpose()? else {
+            return Ok(None);
+        };
+
+        // Every request authenticates, so only write when the recorded time has gone stale
+        let now = chrono::Utc::now();
+        let recently_used = key
+            .last_used_at
+            .as_deref()
+            .and_then(|used| chrono::DateTime::parse_from_rfc3339(used).ok())
+            .is_some_and(|used| now.signed_duration_since(used).num_seconds() < LAST_USED_RESOLUTION_SECS);
+        if !recently_used {
+            let now = now.to_rfc3339();
+            conn.execute(
+                "UPDATE api_keys SET last_used_at = ? WHERE id = ?",
+                params![now, key.id],
+            )?;
+            key.last_used_at = Some(now);
+        }
+
+        Ok(Some(key))
+    }
+
+    /// Returns false if there was no key with this id
+    pub fn delete(&self, id: &str) -> StoreResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute("DELETE FROM api_keys WHERE id = ?", [id])?;
+
+        if deleted > 0 {
+            info!("Deleted API key {}", id);
+        }
+        Ok(deleted > 0)
+    }
+
+    pub fn count(&self) -> StoreResult<usize> {
+        let conn = self.pool.get()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM api_keys", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+// Keys are long random strings, so a plain digest is enough to keep them out of the database
+fn hash_key(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+fn key_from_row(row: &Row<'_>) -> duckdb::Result<ApiKey> {
+    let roles: String = row.get(3)?;
+
+    Ok(ApiKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        key_prefix: row.get(2)?,
+        // A key whose roles can't be parsed gets no access rather than failing every request
+        roles: serde_json::from_str(&roles).unwrap_or_default(),
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+    })
+}
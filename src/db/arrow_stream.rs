@@ -0,0 +1,406 @@
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use axum::body::{Body, Bytes};
+use duckdb::{Connection, Statement};
+use futures_util::stream;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+
+use crate::db::query_guard::single_query;
+
+// Response bodies are sent in pieces of about this size
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// Chunks waiting to be sent before the query thread has to wait for the client
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+/// The rows of a query result a response carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResultWindow {
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl ResultWindow {
+    /// Window for a requested page, never larger than `max_rows` unless that is 0
+    pub fn new(offset: Option<usize>, limit: Option<usize>, max_rows: usize) -> Self {
+        let limit = match (limit, max_rows) {
+            (limit, 0) => limit,
+            (Some(limit), max_rows) => Some(limit.min(max_rows)),
+            (None, max_rows) => Some(max_rows),
+        };
+
+        Self {
+            offset: offset.unwrap_or(0),
+            limit,
+        }
+    }
+
+    /// `sql` narrowed to this window plus one row, so DuckDB only produces the rows needed and
+    /// the extra one shows whether there were more. None if `sql` isn't a single query.
+    pub fn paged_sql(&self, sql: &str) -> Option<String> {
+        if self.offset == 0 && self.limit.is_none() {
+            return None;
+        }
+
+        let statement = single_query(sql)?;
+        let limit = self
+            .limit
+            .map(|limit| format!(" LIMIT {}", limit.saturating_add(1)))
+            .unwrap_or_default();
+        Some(format!(
+            "SELECT * FROM ({}) AS nl_cube_page{} OFFSET {}",
+            statement, limit, self.offset
+        ))
+    }
+}
+
+impl fmt::Display for ResultWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            Some(limit) => write!(f, "offset {} limit {}", self.offset, limit),
+            None => write!(f, "offset {}", self.offset),
+        }
+    }
+}
+
+/// A result column and its Arrow type
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub data_type: String,
+}
+
+/// What the client is told about a result before its rows arrive
+#[derive(Debug, Clone)]
+pub struct ResultMeta {
+    pub columns: Vec<ResultColumn>,
+    // Rows in this response, not in the whole result. None when the window has no limit,
+    // the rows are then sent as they are read and only counted once all have gone
+    pub row_count: Option<usize>,
+    // Rows in the whole result, only known when this page reaches its end
+    pub total_rows: Option<usize>,
+    // The query produced rows past the end of the window
+    pub truncated: bool,
+}
+
+/// A query prepared to produce one window of its result. A single query is narrowed to the
+/// window and streamed, anything else (DESCRIBE, EXPLAIN, allowed writes) has a small result
+/// that is read whole and windowed while it is written.
+pub struct WindowedQuery<'c> {
+    stmt: Statement<'c>,
+    // Known before the query runs only when it is streamed
+    schema: Option<SchemaRef>,
+    window: ResultWindow,
+    // Rows to drop from the front when the window couldn't be pushed into the SQL
+    skip: usize,
+}
+
+impl<'c> WindowedQuery<'c> {
+    pub fn prepare(conn: &'c Connection, sql: &str, window: ResultWindow) -> duckdb::Result<Self> {
+        let Some(statement) = single_query(sql) else {
+            return Ok(Self {
+                stmt: conn.prepare(sql)?,
+                schema: None,
+                window,
+                skip: window.offset,
+            });
+        };
+
+        // Streaming needs the schema up front, an empty run of the query gives it without reading any rows
+        let schema = conn
+            .prepare(&format!("SELECT * FROM ({}) AS nl_cube_schema LIMIT 0", statement))?
+            .query_arrow([])?
+            .get_schema();
+
+        // Pushing the window into the SQL saves DuckDB producing rows that would be thrown away
+        let paged_sql = window.paged_sql(&statement).unwrap_or(statement);
+        Ok(Self {
+            stmt: conn.prepare(&paged_sql)?,
+            schema: Some(schema),
+            window,
+            skip: 0,
+        })
+    }
+
+    /// Run the query and read up to one row past the end of the window, so the client can be
+    /// told how many rows it holds and whether there are more before any are sent. Rows of a
+    /// window without a limit are left to be read while they are written.
+    pub fn execute(&mut self) -> duckdb::Result<WindowedResult<'_>> {
+        let (schema, mut batches): (SchemaRef, Box<dyn Iterator<Item = RecordBatch> + '_>) = match &self.schema {
+            Some(schema) => (schema.clone(), Box::new(self.stmt.stream_arrow([], schema.clone())?)),
+            None => {
+                let arrow = self.stmt.query_arrow([])?;
+                (arrow.get_schema(), Box::new(arrow))
+            }
+        };
+
+        let mut skip = self.skip;
+        let mut page = Vec::new();
+        let mut read = 0;
+        if let Some(limit) = self.window.limit {
+            while read <= limit {
+                let Some(batch) = next_after(&mut batches, &mut skip) else {
+                    break;
+                };
+                read += batch.num_rows();
+                page.push(batch);
+            }
+        }
+
+        let row_count = self.window.limit.map(|limit| read.min(limit));
+        let truncated = row_count.is_some_and(|rows| read > rows);
+
+        // The total is only known when this page runs to the end of a result that isn't empty here
+        let total_rows = match row_count {
+            Some(rows) if !truncated && (rows > 0 || self.window.offset == 0) => Some(self.window.offset + rows),
+            _ => None,
+        };
+
+        let columns = schema
+            .fields()
+            .iter()
+            .map(|field| ResultColumn {
+                name: field.name().clone(),
+                data_type: field.data_type().to_string(),
+            })
+            .collect();
+
+        Ok(WindowedResult {
+            schema,
+            batches,
+            page,
+            skip,
+            meta: ResultMeta {
+                columns,
+                row_count,
+                total_rows,
+                truncated,
+            },
+        })
+    }
+}
+
+// The next batch with the first `skip` rows of the result dropped
+fn next_after(batches: &mut dyn Iterator<Item = RecordBatch>, skip: &mut usize) -> Option<RecordBatch> {
+    loop {
+        let batch = batches.next()?;
+        let rows = batch.num_rows();
+        if *skip >= rows {
+            *skip -= rows;
+            continue;
+        }
+
+        let batch = batch.slice(*skip, rows - *skip);
+        *skip = 0;
+        return Some(batch);
+    }
+}
+
+/// An executed query's window, written out as DuckDB hands over its rows
+pub struct WindowedResult<'a> {
+    schema: SchemaRef,
+    batches: Box<dyn Iterator<Item = RecordBatch> + 'a>,
+    // The rows of a window with a limit, read before any were sent
+    page: Vec<RecordBatch>,
+    skip: usize,
+    pub meta: ResultMeta,
+}
+
+impl WindowedResult<'_> {
+    /// Write the window as an Arrow IPC stream, returning how many rows it held
+    pub fn write_to<W: Write>(mut self, out: W) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let mut writer = StreamWriter::try_new(out, &self.schema)?;
+
+        let mut written = 0;
+        if let Some(row_count) = self.meta.row_count {
+            for batch in &self.page {
+                let take = batch.num_rows().min(row_count - written);
+                if take == 0 {
+                    break;
+                }
+                writer.write(&batch.slice(0, take))?;
+                written += take;
+            }
+        } else {
+            while let Some(batch) = next_after(&mut self.batches, &mut self.skip) {
+                writer.write(&batch)?;
+                written += batch.num_rows();
+            }
+        }
+
+        writer.finish()?;
+        Ok(written)
+    }
+}
+
+/// Passes what is written to it on as response body chunks,
+/// keeping a copy of the whole body when it is small enough to cache
+pub struct ChunkSender {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    pending: Vec<u8>,
+    copy: Option<Vec<u8>>,
+    copy_limit: usize,
+}
+
+/// A writer and the response body everything written to it ends up in
+pub fn body_channel(copy_limit: Option<usize>) -> (ChunkSender, Body) {
+    let (tx, rx) = mpsc::channel(CHUNKS_IN_FLIGHT);
+
+    let sender = ChunkSender {
+        tx,
+        pending: Vec::with_capacity(CHUNK_BYTES),
+        copy: copy_limit.map(|_| Vec::new()),
+        copy_limit: copy_limit.unwrap_or(0),
+    };
+    let body = Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    (sender, body)
+}
+
+impl ChunkSender {
+    /// Send anything still buffered, returning the copy of the body if one was kept
+    pub fn finish(mut self) -> io::Result<Option<Bytes>> {
+        self.flush()?;
+        Ok(self.copy.take().map(Bytes::from))
+    }
+
+    /// End the body with an error so the client doesn't mistake a partial result for a whole one
+    pub fn fail(self, message: String) {
+        let _ = self.tx.blocking_send(Err(io::Error::other(message)));
+    }
+
+    fn send_pending(&mut self) -> io::Result<()> {
+        let chunk = Bytes::from(std::mem::replace(&mut self.pending, Vec::with_capacity(CHUNK_BYTES)));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client stopped reading the result"))
+    }
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        let copy_fits = self
+            .copy
+            .as_ref()
+            .is_some_and(|copy| copy.len() + buf.len() <= self.copy_limit);
+        match self.copy.as_mut() {
+            Some(copy) if copy_fits => copy.extend_from_slice(buf),
+            _ => self.copy = None,
+        }
+
+        if self.pending.len() >= CHUNK_BYTES {
+            self.send_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.send_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::ipc::reader::StreamReader;
+    use duckdb::Connection;
+
+    #[test]
+    fn caps_windows_at_max_rows() {
+        assert_eq!(ResultWindow::new(None, None, 100), ResultWindow { offset: 0, limit: Some(100) });
+        assert_eq!(ResultWindow::new(Some(5), Some(500), 100), ResultWindow { offset: 5, limit: Some(100) });
+        assert_eq!(ResultWindow::new(None, Some(10), 100), ResultWindow { offset: 0, limit: Some(10) });
+        assert_eq!(ResultWindow::new(Some(5), None, 0), ResultWindow { offset: 5, limit: None });
+    }
+
+    #[test]
+    fn pages_single_queries_with_one_extra_row() {
+        let window = ResultWindow::new(Some(20), Some(10), 0);
+        assert_eq!(
+            window.paged_sql("SELECT * FROM orders ORDER BY id;").as_deref(),
+            Some("SELECT * FROM (SELECT * FROM orders ORDER BY id) AS nl_cube_page LIMIT 11 OFFSET 20")
+        );
+        assert_eq!(
+            ResultWindow::new(Some(20), None, 0).paged_sql("SELECT 1").as_deref(),
+            Some("SELECT * FROM (SELECT 1) AS nl_cube_page OFFSET 20")
+        );
+
+        // Nothing to page, or nothing that can be wrapped in a subquery
+        assert_eq!(ResultWindow::new(None, None, 0).paged_sql("SELECT 1"), None);
+        assert_eq!(window.paged_sql("SELECT 1; SELECT 2"), None);
+        assert_eq!(window.paged_sql("DESCRIBE orders"), None);
+        assert_eq!(window.paged_sql("SHOW TABLES"), None);
+    }
+
+    // Runs `sql` for `window`, returning what the client is told and how many rows it gets
+    fn run(sql: &str, window: ResultWindow) -> (ResultMeta, usize) {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut query = WindowedQuery::prepare(&conn, sql, window).unwrap();
+        let result = query.execute().unwrap();
+        let meta = result.meta.clone();
+
+        let mut body = Vec::new();
+        let written = result.write_to(&mut body).unwrap();
+
+        let rows = StreamReader::try_new(body.as_slice(), None)
+            .unwrap()
+            .map(|batch| batch.unwrap().num_rows())
+            .sum();
+        assert_eq!(written, rows);
+        (meta, rows)
+    }
+
+    #[test]
+    fn writes_only_the_window() {
+        let sql = "SELECT range AS n FROM range(10)";
+
+        let (meta, rows) = run(sql, ResultWindow::new(Some(2), Some(3), 0));
+        assert_eq!((meta.row_count, rows, meta.truncated, meta.total_rows), (Some(3), 3, true, None));
+        assert_eq!(meta.columns[0].name, "n");
+
+        // The last page shows the total
+        let (meta, rows) = run(sql, ResultWindow::new(Some(8), Some(3), 0));
+        assert_eq!((meta.row_count, rows, meta.truncated, meta.total_rows), (Some(2), 2, false, Some(10)));
+
+        // Past the end nothing shows it
+        let (meta, rows) = run(sql, ResultWindow::new(Some(20), Some(3), 0));
+        assert_eq!((meta.row_count, rows, meta.truncated, meta.total_rows), (Some(0), 0, false, None));
+    }
+
+    #[test]
+    fn reads_pages_spanning_several_batches() {
+        let (meta, rows) = run("SELECT range AS n FROM range(5000)", ResultWindow::new(None, Some(3000), 0));
+        assert_eq!((meta.row_count, rows, meta.truncated), (Some(3000), 3000, true));
+    }
+
+    #[test]
+    fn streams_windows_without_a_limit_uncounted() {
+        let (meta, rows) = run("SELECT range AS n FROM range(5000)", ResultWindow::new(Some(10), None, 0));
+        assert_eq!((meta.row_count, rows, meta.truncated, meta.total_rows), (None, 4990, false, None));
+        assert_eq!(meta.columns[0].name, "n");
+    }
+
+    #[test]
+    fn skips_rows_itself_when_the_query_is_not_paged() {
+        // One row per described column
+        let sql = "DESCRIBE SELECT 1 AS a, 2 AS b, 3 AS c, 4 AS d";
+
+        let (meta, rows) = run(sql, ResultWindow::new(Some(1), Some(2), 0));
+        assert_eq!((meta.row_count, rows, meta.truncated, meta.total_rows), (Some(2), 2, true, None));
+
+        let (meta, rows) = run(sql, ResultWindow::new(Some(3), None, 0));
+        assert_eq!((meta.row_count, rows, meta.truncated), (None, 1, false));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::db::query_guard::single_query;
+use arrow::datatypes::SchemaRef;
+use arrow::ipc::writer::StreamWriter;
+use arrow::json::writer::JsonFormat;
+use arrow::record_batch::RecordBatch;
+use duckdb::Connection;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use tracing::info;
+
+/// File formats query results can be exported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Ndjson,
+    Parquet,
+    Arrow,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            "parquet" => Some(Self::Parquet),
+            "arrow" | "ipc" => Some(Self::Arrow),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Parquet => "parquet",
+            Self::Arrow => "arrow",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Parquet => "application/vnd.apache.parquet",
+            Self::Arrow => "application/vnd.apache.arrow.stream",
+        }
+    }
+}
+
+/// Write the results of `sql` to `dest` batch by batch, without holding the full result set in memory.
+/// `sql` must be a single SELECT, it only ever runs as a prepared query and is never spliced into a COPY.
+pub fn export_query(
+    conn: &Connection,
+    sql: &str,
+    format: ExportFormat,
+    dest: &Path,
+) -> ExportResult<u64> {
+    let statement = single_query(sql).ok_or("Only a single SELECT query can be exported")?;
+
+    // Streaming needs the schema up front, an empty run of the query gives it without reading any rows
+    let schema = conn
+        .prepare(&format!("SELECT * FROM ({}) AS nl_cube_export LIMIT 0", statement))?
+        .query_arrow([])?
+        .get_schema();
+
+    let mut stmt = conn.prepare(&statement)?;
+    let batches = stmt.stream_arrow([], schema.clone())?;
+
+    let file = BufWriter::new(File::create(dest)?);
+    let mut writer = batch_writer(format, file, schema)?;
+
+    let mut rows = 0u64;
+    for batch in batches {
+        rows += batch.num_rows() as u64;
+        writer.write(&batch)?;
+    }
+    writer.finish()?;
+
+    info!("Exported {} rows as {} to {}", rows, format.extension(), dest.display());
+    Ok(rows)
+}
+
+type ExportResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// The Arrow writers for each format, behind one interface
+trait BatchWriter {
+    fn write(&mut self, batch: &RecordBatch) -> ExportResult<()>;
+    fn finish(self: Box<Self>) -> ExportResult<()>;
+}
+
+impl<W: Write> BatchWriter for arrow::csv::Writer<W> {
+    fn write(&mut self, batch: &RecordBatch) -> ExportResult<()> {
+        Ok(arrow::csv::Writer::write(self, batch)?)
+    }
+
+    fn finish(self: Box<Self>) -> ExportResult<()> {
+        (*self).into_inner().flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write, F: JsonFormat> BatchWriter for arrow::json::Writer<W, F> {
+    fn write(&mut self, batch: &RecordBatch) -> ExportResult<()> {
+        Ok(arrow::json::Writer::write(self, batch)?)
+    }
+
+    fn finish(mut self: Box<Self>) -> ExportResult<()> {
+        arrow::json::Writer::finish(&mut self)?;
+        (*self).into_inner().flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> BatchWriter for ArrowWriter<W> {
+    fn write(&mut self, batch: &RecordBatch) -> ExportResult<()> {
+        Ok(ArrowWriter::write(self, batch)?)
+    }
+
+    fn finish(self: Box<Self>) -> ExportResult<()> {
+        self.close()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> BatchWriter for StreamWriter<W> {
+    fn write(&mut self, batch: &RecordBatch) -> ExportResult<()> {
+        Ok(StreamWriter::write(self, batch)?)
+    }
+
+    fn finish(mut self: Box<Self>) -> ExportResult<()> {
+        StreamWriter::finish(&mut self)?;
+        Ok(())
+    }
+}
+
+fn batch_writer(
+    format: ExportFormat,
+    file: BufWriter<File>,
+    schema: SchemaRef,
+) -> ExportResult<Box<dyn BatchWriter>> {
+    Ok(match format {
+        ExportFormat::Csv => Box::new(arrow::csv::WriterBuilder::new().with_header(true).build(file)),
+        ExportFormat::Json => Box::new(arrow::json::ArrayWriter::new(file)),
+        ExportFormat::Ndjson => Box::new(arrow::json::LineDelimitedWriter::new(file)),
+        ExportFormat::Parquet => Box::new(ArrowWriter::try_new(file, schema, None)?),
+        ExportFormat::Arrow => Box::new(StreamWriter::try_new(file, &schema)?),
+    })
+}
@@ -0,0 +1,201 @@
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::llm::models::{QueryHistoryItem, QueryKind};
+use duckdb::{params, Row};
+use r2d2::Pool;
+use tracing::info;
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const HISTORY_COLUMNS: &str =
+    "id, kind, user_name, subject, question, sql, execution_time_ms, row_count, error, created_at";
+
+/// Narrows a history listing, `None` fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    // Matched case-insensitively against the question and the SQL
+    pub search: Option<String>,
+    pub subject: Option<String>,
+    pub user: Option<String>,
+    // Only entries for these subjects, used to hide subjects the caller can't query
+    pub visible_subjects: Option<Vec<String>>,
+}
+
+/// Persists every query run through the API in the main NL-Cube database
+pub struct HistoryStore {
+    pool: Pool<DuckDBConnectionManager>,
+}
+
+impl HistoryStore {
+    pub fn new(pool: Pool<DuckDBConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    /// Create the query_history table if this is a fresh database
+    pub fn init(&self) -> StoreResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS query_history (
+                id VARCHAR PRIMARY KEY,
+                kind VARCHAR NOT NULL,
+                user_name VARCHAR,
+                subject VARCHAR,
+                question VARCHAR,
+                sql VARCHAR,
+                execution_time_ms BIGINT NOT NULL,
+                row_count BIGINT,
+                error VARCHAR,
+                created_at VARCHAR NOT NULL
+            );",
+        )?;
+
+        info!("Query history store initialized");
+        Ok(())
+    }
+
+    pub fn record(&self, item: &QueryHistoryItem) -> StoreResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            &format!(
+                "INSERT INTO query_history ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                HISTORY_COLUMNS
+            ),
+            params![
+                item.id,
+                item.kind.as_str(),
+                item.user,
+                item.subject,
+                item.question,
+                item.sql,
+                item.execution_time_ms as i64,
+                item.row_count.map(|count| count as i64),
+                item.error,
+                item.timestamp.to_rfc3339()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// One page of matching entries, newest first, along with the total number of matches
+    pub fn list(
+        &self,
+        filter: &HistoryFilter,
+        limit: usize,
+        offset: usize,
+    ) -> StoreResult<(Vec<QueryHistoryItem>, usize)> {
+        let conn = self.pool.get()?;
+
+        let pattern = filter.search.as_deref().map(contains_pattern);
+        let visible = filter
+            .visible_subjects
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+
+        let conditions = "($1 IS NULL OR question ILIKE $1 ESCAPE '\\' OR sql ILIKE $1 ESCAPE '\\')
+             AND ($2 IS NULL OR subject = $2)
+             AND ($3 IS NULL OR user_name = $3)
+             AND ($4 IS NULL OR list_contains(from_json($4, '[\"VARCHAR\"]'), subject))";
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM query_history WHERE {}", conditions),
+            params![pattern, filter.subject, filter.user, visible],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM query_history WHERE {}
+             ORDER BY created_at DESC LIMIT $5 OFFSET $6",
+            HISTORY_COLUMNS, conditions
+        ))?;
+
+        let items = stmt
+            .query_map(
+                params![
+                    pattern,
+                    filter.subject,
+                    filter.user,
+                    visible,
+                    limit as i64,
+                    offset as i64
+                ],
+                item_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((items, total as usize))
+    }
+
+    pub fn get(&self, id: &str) -> StoreResult<Option<QueryHistoryItem>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM query_history WHERE id = ?",
+            HISTORY_COLUMNS
+        ))?;
+
+        let mut rows = stmt.query_map([id], item_from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+}
+
+fn item_from_row(row: &Row<'_>) -> duckdb::Result<QueryHistoryItem> {
+    let kind: String = row.get(1)?;
+    let execution_time_ms: i64 = row.get(6)?;
+    let row_count: Option<i64> = row.get(7)?;
+    let created_at: String = row.get(9)?;
+
+    Ok(QueryHistoryItem {
+        id: row.get(0)?,
+        kind: if kind == "nl" { QueryKind::Nl } else { QueryKind::Sql },
+        user: row.get(2)?,
+        subject: row.get(3)?,
+        question: row.get(4)?,
+        sql: row.get(5)?,
+        execution_time_ms: execution_time_ms as u64,
+        row_count: row_count.map(|count| count as usize),
+        error: row.get(8)?,
+        timestamp: chrono::DateTime::parse_from_rfc3339(&created_at)
+            .map(|t| t.with_timezone(&chrono::Utc))
+            .unwrap_or_default(),
+    })
+}
+
+// An ILIKE pattern matching text that contains `search`, which is taken literally
+fn contains_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_wildcards_in_searches() {
+        assert_eq!(contains_pattern("revenue"), "%revenue%");
+        assert_eq!(contains_pattern("100%"), "%100\\%%");
+        assert_eq!(contains_pattern("order_id"), "%order\\_id%");
+        assert_eq!(contains_pattern("a\\b"), "%a\\\\b%");
+    }
+
+    #[test]
+    fn searches_match_wildcards_literally() {
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        let matches = |text: &str, search: &str| -> bool {
+            conn.query_row(
+                "SELECT ? ILIKE ? ESCAPE '\\'",
+                params![text, contains_pattern(search)],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+
+        assert!(matches("SELECT order_id FROM orders", "ORDER_ID"));
+        assert!(!matches("SELECT orderXid FROM orders", "order_id"));
+        assert!(matches("growth of 100%", "100%"));
+        assert!(!matches("growth of 1000", "100%"));
+    }
+}
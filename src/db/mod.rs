@@ -1,3 +1,11 @@
+pub mod api_key_store;
+pub mod arrow_stream;
 pub mod db_pool;
+pub mod export;
+pub mod history_store;
 pub mod multi_db_pool;
+pub mod query_guard;
+pub mod report_store;
+pub mod result_cache;
+pub mod running_queries;
 pub mod schema_manager;
@@ -30,10 +30,10 @@ impl MultiDbConnectionManager {
     // Get the path to a subject database, creating parent directories if needed
     pub fn get_subject_db_path(&self, subject: &str) -> PathBuf {
         let subject_dir = self.data_dir.join(subject);
-        if !subject_dir.exists() {
-            if let Err(e) = std::fs::create_dir_all(&subject_dir) {
-                warn!("Failed to create subject directory for {}: {}", subject, e);
-            }
+        if !subject_dir.exists()
+            && let Err(e) = std::fs::create_dir_all(&subject_dir)
+        {
+            warn!("Failed to create subject directory for {}: {}", subject, e);
         }
         subject_dir.join(format!("{}.duckdb", subject))
     }
@@ -0,0 +1,210 @@
+use duckdb::{AccessMode, Config, Connection};
+use std::path::Path;
+
+/// Statement types that can never modify a subject database
+const READ_ONLY_KEYWORDS: [&str; 4] = ["SELECT", "WITH", "DESCRIBE", "EXPLAIN"];
+
+/// Broad classification of a SQL statement by its leading keyword
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementKind {
+    ReadOnly(String),
+    Write(String),
+    Empty,
+}
+
+/// Classify a single statement, looking through `EXPLAIN [ANALYZE]` at the statement it wraps
+pub fn classify_statement(statement: &str) -> StatementKind {
+    let mut words = statement
+        .split(|c: char| c.is_whitespace() || c == '(')
+        .map(|w| {
+            w.chars()
+                .take_while(|c| c.is_ascii_alphabetic())
+                .collect::<String>()
+                .to_uppercase()
+        })
+        .filter(|w| !w.is_empty());
+
+    let keyword = match words.next() {
+        Some(keyword) => keyword,
+        None => return StatementKind::Empty,
+    };
+
+    // EXPLAIN ANALYZE actually runs the statement, so judge the inner one
+    if keyword == "EXPLAIN" {
+        let inner = words
+            .skip_while(|w| w == "ANALYZE" || w == "ANALYSE")
+            .collect::<Vec<_>>()
+            .join(" ");
+        return match classify_statement(&inner) {
+            StatementKind::Write(inner_keyword) => StatementKind::Write(inner_keyword),
+            _ => StatementKind::ReadOnly(keyword),
+        };
+    }
+
+    if READ_ONLY_KEYWORDS.contains(&keyword.as_str()) {
+        StatementKind::ReadOnly(keyword)
+    } else {
+        StatementKind::Write(keyword)
+    }
+}
+
+/// Split SQL into statements on semicolons, ignoring comments and anything quoted
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                current.push(c);
+                for next in chars.by_ref() {
+                    current.push(next);
+                    if next == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                // Line comment, skip to end of line
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        current.push(' ');
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                // Block comment, skip to the closing marker
+                chars.next();
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+                current.push(' ');
+            }
+            ';' => {
+                if !current.trim().is_empty() {
+                    statements.push(current.trim().to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    if !current.trim().is_empty() {
+        statements.push(current.trim().to_string());
+    }
+
+    statements
+}
+
+/// Reject SQL containing anything other than read-only statements, unless writes are allowed
+pub fn ensure_read_only(sql: &str, allow_writes: bool) -> Result<(), String> {
+    if allow_writes {
+        return Ok(());
+    }
+
+    let statements = split_statements(sql);
+    if statements.is_empty() {
+        return Err("Query is empty".to_string());
+    }
+
+    for statement in &statements {
+        if let StatementKind::Write(keyword) = classify_statement(statement) {
+            return Err(format!(
+                "{} statements are not allowed; only SELECT, WITH, DESCRIBE and EXPLAIN queries can be run",
+                keyword
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The statement in `sql` when it is a single query that can be wrapped in a subquery
+pub fn single_query(sql: &str) -> Option<String> {
+    let mut statements = split_statements(sql);
+    if statements.len() != 1 {
+        return None;
+    }
+    let statement = statements.pop()?;
+    match classify_statement(&statement) {
+        StatementKind::ReadOnly(keyword) if keyword == "SELECT" || keyword == "WITH" => Some(statement),
+        _ => None,
+    }
+}
+
+/// Open a subject database for running queries, read-only unless writes are allowed
+pub fn open_for_query(db_path: &Path, allow_writes: bool) -> Result<Connection, duckdb::Error> {
+    if allow_writes {
+        return Connection::open(db_path);
+    }
+
+    let config = Config::default().access_mode(AccessMode::ReadOnly)?;
+    Connection::open_with_flags(db_path, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_only(keyword: &str) -> StatementKind {
+        StatementKind::ReadOnly(keyword.to_string())
+    }
+
+    fn write(keyword: &str) -> StatementKind {
+        StatementKind::Write(keyword.to_string())
+    }
+
+    #[test]
+    fn classifies_by_leading_keyword() {
+        assert_eq!(classify_statement("select * from orders"), read_only("SELECT"));
+        assert_eq!(classify_statement("WITH t AS (SELECT 1) SELECT * FROM t"), read_only("WITH"));
+        assert_eq!(classify_statement("(SELECT 1) UNION (SELECT 2)"), read_only("SELECT"));
+        assert_eq!(classify_statement("DESCRIBE orders"), read_only("DESCRIBE"));
+        assert_eq!(classify_statement("  Delete FROM orders"), write("DELETE"));
+        assert_eq!(classify_statement("COPY orders TO 'out.csv'"), write("COPY"));
+        assert_eq!(classify_statement("ATTACH 'other.db'"), write("ATTACH"));
+        assert_eq!(classify_statement("   "), StatementKind::Empty);
+    }
+
+    #[test]
+    fn looks_through_explain_at_the_statement() {
+        assert_eq!(classify_statement("EXPLAIN SELECT 1"), read_only("EXPLAIN"));
+        assert_eq!(classify_statement("EXPLAIN ANALYZE SELECT 1"), read_only("EXPLAIN"));
+        assert_eq!(classify_statement("EXPLAIN ANALYZE DELETE FROM orders"), write("DELETE"));
+        assert_eq!(classify_statement("explain analyse drop table orders"), write("DROP"));
+    }
+
+    #[test]
+    fn splits_on_semicolons_outside_quotes_and_comments() {
+        assert_eq!(split_statements("SELECT 1; SELECT 2;"), ["SELECT 1", "SELECT 2"]);
+        assert_eq!(split_statements("SELECT ';' AS \"a;b\""), ["SELECT ';' AS \"a;b\""]);
+        assert_eq!(split_statements("SELECT 1 -- done; DROP TABLE orders\n"), ["SELECT 1"]);
+        assert_eq!(split_statements("SELECT 1 /* ; DROP TABLE orders */"), ["SELECT 1"]);
+        assert!(split_statements(" ; ;\n").is_empty());
+    }
+
+    #[test]
+    fn rejects_writes_hidden_among_reads() {
+        assert!(ensure_read_only("SELECT 1; SELECT 2", false).is_ok());
+        assert!(ensure_read_only("SELECT 1; DROP TABLE orders", false).is_err());
+        assert!(ensure_read_only("/* hello */ INSERT INTO orders VALUES (1)", false).is_err());
+        assert!(ensure_read_only("-- nothing\n", false).is_err());
+        assert!(ensure_read_only("DROP TABLE orders", true).is_ok());
+    }
+
+    #[test]
+    fn takes_single_queries_only() {
+        assert_eq!(single_query("SELECT 1;").as_deref(), Some("SELECT 1"));
+        assert_eq!(single_query("WITH t AS (SELECT 1) SELECT * FROM t").as_deref(), Some("WITH t AS (SELECT 1) SELECT * FROM t"));
+        assert_eq!(single_query("SELECT 1; SELECT 2"), None);
+        assert_eq!(single_query("DESCRIBE orders"), None);
+        assert_eq!(single_query("COPY orders TO 'out.csv'"), None);
+    }
+}
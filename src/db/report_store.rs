@@ -0,0 +1,223 @@
+use crate::db::db_pool::DuckDBConnectionManager;
+use duckdb::{params, Row};
+use r2d2::Pool;
+use serde::Serialize;
+use tracing::info;
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const REPORT_COLUMNS: &str =
+    "id, name, category, question, sql, config, subject, created_at, updated_at";
+
+/// A saved query together with its Perspective view configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub id: String,
+    pub name: String,
+    pub category: String,
+    pub question: Option<String>,
+    pub sql: String,
+    pub config: serde_json::Value,
+    pub subject: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Fields for a report that hasn't been stored yet
+#[derive(Debug, Clone)]
+pub struct NewReport {
+    pub name: String,
+    pub category: String,
+    pub question: Option<String>,
+    pub sql: String,
+    pub config: serde_json::Value,
+    pub subject: Option<String>,
+}
+
+/// Changes to apply to a stored report, `None` leaves a field as it is
+#[derive(Debug, Clone, Default)]
+pub struct ReportChanges {
+    pub name: Option<String>,
+    pub category: Option<String>,
+    pub question: Option<String>,
+    pub sql: Option<String>,
+    pub config: Option<serde_json::Value>,
+    pub subject: Option<String>,
+}
+
+/// Persists saved reports in the main NL-Cube database
+pub struct ReportStore {
+    pool: Pool<DuckDBConnectionManager>,
+}
+
+impl ReportStore {
+    pub fn new(pool: Pool<DuckDBConnectionManager>) -> Self {
+        Self { pool }
+    }
+
+    /// Create the reports table if this is a fresh database
+    pub fn init(&self) -> StoreResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS reports (
+                id VARCHAR PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                category VARCHAR NOT NULL,
+                question VARCHAR,
+                sql VARCHAR NOT NULL,
+                config VARCHAR NOT NULL,
+                subject VARCHAR,
+                created_at VARCHAR NOT NULL,
+                updated_at VARCHAR NOT NULL
+            );",
+        )?;
+
+        info!("Report store initialized");
+        Ok(())
+    }
+
+    pub fn list(&self, subject: Option<&str>, category: Option<&str>) -> StoreResult<Vec<Report>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM reports
+             WHERE ($1 IS NULL OR subject = $1) AND ($2 IS NULL OR category = $2)
+             ORDER BY category, name",
+            REPORT_COLUMNS
+        ))?;
+
+        let reports = stmt
+            .query_map(params![subject, category], report_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(reports)
+    }
+
+    pub fn get(&self, id: &str) -> StoreResult<Option<Report>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM reports WHERE id = ?",
+            REPORT_COLUMNS
+        ))?;
+
+        let mut rows = stmt.query_map([id], report_from_row)?;
+        Ok(rows.next().transpose()?)
+    }
+
+    pub fn create(&self, report: NewReport) -> StoreResult<Report> {
+        let conn = self.pool.get()?;
+        let id: String = conn.query_row("SELECT gen_random_uuid()::VARCHAR", [], |row| row.get(0))?;
+        let now = chrono::Utc::now().to_rfc3339();
+
+        conn.execute(
+            &format!(
+                "INSERT INTO reports ({}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                REPORT_COLUMNS
+            ),
+            params![
+                id,
+                report.name,
+                report.category,
+                report.question,
+                report.sql,
+                report.config.to_string(),
+                report.subject,
+                now,
+                now
+            ],
+        )?;
+
+        info!("Saved report {} ({})", report.name, id);
+
+        Ok(Report {
+            id,
+            name: report.name,
+            category: report.category,
+            question: report.question,
+            sql: report.sql,
+            config: report.config,
+            subject: report.subject,
+            created_at: now.clone(),
+            updated_at: now,
+        })
+    }
+
+    pub fn update(&self, id: &str, changes: ReportChanges) -> StoreResult<Option<Report>> {
+        let Some(mut report) = self.get(id)? else {
+            return Ok(None);
+        };
+
+        if let Some(name) = changes.name {
+            report.name = name;
+        }
+        if let Some(category) = changes.category {
+            report.category = category;
+        }
+        if let Some(question) = changes.question {
+            report.question = Some(question);
+        }
+        if let Some(sql) = changes.sql {
+            report.sql = sql;
+        }
+        if let Some(config) = changes.config {
+            report.config = config;
+        }
+        if let Some(subject) = changes.subject {
+            report.subject = Some(subject);
+        }
+        report.updated_at = chrono::Utc::now().to_rfc3339();
+
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE reports
+             SET name = ?, category = ?, question = ?, sql = ?, config = ?, subject = ?, updated_at = ?
+             WHERE id = ?",
+            params![
+                report.name,
+                report.category,
+                report.question,
+                report.sql,
+                report.config.to_string(),
+                report.subject,
+                report.updated_at,
+                id
+            ],
+        )?;
+
+        info!("Updated report {} ({})", report.name, id);
+        Ok(Some(report))
+    }
+
+    /// Returns false if there was no report with this id
+    pub fn delete(&self, id: &str) -> StoreResult<bool> {
+        let conn = self.pool.get()?;
+        let deleted = conn.execute("DELETE FROM reports WHERE id = ?", [id])?;
+
+        if deleted > 0 {
+            info!("Deleted report {}", id);
+        }
+        Ok(deleted > 0)
+    }
+
+    pub fn count(&self) -> StoreResult<usize> {
+        let conn = self.pool.get()?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM reports", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+fn report_from_row(row: &Row<'_>) -> duckdb::Result<Report> {
+    let config: String = row.get(5)?;
+
+    Ok(Report {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        category: row.get(2)?,
+        question: row.get(3)?,
+        sql: row.get(4)?,
+        // A report whose view config can't be parsed should still load with the default view
+        config: serde_json::from_str(&config).unwrap_or(serde_json::Value::Null),
+        subject: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+    })
+}
@@ -0,0 +1,215 @@
+use crate::db::arrow_stream::ResultMeta;
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// Arrow IPC output of a query, ready to be sent again
+#[derive(Debug, Clone)]
+pub struct CachedResult {
+    pub body: Bytes,
+    pub meta: ResultMeta,
+}
+
+struct CacheEntry {
+    subject: String,
+    result: CachedResult,
+    // Larger is more recently used
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<String, CacheEntry>,
+    // Bumped whenever a subject's data changes, so older entries stop matching
+    versions: HashMap<String, u64>,
+    used_bytes: usize,
+    clock: u64,
+}
+
+/// Query results keyed by subject, SQL text and the subject's data version,
+/// evicting the least recently used results to stay within a memory budget
+pub struct ResultCache {
+    budget_bytes: usize,
+    // Changes on every start, so ETags from before a restart never match
+    generation: String,
+    inner: Mutex<CacheInner>,
+}
+
+impl ResultCache {
+    pub fn new(budget_mb: u64) -> Self {
+        Self {
+            budget_bytes: (budget_mb * 1024 * 1024) as usize,
+            generation: uuid::Uuid::new_v4().simple().to_string(),
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    /// Largest result worth keeping a copy of
+    pub fn max_entry_bytes(&self) -> usize {
+        self.budget_bytes
+    }
+
+    /// Quoted ETag for `sql` against the current data in `subject`
+    pub fn etag(&self, subject: &str, sql: &str) -> String {
+        let version = self.inner.lock().unwrap().versions.get(subject).copied().unwrap_or(0);
+        let digest = Sha256::digest(format!("{}\n{}\n{}\n{}", self.generation, subject, version, sql).as_bytes());
+        format!("\"{:x}\"", digest)
+    }
+
+    pub fn get(&self, subject: &str, sql: &str) -> Option<CachedResult> {
+        let key = self.etag(subject, sql);
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let entry = inner.entries.get_mut(&key)?;
+        entry.last_used = clock;
+        Some(entry.result.clone())
+    }
+
+    pub fn insert(&self, subject: &str, sql: &str, result: CachedResult) {
+        let size = result.body.len();
+        if size > self.budget_bytes {
+            return;
+        }
+
+        let key = self.etag(subject, sql);
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        if let Some(previous) = inner.entries.remove(&key) {
+            inner.used_bytes -= previous.result.body.len();
+        }
+
+        while inner.used_bytes + size > self.budget_bytes {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.used_bytes -= evicted.result.body.len();
+            }
+        }
+
+        inner.used_bytes += size;
+        inner.entries.insert(
+            key,
+            CacheEntry {
+                subject: subject.to_string(),
+                result,
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Record that `subject`'s data changed, dropping its cached results
+    pub fn bump_version(&self, subject: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.versions.entry(subject.to_string()).or_insert(0) += 1;
+
+        let stale: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.subject == subject)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            if let Some(entry) = inner.entries.remove(&key) {
+                inner.used_bytes -= entry.result.body.len();
+            }
+        }
+
+        debug!("Subject '{}' data changed, result cache now {} bytes", subject, inner.used_bytes);
+    }
+}
+
+/// Whether an If-None-Match header value names `etag`
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(bytes: usize) -> CachedResult {
+        CachedResult {
+            body: Bytes::from(vec![0u8; bytes]),
+            meta: ResultMeta {
+                columns: Vec::new(),
+                row_count: Some(0),
+                total_rows: Some(0),
+                truncated: false,
+            },
+        }
+    }
+
+    #[test]
+    fn matches_if_none_match_values() {
+        let etag = "\"abc\"";
+        assert!(etag_matches("\"abc\"", etag));
+        assert!(etag_matches("W/\"abc\"", etag));
+        assert!(etag_matches("\"old\", \"abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"old\"", etag));
+        assert!(!etag_matches("abc", etag));
+        assert!(!etag_matches("", etag));
+    }
+
+    #[test]
+    fn etags_change_with_the_data() {
+        let cache = ResultCache::new(1);
+        let etag = cache.etag("sales", "SELECT 1");
+        assert_eq!(etag, cache.etag("sales", "SELECT 1"));
+        assert_ne!(etag, cache.etag("sales", "SELECT 2"));
+        assert_ne!(etag, cache.etag("hr", "SELECT 1"));
+
+        // An upload into another subject leaves this one's results valid
+        cache.bump_version("hr");
+        assert_eq!(etag, cache.etag("sales", "SELECT 1"));
+        cache.bump_version("sales");
+        assert_ne!(etag, cache.etag("sales", "SELECT 1"));
+
+        // Nor do ETags survive a restart
+        assert_ne!(etag, ResultCache::new(1).etag("sales", "SELECT 1"));
+    }
+
+    #[test]
+    fn drops_results_when_their_subject_changes() {
+        let cache = ResultCache::new(1);
+        cache.insert("sales", "SELECT 1", result(10));
+        cache.insert("hr", "SELECT 1", result(10));
+
+        cache.bump_version("sales");
+        assert!(cache.get("sales", "SELECT 1").is_none());
+        assert!(cache.get("hr", "SELECT 1").is_some());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_within_budget() {
+        let cache = ResultCache::new(1);
+        let half = 512 * 1024;
+        cache.insert("sales", "SELECT 1", result(half));
+        cache.insert("sales", "SELECT 2", result(half));
+        assert!(cache.get("sales", "SELECT 1").is_some());
+
+        cache.insert("sales", "SELECT 3", result(half));
+        assert!(cache.get("sales", "SELECT 1").is_some());
+        assert!(cache.get("sales", "SELECT 2").is_none());
+        assert!(cache.get("sales", "SELECT 3").is_some());
+
+        // Never kept at all when larger than the whole budget
+        cache.insert("sales", "SELECT 4", result(2 * half + 1));
+        assert!(cache.get("sales", "SELECT 4").is_none());
+    }
+}
@@ -0,0 +1,294 @@
+use duckdb::{Connection, InterruptHandle};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const RUNNING: u8 = 0;
+const CANCELLED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+
+/// A query currently executing against a subject database
+#[derive(Debug, Clone, Serialize)]
+pub struct RunningQueryInfo {
+    pub id: String,
+    pub user: Option<String>,
+    pub subject: String,
+    // Natural language queries are registered with their question while SQL is generated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub question: Option<String>,
+    pub sql: String,
+    pub started_at: String,
+}
+
+/// Why a query stopped before finishing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryInterrupted {
+    Cancelled,
+    TimedOut(Duration),
+}
+
+impl fmt::Display for QueryInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryInterrupted::Cancelled => write!(f, "Query was cancelled"),
+            QueryInterrupted::TimedOut(timeout) => {
+                write!(f, "Query timed out after {} seconds", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryInterrupted {}
+
+// Shared by the registry, which stops queries, and the guard of the query being stopped
+struct QueryControl {
+    state: AtomicU8,
+    // Only there once the query has a connection to run on
+    interrupt: Mutex<Option<Arc<InterruptHandle>>>,
+}
+
+impl QueryControl {
+    // Returns false if the query was already stopped
+    fn stop(&self, reason: u8) -> bool {
+        if self
+            .state
+            .compare_exchange(RUNNING, reason, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return false;
+        }
+        if let Some(interrupt) = self.interrupt.lock().unwrap().as_ref() {
+            interrupt.interrupt();
+        }
+        true
+    }
+}
+
+struct RunningQuery {
+    info: RunningQueryInfo,
+    control: Arc<QueryControl>,
+}
+
+type QueryMap = Arc<Mutex<HashMap<String, RunningQuery>>>;
+
+/// Tracks executing queries so they can be listed, cancelled and timed out
+pub struct RunningQueries {
+    timeout: Option<Duration>,
+    queries: QueryMap,
+}
+
+impl RunningQueries {
+    /// A zero timeout lets queries run for as long as they need
+    pub fn new(timeout_secs: u64) -> Self {
+        Self {
+            timeout: (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs)),
+            queries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Track a query from the moment its request arrives, so it can be cancelled before
+    /// it has a connection. The query stops being tracked when the guard is dropped.
+    pub fn register(&self, info: RunningQueryInfo) -> Result<QueryGuard, String> {
+        let control = Arc::new(QueryControl {
+            state: AtomicU8::new(RUNNING),
+            interrupt: Mutex::new(None),
+        });
+        let id = info.id.clone();
+
+        let mut queries = self.queries.lock().unwrap();
+        if queries.contains_key(&id) {
+            return Err(format!("Query {} is already running", id));
+        }
+        queries.insert(
+            id.clone(),
+            RunningQuery {
+                info,
+                control: Arc::clone(&control),
+            },
+        );
+
+        Ok(QueryGuard {
+            queries: Arc::clone(&self.queries),
+            id,
+            control,
+            timeout: self.timeout,
+            timer: None,
+        })
+    }
+
+    pub fn list(&self) -> Vec<RunningQueryInfo> {
+        let mut queries: Vec<RunningQueryInfo> = self
+            .queries
+            .lock()
+            .unwrap()
+            .values()
+            .map(|query| query.info.clone())
+            .collect();
+        queries.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+        queries
+    }
+
+    pub fn get(&self, id: &str) -> Option<RunningQueryInfo> {
+        self.queries.lock().unwrap().get(id).map(|query| query.info.clone())
+    }
+
+    /// Interrupt a running query, returns false if it isn't running
+    pub fn cancel(&self, id: &str) -> bool {
+        let queries = self.queries.lock().unwrap();
+        let Some(query) = queries.get(id) else {
+            return false;
+        };
+
+        if query.control.stop(CANCELLED) {
+            info!("Cancelling query {}", id);
+        }
+        true
+    }
+}
+
+/// Keeps a query registered while it runs
+pub struct QueryGuard {
+    queries: QueryMap,
+    id: String,
+    control: Arc<QueryControl>,
+    timeout: Option<Duration>,
+    timer: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl QueryGuard {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The query is about to run on `conn`, interrupt it there once the timeout passes.
+    /// A query cancelled before this is interrupted straight away.
+    pub fn attach(&mut self, conn: &Connection) {
+        let interrupt = conn.interrupt_handle();
+        *self.control.interrupt.lock().unwrap() = Some(Arc::clone(&interrupt));
+        if self.interruption().is_some() {
+            interrupt.interrupt();
+            return;
+        }
+
+        if let Some(timeout) = self.timeout
+            && self.timer.is_none()
+        {
+            let control = Arc::clone(&self.control);
+            let id = self.id.clone();
+            self.timer = Some(tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                if control.stop(TIMED_OUT) {
+                    warn!("Query {} timed out after {}s, interrupting it", id, timeout.as_secs());
+                }
+            }));
+        }
+    }
+
+    /// Show the SQL once it is known, for queries registered before it was generated
+    pub fn set_sql(&self, sql: &str) {
+        if let Some(query) = self.queries.lock().unwrap().get_mut(&self.id) {
+            query.info.sql = sql.to_string();
+        }
+    }
+
+    /// Whether the query was stopped by a cancel request or the timeout
+    pub fn interruption(&self) -> Option<QueryInterrupted> {
+        match self.control.state.load(Ordering::SeqCst) {
+            CANCELLED => Some(QueryInterrupted::Cancelled),
+            TIMED_OUT => Some(QueryInterrupted::TimedOut(self.timeout.unwrap_or_default())),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for QueryGuard {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer.abort();
+        }
+        self.queries.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Long enough that it only ends by being interrupted
+    const SLOW_SQL: &str = "SELECT count(*) FROM range(1000000000000) a WHERE a.range % 7 = 3";
+
+    fn info(id: &str) -> RunningQueryInfo {
+        RunningQueryInfo {
+            id: id.to_string(),
+            user: None,
+            subject: "test".to_string(),
+            question: None,
+            sql: SLOW_SQL.to_string(),
+            started_at: "2026-01-01T00:00:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn cancel_interrupts_a_running_query() {
+        let registry = RunningQueries::new(0);
+        let conn = Connection::open_in_memory().unwrap();
+        let mut running = registry.register(info("q1")).unwrap();
+        running.attach(&conn);
+
+        assert!(registry.register(info("q1")).is_err());
+        assert_eq!(registry.list().len(), 1);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(Duration::from_millis(200));
+                assert!(registry.cancel("q1"));
+            });
+            assert!(conn.query_row(SLOW_SQL, [], |row| row.get::<_, i64>(0)).is_err());
+        });
+
+        assert_eq!(running.interruption(), Some(QueryInterrupted::Cancelled));
+        drop(running);
+        assert!(registry.get("q1").is_none());
+        assert!(!registry.cancel("q1"));
+    }
+
+    #[test]
+    fn cancels_queries_before_they_have_a_connection() {
+        let registry = RunningQueries::new(0);
+        let running = registry.register(RunningQueryInfo {
+            question: Some("How many orders?".to_string()),
+            sql: String::new(),
+            ..info("q3")
+        })
+        .unwrap();
+
+        assert!(registry.cancel("q3"));
+        assert_eq!(running.interruption(), Some(QueryInterrupted::Cancelled));
+
+        // Still listed until its request gives up
+        running.set_sql("SELECT count(*) FROM orders");
+        let listed = registry.get("q3").unwrap();
+        assert_eq!(listed.question.as_deref(), Some("How many orders?"));
+        assert_eq!(listed.sql, "SELECT count(*) FROM orders");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn timeout_interrupts_a_slow_query() {
+        let registry = RunningQueries::new(1);
+        let conn = Connection::open_in_memory().unwrap();
+        let mut running = registry.register(info("q2")).unwrap();
+        running.attach(&conn);
+
+        let result = tokio::task::block_in_place(|| conn.query_row(SLOW_SQL, [], |row| row.get::<_, i64>(0)));
+
+        assert!(result.is_err());
+        assert_eq!(
+            running.interruption(),
+            Some(QueryInterrupted::TimedOut(Duration::from_secs(1)))
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::ingest::schema::{ColumnSchema, DataType, TableSchema};
-use crate::ingest::{FileIngestor, IngestError};
+use crate::ingest::{writer, FileIngestor, IngestError, IngestOptions};
 use duckdb::Connection;
 use std::fs::File;
 use std::io::{BufReader, Read};
@@ -66,16 +66,7 @@ impl CsvIngestor {
 
                 Ok(ColumnSchema {
                     name: row.get(1)?,
-                    data_type: match row.get::<_, String>(2)?.to_lowercase().as_str() {
-                        "integer" => DataType::Integer,
-                        "bigint" => DataType::BigInt,
-                        "double" => DataType::Double,
-                        "varchar" | "text" => DataType::String,
-                        "boolean" => DataType::Boolean,
-                        "date" => DataType::Date,
-                        "timestamp" => DataType::Timestamp,
-                        other => DataType::Unknown(other.to_string()),
-                    },
+                    data_type: DataType::from_duckdb_type(&row.get::<_, String>(2)?),
                     nullable: !is_not_null, // If is_not_null is true, then the column is not nullable
                 })
             })
@@ -91,6 +82,7 @@ impl CsvIngestor {
         Ok(TableSchema {
             name: file_stem,
             columns,
+            rows_written: None,
         })
     }
 }
@@ -106,19 +98,20 @@ impl FileIngestor for CsvIngestor {
         path: &Path,
         table_name: &str,
         subject: &str,
+        options: &IngestOptions,
     ) -> Result<TableSchema, IngestError> {
         // First infer the schema
         let mut schema = self.infer_schema(path)?;
         schema.name = table_name.to_string();
 
         // Get the absolute path to the CSV file for DuckDB
-        let absolute_path = path.canonicalize().map_err(|e| IngestError::IoError(e))?;
+        let absolute_path = path.canonicalize().map_err(IngestError::IoError)?;
 
         // Build the path to the subject database
         let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
         let subject_dir = Path::new(&data_dir).join(subject);
         if !subject_dir.exists() {
-            std::fs::create_dir_all(&subject_dir).map_err(|e| IngestError::IoError(e))?;
+            std::fs::create_dir_all(&subject_dir).map_err(IngestError::IoError)?;
         }
 
         let db_path = subject_dir.join(format!("{}.duckdb", subject));
@@ -136,48 +129,20 @@ impl FileIngestor for CsvIngestor {
             absolute_path.display()
         );
 
-        // Create a more robust create table statement with explicit DROP IF EXISTS
-        let drop_sql = format!("DROP TABLE IF EXISTS \"{}\"", table_name);
-
-        // First drop the table if it exists
-        conn.execute(&drop_sql, []).map_err(|e| {
-            IngestError::DatabaseError(format!("Failed to drop existing table: {}", e))
-        })?;
-
-        // Now use DuckDB's CSV reading to create the table directly
-        let create_sql = format!(
-            "CREATE TABLE \"{}\" AS SELECT * FROM read_csv_auto('{}', HEADER=true, AUTO_DETECT=true)",
-            table_name,
+        // Use DuckDB's CSV reading to load the table directly
+        let source_sql = format!(
+            "SELECT * FROM read_csv_auto('{}', HEADER=true, AUTO_DETECT=true)",
             absolute_path.to_string_lossy()
         );
 
-        tracing::info!("Executing SQL: {}", create_sql);
-
-        conn.execute(&create_sql, [])
-            .map_err(|e| IngestError::DatabaseError(format!("Failed to create table: {}", e)))?;
-
-        // Verify table was created
-        let verify_sql = format!("SELECT COUNT(*) FROM \"{}\"", table_name);
-
-        match conn.query_row(&verify_sql, [], |row| row.get::<_, i64>(0)) {
-            Ok(count) => {
-                tracing::info!(
-                    "Successfully created table {} with {} rows",
-                    table_name,
-                    count
-                );
-            }
-            Err(e) => {
-                tracing::error!("Table creation verification failed: {}", e);
-                return Err(IngestError::DatabaseError(format!(
-                    "Table verification failed: {}",
-                    e
-                )));
-            }
-        }
-
-        // Wait a small amount of time for DuckDB to complete any background operations
-        std::thread::sleep(std::time::Duration::from_millis(300));
+        let count = writer::write_table(&conn, table_name, &source_sql, options)?;
+        tracing::info!(
+            "Successfully wrote {} rows to table {} ({:?})",
+            count,
+            table_name,
+            options.mode
+        );
+        schema.rows_written = Some(count);
 
         Ok(schema)
     }
@@ -0,0 +1,605 @@
+use crate::ingest::schema::{ColumnSchema, DataType, TableSchema};
+use crate::ingest::{writer, FileIngestor, IngestError, IngestOptions};
+use calamine::{open_workbook_auto, Data, Reader};
+use duckdb::types::{TimeUnit, Value};
+use duckdb::{appender_params_from_iter, Connection};
+use std::path::Path;
+
+// Sheets are loaded into a temp table with this prefix first so they can be written with the
+// upload's write mode
+const SHEET_STAGING_PREFIX: &str = "nl_cube_sheet_";
+
+pub struct ExcelIngestor {
+    // How many rows from the top of a sheet to search for the header
+    header_search_rows: usize,
+}
+
+// A sheet's header and data rows once leading title/blank rows are skipped
+struct SheetTable {
+    columns: Vec<ColumnSchema>,
+    column_indexes: Vec<usize>,
+    rows: Vec<Vec<Data>>,
+}
+
+impl ExcelIngestor {
+    pub fn new() -> Self {
+        Self {
+            header_search_rows: 20,
+        }
+    }
+
+    /// Load every sheet as its own table, or only `sheet` if one is chosen
+    pub fn ingest_sheets(
+        &self,
+        path: &Path,
+        table_name: &str,
+        subject: &str,
+        options: &IngestOptions,
+    ) -> Result<Vec<TableSchema>, IngestError> {
+        let conn = open_subject_db(subject)?;
+        self.load_sheets(&conn, path, table_name, options)
+    }
+
+    fn load_sheets(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        table_name: &str,
+        options: &IngestOptions,
+    ) -> Result<Vec<TableSchema>, IngestError> {
+        let mut workbook = open_workbook_auto(path)
+            .map_err(|e| IngestError::ParseError(format!("Failed to open workbook: {}", e)))?;
+
+        let sheet_names = workbook.sheet_names().to_vec();
+        let selected: Vec<String> = match options.sheet.as_deref() {
+            Some(name) => {
+                if !sheet_names.iter().any(|s| s == name) {
+                    return Err(IngestError::ParseError(format!(
+                        "Sheet '{}' not found, available sheets: {}",
+                        name,
+                        sheet_names.join(", ")
+                    )));
+                }
+                vec![name.to_string()]
+            }
+            None => sheet_names,
+        };
+
+        let mut schemas = Vec::new();
+
+        for sheet_name in &selected {
+            let range = workbook.worksheet_range(sheet_name).map_err(|e| {
+                IngestError::ParseError(format!("Failed to read sheet '{}': {}", sheet_name, e))
+            })?;
+
+            let rows: Vec<&[Data]> = range.rows().collect();
+            let Some(sheet_table) = self.read_table(&rows) else {
+                tracing::warn!("Skipping empty sheet '{}'", sheet_name);
+                continue;
+            };
+
+            // A single sheet keeps the file's table name, otherwise each gets a suffix
+            let sheet_table_name = if selected.len() == 1 {
+                table_name.to_string()
+            } else {
+                format!("{}_{}", table_name, sanitize_name(sheet_name))
+            };
+
+            tracing::info!(
+                "Ingesting sheet '{}' to subject database. Table: {}",
+                sheet_name,
+                sheet_table_name
+            );
+
+            schemas.push(load_table(conn, &sheet_table_name, sheet_table, options)?);
+        }
+
+        if schemas.is_empty() {
+            return Err(IngestError::ParseError(
+                "Workbook contains no data".to_string(),
+            ));
+        }
+
+        Ok(schemas)
+    }
+
+    fn read_table(&self, rows: &[&[Data]]) -> Option<SheetTable> {
+        let (header_index, has_header) = self.find_header_row(rows)?;
+        let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+
+        let data_start = if has_header { header_index + 1 } else { header_index };
+        let data_rows: Vec<Vec<Data>> = rows[data_start..]
+            .iter()
+            .filter(|row| row.iter().any(|c| !is_blank(c)))
+            .map(|row| row.to_vec())
+            .collect();
+
+        let mut columns = Vec::new();
+        let mut column_indexes = Vec::new();
+        let mut used_names: Vec<String> = Vec::new();
+
+        for index in 0..width {
+            let header = if has_header {
+                rows[header_index]
+                    .get(index)
+                    .filter(|c| !is_blank(c))
+                    .map(|c| c.to_string().trim().to_string())
+            } else {
+                None
+            };
+
+            let values: Vec<&Data> = data_rows.iter().filter_map(|r| r.get(index)).collect();
+
+            // Columns with no header and no data are just padding from merged or formatted cells
+            if header.is_none() && values.iter().all(|c| is_blank(c)) {
+                continue;
+            }
+
+            let mut name = header.unwrap_or_else(|| format!("column_{}", index + 1));
+            if used_names.contains(&name) {
+                name = format!("{}_{}", name, index + 1);
+            }
+            used_names.push(name.clone());
+
+            columns.push(ColumnSchema {
+                name,
+                data_type: infer_type(&values),
+                nullable: values.len() < data_rows.len() || values.iter().any(|c| is_blank(c)),
+            });
+            column_indexes.push(index);
+        }
+
+        if columns.is_empty() {
+            return None;
+        }
+
+        Some(SheetTable {
+            columns,
+            column_indexes,
+            rows: data_rows,
+        })
+    }
+
+    // Title rows and merged banners above a table usually fill only a cell or two,
+    // so the header is the first row that is mostly filled with text
+    fn find_header_row(&self, rows: &[&[Data]]) -> Option<(usize, bool)> {
+        let search = &rows[..rows.len().min(self.header_search_rows)];
+        let filled = |row: &[Data]| row.iter().filter(|c| !is_blank(c)).count();
+
+        let widest = search.iter().map(|r| filled(r)).max().filter(|w| *w > 0)?;
+        let index = search
+            .iter()
+            .position(|r| filled(r) * 2 >= widest && filled(r) > 0)?;
+
+        let has_header = search[index]
+            .iter()
+            .filter(|c| !is_blank(c))
+            .all(|c| matches!(c, Data::String(_)));
+
+        Some((index, has_header))
+    }
+}
+
+// Implement Send + Sync safely
+unsafe impl Send for ExcelIngestor {}
+unsafe impl Sync for ExcelIngestor {}
+
+impl FileIngestor for ExcelIngestor {
+    fn ingest(
+        &self,
+        path: &Path,
+        table_name: &str,
+        subject: &str,
+        options: &IngestOptions,
+    ) -> Result<TableSchema, IngestError> {
+        // Without a sheet choice only the first sheet can be loaded under this table name
+        let workbook = open_workbook_auto(path)
+            .map_err(|e| IngestError::ParseError(format!("Failed to open workbook: {}", e)))?;
+        let first_sheet = workbook
+            .sheet_names()
+            .first()
+            .cloned()
+            .ok_or_else(|| IngestError::ParseError("Workbook has no sheets".to_string()))?;
+
+        let options = IngestOptions {
+            sheet: Some(first_sheet),
+            ..options.clone()
+        };
+        let mut schemas = self.ingest_sheets(path, table_name, subject, &options)?;
+        Ok(schemas.remove(0))
+    }
+}
+
+fn open_subject_db(subject: &str) -> Result<Connection, IngestError> {
+    // Build the path to the subject database
+    let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
+    let subject_dir = Path::new(&data_dir).join(subject);
+    if !subject_dir.exists() {
+        std::fs::create_dir_all(&subject_dir).map_err(IngestError::IoError)?;
+    }
+
+    let db_path = subject_dir.join(format!("{}.duckdb", subject));
+
+    tracing::info!("Opening subject database at: {}", db_path.display());
+
+    Connection::open(&db_path).map_err(|e| IngestError::DatabaseError(e.to_string()))
+}
+
+fn load_table(
+    conn: &Connection,
+    table_name: &str,
+    table: SheetTable,
+    options: &IngestOptions,
+) -> Result<TableSchema, IngestError> {
+    let column_defs = table
+        .columns
+        .iter()
+        .map(|c| format!("\"{}\" {}", c.name.replace('"', "\"\""), sql_type(&c.data_type)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // Temp and uniquely named, so it never lands in the subject's database or meets another upload
+    let staging = format!("{}{}", SHEET_STAGING_PREFIX, uuid::Uuid::new_v4().simple());
+    let create_sql = format!("CREATE TEMP TABLE {} ({})", staging, column_defs);
+    tracing::info!("Executing SQL: {}", create_sql);
+
+    conn.execute(&create_sql, [])
+        .map_err(|e| IngestError::DatabaseError(format!("Failed to create table: {}", e)))?;
+
+    let mut appender = conn
+        .appender(&staging)
+        .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+    for row in &table.rows {
+        let values = table
+            .columns
+            .iter()
+            .zip(&table.column_indexes)
+            .map(|(column, index)| to_value(row.get(*index).unwrap_or(&Data::Empty), &column.data_type));
+
+        appender
+            .append_row(appender_params_from_iter(values))
+            .map_err(|e| IngestError::DatabaseError(format!("Failed to append row: {}", e)))?;
+    }
+
+    appender
+        .flush()
+        .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+    drop(appender);
+
+    let result = writer::write_table(
+        conn,
+        table_name,
+        &format!("SELECT * FROM {}", staging),
+        options,
+    );
+    let _ = conn.execute(&format!("DROP TABLE IF EXISTS {}", staging), []);
+    let count = result?;
+
+    tracing::info!(
+        "Successfully wrote {} rows to table {} ({:?})",
+        count,
+        table_name,
+        options.mode
+    );
+
+    Ok(TableSchema {
+        name: table_name.to_string(),
+        columns: table.columns,
+        rows_written: Some(count),
+    })
+}
+
+fn is_blank(cell: &Data) -> bool {
+    match cell {
+        Data::Empty | Data::Error(_) => true,
+        Data::String(s) => s.trim().is_empty(),
+        _ => false,
+    }
+}
+
+fn infer_type(values: &[&Data]) -> DataType {
+    let cells: Vec<&Data> = values.iter().copied().filter(|c| !is_blank(c)).collect();
+
+    if cells.is_empty() {
+        return DataType::String;
+    }
+
+    if cells.iter().all(|c| matches!(c, Data::Bool(_))) {
+        return DataType::Boolean;
+    }
+
+    if cells.iter().all(|c| matches!(c, Data::DateTime(d) if d.is_datetime())) {
+        // Excel has no separate date type, dates are datetimes at midnight
+        let all_dates = cells.iter().all(|c| match c {
+            Data::DateTime(d) => d
+                .as_datetime()
+                .map(|dt| dt.time() == chrono::NaiveTime::MIN)
+                .unwrap_or(false),
+            _ => false,
+        });
+        return if all_dates {
+            DataType::Date
+        } else {
+            DataType::Timestamp
+        };
+    }
+
+    // Excel stores every number as a float, so whole numbers become integers
+    if cells.iter().all(|c| matches!(c, Data::Int(_) | Data::Float(_))) {
+        let all_integral = cells.iter().all(|c| match c {
+            Data::Float(f) => f.fract() == 0.0 && f.abs() < i64::MAX as f64,
+            _ => true,
+        });
+        return if all_integral {
+            DataType::BigInt
+        } else {
+            DataType::Double
+        };
+    }
+
+    DataType::String
+}
+
+fn to_value(cell: &Data, data_type: &DataType) -> Value {
+    if is_blank(cell) {
+        return Value::Null;
+    }
+
+    match (data_type, cell) {
+        (DataType::BigInt, Data::Int(i)) => Value::BigInt(*i),
+        (DataType::BigInt, Data::Float(f)) => Value::BigInt(*f as i64),
+        (DataType::Double, Data::Int(i)) => Value::Double(*i as f64),
+        (DataType::Double, Data::Float(f)) => Value::Double(*f),
+        (DataType::Boolean, Data::Bool(b)) => Value::Boolean(*b),
+        (DataType::Date, Data::DateTime(d)) => match d.as_datetime() {
+            Some(dt) => Value::Date32(dt.and_utc().timestamp().div_euclid(86_400) as i32),
+            None => Value::Null,
+        },
+        (DataType::Timestamp, Data::DateTime(d)) => match d.as_datetime() {
+            Some(dt) => Value::Timestamp(TimeUnit::Microsecond, dt.and_utc().timestamp_micros()),
+            None => Value::Null,
+        },
+        (_, Data::DateTime(d)) => match d.as_datetime() {
+            Some(dt) => Value::Text(dt.to_string()),
+            None => Value::Text(cell.to_string()),
+        },
+        _ => Value::Text(cell.to_string()),
+    }
+}
+
+fn sql_type(data_type: &DataType) -> &'static str {
+    match data_type {
+        DataType::Integer => "INTEGER",
+        DataType::BigInt => "BIGINT",
+        DataType::Double => "DOUBLE",
+        DataType::Boolean => "BOOLEAN",
+        DataType::Date => "DATE",
+        DataType::Timestamp => "TIMESTAMP",
+        DataType::String | DataType::Unknown(_) => "VARCHAR",
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::WriteMode;
+    use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet};
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("nl-cube-excel-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn date(value: &str) -> ExcelDateTime {
+        ExcelDateTime::parse_from_str(value).unwrap()
+    }
+
+    // A report the way people lay them out: a title, a blank row, then the table
+    fn write_sales(sheet: &mut Worksheet) {
+        let day = Format::new().set_num_format("yyyy-mm-dd");
+        let time = Format::new().set_num_format("yyyy-mm-dd hh:mm");
+
+        sheet.write_string(0, 0, "Quarterly sales").unwrap();
+        for (col, header) in ["region", "units", "price", "code", "shipped", "ordered_at", "paid"]
+            .into_iter()
+            .enumerate()
+        {
+            sheet.write_string(2, col as u16, header).unwrap();
+        }
+
+        sheet.write_string(3, 0, "EU").unwrap();
+        sheet.write_number(3, 1, 3.0).unwrap();
+        sheet.write_number(3, 2, 9.5).unwrap();
+        sheet.write_number(3, 3, 101.0).unwrap();
+        sheet.write_datetime_with_format(3, 4, date("2024-01-31"), &day).unwrap();
+        sheet.write_datetime_with_format(3, 5, date("2024-01-30 14:30:00"), &time).unwrap();
+        sheet.write_boolean(3, 6, true).unwrap();
+
+        sheet.write_string(4, 0, "US").unwrap();
+        sheet.write_number(4, 1, 5.0).unwrap();
+        sheet.write_number(4, 2, 12.0).unwrap();
+        sheet.write_string(4, 3, "A-7").unwrap();
+        sheet.write_datetime_with_format(4, 4, date("2024-02-01"), &day).unwrap();
+        sheet.write_datetime_with_format(4, 5, date("2024-02-01 09:00:00"), &time).unwrap();
+        sheet.write_boolean(4, 6, false).unwrap();
+    }
+
+    fn write_returns(sheet: &mut Worksheet) {
+        sheet.write_string(0, 0, "order_id").unwrap();
+        sheet.write_string(0, 1, "reason").unwrap();
+        sheet.write_number(1, 0, 1.0).unwrap();
+        sheet.write_string(1, 1, "damaged").unwrap();
+    }
+
+    fn workbook(dir: &TempDir) -> PathBuf {
+        let mut workbook = Workbook::new();
+        write_sales(workbook.add_worksheet().set_name("Q1 Sales").unwrap());
+        workbook.add_worksheet().set_name("Notes").unwrap();
+        write_returns(workbook.add_worksheet().set_name("Returns").unwrap());
+
+        let path = dir.0.join("book.xlsx");
+        workbook.save(&path).unwrap();
+        path
+    }
+
+    fn options(sheet: Option<&str>) -> IngestOptions {
+        IngestOptions {
+            mode: WriteMode::Replace,
+            sheet: sheet.map(str::to_string),
+            ..IngestOptions::default()
+        }
+    }
+
+    fn column_types(schema: &TableSchema) -> Vec<(&str, &DataType, bool)> {
+        schema
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), &c.data_type, c.nullable))
+            .collect()
+    }
+
+    fn table_names(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT table_name FROM duckdb_tables() WHERE NOT temporary ORDER BY table_name")
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn finds_the_header_below_title_rows_and_infers_types() {
+        let dir = TempDir::new();
+        let path = workbook(&dir);
+        let conn = Connection::open_in_memory().unwrap();
+
+        let schemas = ExcelIngestor::new()
+            .load_sheets(&conn, &path, "sales", &options(Some("Q1 Sales")))
+            .unwrap();
+
+        assert_eq!(schemas.len(), 1);
+        assert_eq!(schemas[0].name, "sales");
+        assert_eq!(
+            column_types(&schemas[0]),
+            [
+                ("region", &DataType::String, false),
+                ("units", &DataType::BigInt, false),
+                ("price", &DataType::Double, false),
+                // Numbers and text in one column can only be kept as text
+                ("code", &DataType::String, false),
+                ("shipped", &DataType::Date, false),
+                ("ordered_at", &DataType::Timestamp, false),
+                ("paid", &DataType::Boolean, false),
+            ]
+        );
+
+        let rows: Vec<(String, i64, f64, String, String, String, bool)> = conn
+            .prepare(
+                "SELECT region, units, price, code, CAST(shipped AS VARCHAR), \
+                 CAST(ordered_at AS VARCHAR), paid FROM sales ORDER BY region",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            rows,
+            [
+                ("EU".into(), 3, 9.5, "101".into(), "2024-01-31".into(), "2024-01-30 14:30:00".into(), true),
+                ("US".into(), 5, 12.0, "A-7".into(), "2024-02-01".into(), "2024-02-01 09:00:00".into(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_every_sheet_into_its_own_table() {
+        let dir = TempDir::new();
+        let path = workbook(&dir);
+        let conn = Connection::open_in_memory().unwrap();
+
+        let schemas = ExcelIngestor::new()
+            .load_sheets(&conn, &path, "book", &options(None))
+            .unwrap();
+
+        // The empty sheet is skipped
+        let names: Vec<&str> = schemas.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["book_Q1_Sales", "book_Returns"]);
+        assert_eq!(table_names(&conn), ["book_Q1_Sales", "book_Returns"]);
+
+        let reasons: Vec<String> = conn
+            .prepare("SELECT reason FROM book_Returns")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reasons, ["damaged"]);
+    }
+
+    #[test]
+    fn loads_only_the_chosen_sheet() {
+        let dir = TempDir::new();
+        let path = workbook(&dir);
+        let conn = Connection::open_in_memory().unwrap();
+
+        let schemas = ExcelIngestor::new()
+            .load_sheets(&conn, &path, "returns", &options(Some("Returns")))
+            .unwrap();
+
+        assert_eq!(schemas.len(), 1);
+        assert_eq!(
+            column_types(&schemas[0]),
+            [("order_id", &DataType::BigInt, false), ("reason", &DataType::String, false)]
+        );
+        assert_eq!(table_names(&conn), ["returns"]);
+    }
+
+    #[test]
+    fn rejects_a_sheet_the_workbook_does_not_have() {
+        let dir = TempDir::new();
+        let path = workbook(&dir);
+        let conn = Connection::open_in_memory().unwrap();
+
+        match ExcelIngestor::new().load_sheets(&conn, &path, "book", &options(Some("Q2 Sales"))) {
+            Err(IngestError::ParseError(message)) => {
+                assert!(message.contains("'Q2 Sales' not found"), "{}", message);
+                assert!(message.contains("Q1 Sales, Notes, Returns"), "{}", message);
+            }
+            other => panic!("expected a parse error, got {:?}", other.map(|s| s.len())),
+        }
+        assert!(table_names(&conn).is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tokio::sync::broadcast;
+
+// Finished jobs kept around for clients that check in late
+const MAX_FINISHED_JOBS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed)
+    }
+}
+
+/// A table written by one uploaded file
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadedTable {
+    pub name: String,
+    // Rows written from the file, not the table's size afterwards
+    pub rows: Option<i64>,
+}
+
+/// An uploaded file written to the subject directory, waiting to be ingested
+#[derive(Debug, Clone)]
+pub struct StagedFile {
+    pub name: String,
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// Progress of one file within an ingest job
+#[derive(Debug, Clone, Serialize)]
+pub struct FileProgress {
+    pub file: String,
+    pub bytes: u64,
+    pub sha256: String,
+    pub state: JobState,
+    pub tables: Vec<LoadedTable>,
+    pub error: Option<String>,
+}
+
+/// A batch of uploaded files being loaded into a subject in the background
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestJob {
+    pub id: String,
+    pub subject: String,
+    pub state: JobState,
+    pub files: Vec<FileProgress>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// Tracks ingest jobs and broadcasts every change to anyone watching
+pub struct JobRegistry {
+    jobs: RwLock<HashMap<String, IngestJob>>,
+    updates: broadcast::Sender<IngestJob>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        let (updates, _) = broadcast::channel(256);
+        Self {
+            jobs: RwLock::new(HashMap::new()),
+            updates,
+        }
+    }
+
+    pub fn create(&self, subject: &str, files: &[StagedFile]) -> IngestJob {
+        let job = IngestJob {
+            id: uuid::Uuid::new_v4().to_string(),
+            subject: subject.to_string(),
+            state: JobState::Queued,
+            files: files
+                .iter()
+                .map(|file| FileProgress {
+                    file: file.name.clone(),
+                    bytes: file.bytes,
+                    sha256: file.sha256.clone(),
+                    state: JobState::Queued,
+                    tables: Vec::new(),
+                    error: None,
+                })
+                .collect(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            finished_at: None,
+        };
+
+        let mut jobs = self.jobs.write().unwrap();
+        prune_finished(&mut jobs);
+        jobs.insert(job.id.clone(), job.clone());
+        job
+    }
+
+    pub fn get(&self, id: &str) -> Option<IngestJob> {
+        self.jobs.read().unwrap().get(id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<IngestJob> {
+        let mut jobs: Vec<IngestJob> = self.jobs.read().unwrap().values().cloned().collect();
+        jobs.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        jobs
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<IngestJob> {
+        self.updates.subscribe()
+    }
+
+    pub fn start(&self, id: &str) {
+        self.update(id, |job| job.state = JobState::Running);
+    }
+
+    pub fn start_file(&self, id: &str, index: usize) {
+        self.update(id, |job| {
+            if let Some(file) = job.files.get_mut(index) {
+                file.state = JobState::Running;
+            }
+        });
+    }
+
+    pub fn complete_file(&self, id: &str, index: usize, tables: Vec<LoadedTable>) {
+        self.update(id, |job| {
+            if let Some(file) = job.files.get_mut(index) {
+                file.state = JobState::Completed;
+                file.tables = tables;
+            }
+        });
+    }
+
+    pub fn fail_file(&self, id: &str, index: usize, error: String) {
+        self.update(id, |job| {
+            if let Some(file) = job.files.get_mut(index) {
+                file.state = JobState::Failed;
+                file.error = Some(error);
+            }
+        });
+    }
+
+    /// Mark the job finished, it fails if any of its files did
+    pub fn finish(&self, id: &str) {
+        self.update(id, |job| {
+            job.state = if job.files.iter().any(|f| f.state == JobState::Failed) {
+                JobState::Failed
+            } else {
+                JobState::Completed
+            };
+            job.finished_at = Some(chrono::Utc::now().to_rfc3339());
+        });
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut IngestJob)) {
+        let snapshot = {
+            let mut jobs = self.jobs.write().unwrap();
+            let Some(job) = jobs.get_mut(id) else {
+                return;
+            };
+            change(job);
+            job.clone()
+        };
+
+        // Nobody listening is fine, the job can still be polled
+        let _ = self.updates.send(snapshot);
+    }
+}
+
+fn prune_finished(jobs: &mut HashMap<String, IngestJob>) {
+    let mut finished: Vec<(String, String)> = jobs
+        .values()
+        .filter(|j| j.state.is_finished())
+        .map(|j| (j.created_at.clone(), j.id.clone()))
+        .collect();
+
+    if finished.len() < MAX_FINISHED_JOBS {
+        return;
+    }
+
+    finished.sort();
+    for (_, id) in finished.iter().take(finished.len() + 1 - MAX_FINISHED_JOBS) {
+        jobs.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn staged(name: &str) -> StagedFile {
+        StagedFile {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            bytes: 10,
+            sha256: "abc".to_string(),
+        }
+    }
+
+    fn loaded(name: &str, rows: i64) -> Vec<LoadedTable> {
+        vec![LoadedTable { name: name.to_string(), rows: Some(rows) }]
+    }
+
+    #[test]
+    fn follows_a_job_through_its_files() {
+        let registry = JobRegistry::new();
+        let job = registry.create("sales", &[staged("orders.csv"), staged("returns.csv")]);
+        assert_eq!(job.state, JobState::Queued);
+        assert!(job.files.iter().all(|f| f.state == JobState::Queued));
+
+        registry.start(&job.id);
+        registry.start_file(&job.id, 0);
+        assert_eq!(registry.get(&job.id).unwrap().state, JobState::Running);
+        assert_eq!(registry.get(&job.id).unwrap().files[0].state, JobState::Running);
+
+        registry.complete_file(&job.id, 0, loaded("orders", 3));
+        registry.start_file(&job.id, 1);
+        registry.fail_file(&job.id, 1, "bad file".to_string());
+        registry.finish(&job.id);
+
+        let job = registry.get(&job.id).unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert!(job.finished_at.is_some());
+        assert_eq!(job.files[0].state, JobState::Completed);
+        assert_eq!(job.files[0].tables[0].rows, Some(3));
+        assert_eq!(job.files[1].state, JobState::Failed);
+        assert_eq!(job.files[1].error.as_deref(), Some("bad file"));
+    }
+
+    #[test]
+    fn completes_jobs_whose_files_all_loaded() {
+        let registry = JobRegistry::new();
+        let job = registry.create("sales", &[staged("orders.csv")]);
+
+        registry.start(&job.id);
+        registry.complete_file(&job.id, 0, loaded("orders", 3));
+        registry.finish(&job.id);
+
+        assert_eq!(registry.get(&job.id).unwrap().state, JobState::Completed);
+    }
+
+    #[test]
+    fn broadcasts_every_change() {
+        let registry = JobRegistry::new();
+        let mut updates = registry.subscribe();
+        let job = registry.create("sales", &[staged("orders.csv")]);
+
+        registry.start(&job.id);
+        registry.start_file(&job.id, 0);
+        registry.complete_file(&job.id, 0, loaded("orders", 3));
+        registry.finish(&job.id);
+        // Changes to jobs the registry doesn't know aren't sent
+        registry.start("unknown");
+
+        let states: Vec<(JobState, JobState)> = std::iter::from_fn(|| updates.try_recv().ok())
+            .map(|update| {
+                assert_eq!(update.id, job.id);
+                (update.state, update.files[0].state)
+            })
+            .collect();
+        assert_eq!(
+            states,
+            [
+                (JobState::Running, JobState::Queued),
+                (JobState::Running, JobState::Running),
+                (JobState::Running, JobState::Completed),
+                (JobState::Completed, JobState::Completed),
+            ]
+        );
+        assert!(matches!(updates.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[test]
+    fn keeps_a_limited_number_of_finished_jobs() {
+        let registry = JobRegistry::new();
+
+        let running = registry.create("sales", &[staged("orders.csv")]);
+        registry.start(&running.id);
+
+        let mut finished = Vec::new();
+        for _ in 0..MAX_FINISHED_JOBS {
+            let job = registry.create("sales", &[staged("orders.csv")]);
+            registry.finish(&job.id);
+            finished.push(job.id);
+        }
+        // Creation times are only as fine as the clock, so spell out which jobs are oldest
+        {
+            let mut jobs = registry.jobs.write().unwrap();
+            for (index, id) in finished.iter().enumerate() {
+                jobs.get_mut(id).unwrap().created_at = format!("2024-01-01T00:00:{:02}Z", index);
+            }
+            jobs.get_mut(&running.id).unwrap().created_at = "2023-01-01T00:00:00Z".to_string();
+        }
+        assert_eq!(registry.list().len(), MAX_FINISHED_JOBS + 1);
+
+        let newest = registry.create("sales", &[staged("orders.csv")]);
+
+        assert_eq!(registry.list().len(), MAX_FINISHED_JOBS + 1);
+        assert!(registry.get(&finished[0]).is_none());
+        assert!(registry.get(&finished[1]).is_some());
+        // Jobs still running are never dropped
+        assert!(registry.get(&running.id).is_some());
+        assert!(registry.get(&newest.id).is_some());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::ingest::schema::{ColumnSchema, DataType, TableSchema};
+use crate::ingest::{writer, FileIngestor, IngestError, IngestOptions};
+use duckdb::Connection;
+use std::collections::HashSet;
+use std::path::Path;
+
+// Nested structs deeper than this are kept as STRUCT columns when flattening
+const MAX_FLATTEN_DEPTH: usize = 5;
+
+pub struct JsonIngestor {
+    sample_size: usize,
+    flatten_structs: bool,
+}
+
+// A column in the SELECT list used to load the file
+struct JsonColumn {
+    expr: String,
+    name: String,
+    data_type: String,
+    nullable: bool,
+}
+
+impl JsonIngestor {
+    pub fn new() -> Self {
+        Self {
+            sample_size: 1000, // Default sample size for schema inference
+            flatten_structs: false,
+        }
+    }
+
+    /// Expand nested objects into one column per field, named `parent_child`
+    pub fn with_flatten_structs(mut self, flatten_structs: bool) -> Self {
+        self.flatten_structs = flatten_structs;
+        self
+    }
+
+    // Build the read_json_auto call for this file, NDJSON files are read line by line
+    fn source(&self, path: &Path) -> String {
+        let format = match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+        {
+            Some("ndjson") | Some("jsonl") => "newline_delimited",
+            _ => "auto",
+        };
+
+        format!(
+            "read_json_auto('{}', format='{}', sample_size={})",
+            path.to_string_lossy().replace('\'', "''"),
+            format,
+            self.sample_size
+        )
+    }
+
+    // Describe the columns produced by a SELECT list over the source
+    fn describe(
+        conn: &Connection,
+        select_list: &str,
+        source: &str,
+    ) -> Result<Vec<(String, String, bool)>, IngestError> {
+        let mut stmt = conn
+            .prepare(&format!("DESCRIBE SELECT {} FROM {}", select_list, source))
+            .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?, // column_name
+                    row.get::<_, String>(1)?, // column_type
+                    row.get::<_, String>(2)? == "YES", // null
+                ))
+            })
+            .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+        let columns: Result<Vec<_>, _> = rows.collect();
+        columns.map_err(|e| IngestError::DatabaseError(e.to_string()))
+    }
+
+    fn infer_columns(&self, conn: &Connection, path: &Path) -> Result<Vec<JsonColumn>, IngestError> {
+        let source = self.source(path);
+
+        let mut columns: Vec<JsonColumn> = Self::describe(conn, "*", &source)?
+            .into_iter()
+            .map(|(name, data_type, nullable)| JsonColumn {
+                expr: format!("\"{}\"", name.replace('"', "\"\"")),
+                name,
+                data_type,
+                nullable,
+            })
+            .collect();
+
+        if !self.flatten_structs {
+            return Ok(columns);
+        }
+
+        // Names compare case-insensitively in DuckDB, a flattened field never takes a name
+        // already in use, the file's own columns keep theirs
+        let mut taken: HashSet<String> = columns.iter().map(|c| c.name.to_lowercase()).collect();
+
+        // Expand struct columns one level at a time until none are left
+        for _ in 0..MAX_FLATTEN_DEPTH {
+            if !columns.iter().any(|c| c.data_type.starts_with("STRUCT")) {
+                break;
+            }
+
+            let mut flattened = Vec::new();
+            for column in columns {
+                if !column.data_type.starts_with("STRUCT") {
+                    flattened.push(column);
+                    continue;
+                }
+
+                let fields = Self::describe(conn, &format!("unnest({})", column.expr), &source)?;
+                for (field, data_type, _) in fields {
+                    flattened.push(JsonColumn {
+                        expr: format!("{}.\"{}\"", column.expr, field.replace('"', "\"\"")),
+                        name: unique_name(format!("{}_{}", column.name, field), &mut taken),
+                        data_type,
+                        // A missing parent object makes every field null
+                        nullable: true,
+                    });
+                }
+            }
+            columns = flattened;
+        }
+
+        Ok(columns)
+    }
+
+    fn select_list(columns: &[JsonColumn]) -> String {
+        columns
+            .iter()
+            .map(|c| format!("{} AS \"{}\"", c.expr, c.name.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// Adds a numbered suffix to a name that is already taken, and takes the result
+fn unique_name(name: String, taken: &mut HashSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut suffix = 2;
+    while !taken.insert(candidate.to_lowercase()) {
+        candidate = format!("{}_{}", name, suffix);
+        suffix += 1;
+    }
+    candidate
+}
+
+// Implement Send + Sync safely
+unsafe impl Send for JsonIngestor {}
+unsafe impl Sync for JsonIngestor {}
+
+impl FileIngestor for JsonIngestor {
+    fn ingest(
+        &self,
+        path: &Path,
+        table_name: &str,
+        subject: &str,
+        options: &IngestOptions,
+    ) -> Result<TableSchema, IngestError> {
+        // Get the absolute path to the JSON file for DuckDB
+        let absolute_path = path.canonicalize().map_err(IngestError::IoError)?;
+
+        // Infer the schema with a temporary in-memory connection
+        let columns = {
+            let conn = Connection::open_in_memory()
+                .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+            self.infer_columns(&conn, &absolute_path)?
+        };
+
+        if columns.is_empty() {
+            return Err(IngestError::DatabaseError(
+                "No columns found in JSON file".to_string(),
+            ));
+        }
+
+        let mut schema = TableSchema {
+            name: table_name.to_string(),
+            columns: columns
+                .iter()
+                .map(|c| ColumnSchema {
+                    name: c.name.clone(),
+                    data_type: DataType::from_duckdb_type(&c.data_type),
+                    nullable: c.nullable,
+                })
+                .collect(),
+            rows_written: None,
+        };
+
+        // Build the path to the subject database
+        let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
+        let subject_dir = Path::new(&data_dir).join(subject);
+        if !subject_dir.exists() {
+            std::fs::create_dir_all(&subject_dir).map_err(IngestError::IoError)?;
+        }
+
+        let db_path = subject_dir.join(format!("{}.duckdb", subject));
+
+        tracing::info!("Opening subject database at: {}", db_path.display());
+
+        // Connect directly to the subject database
+        let conn =
+            Connection::open(&db_path).map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+        tracing::info!(
+            "Ingesting JSON file to subject database. Table: {}, File: {}, Flatten: {}",
+            table_name,
+            absolute_path.display(),
+            self.flatten_structs
+        );
+
+        let source_sql = format!(
+            "SELECT {} FROM {}",
+            Self::select_list(&columns),
+            self.source(&absolute_path)
+        );
+
+        let count = writer::write_table(&conn, table_name, &source_sql, options)?;
+        tracing::info!(
+            "Successfully wrote {} rows to table {} ({:?})",
+            count,
+            table_name,
+            options.mode
+        );
+        schema.rows_written = Some(count);
+
+        Ok(schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("nl-cube-json-{}", uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn file(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(columns: &[JsonColumn]) -> Vec<&str> {
+        columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    // Loads the file the way ingest does and returns its rows as strings
+    fn load(ingestor: &JsonIngestor, path: &Path) -> (Vec<String>, Vec<Vec<String>>) {
+        let conn = Connection::open_in_memory().unwrap();
+        let columns = ingestor.infer_columns(&conn, path).unwrap();
+        let sql = format!(
+            "SELECT {} FROM {}",
+            JsonIngestor::select_list(&columns),
+            ingestor.source(path)
+        );
+        conn.execute(&format!("CREATE TABLE loaded AS {}", sql), []).unwrap();
+
+        let casts = columns
+            .iter()
+            .map(|c| format!("COALESCE(CAST(\"{}\" AS VARCHAR), 'NULL')", c.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM loaded", casts)).unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                (0..columns.len()).map(|i| row.get::<_, String>(i)).collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        (columns.into_iter().map(|c| c.name).collect(), rows)
+    }
+
+    #[test]
+    fn reads_newline_delimited_files() {
+        let dir = TempDir::new();
+        let path = dir.file(
+            "orders.ndjson",
+            "{\"id\": 1, \"region\": \"EU\"}\n{\"id\": 2, \"region\": \"US\"}\n{\"id\": 3}\n",
+        );
+
+        let (names, rows) = load(&JsonIngestor::new(), &path);
+        assert_eq!(names, ["id", "region"]);
+        assert_eq!(rows, [["1", "EU"], ["2", "US"], ["3", "NULL"]]);
+    }
+
+    #[test]
+    fn flattens_nested_objects_into_columns() {
+        let dir = TempDir::new();
+        let path = dir.file(
+            "customers.json",
+            r#"[{"id": 1, "address": {"city": "Oslo", "geo": {"lat": 59.9}}},
+                {"id": 2}]"#,
+        );
+
+        let conn = Connection::open_in_memory().unwrap();
+        let nested = JsonIngestor::new().infer_columns(&conn, &path).unwrap();
+        assert_eq!(names(&nested), ["id", "address"]);
+        assert!(nested[1].data_type.starts_with("STRUCT"));
+
+        let (names, rows) = load(&JsonIngestor::new().with_flatten_structs(true), &path);
+        assert_eq!(names, ["id", "address_city", "address_geo_lat"]);
+        assert_eq!(rows, [["1", "Oslo", "59.9"], ["2", "NULL", "NULL"]]);
+    }
+
+    #[test]
+    fn keeps_structs_below_the_depth_limit() {
+        let dir = TempDir::new();
+        let path = dir.file("deep.json", r#"[{"a": {"b": {"c": {"d": {"e": {"f": {"g": 1}}}}}}}]"#);
+
+        let conn = Connection::open_in_memory().unwrap();
+        let columns = JsonIngestor::new()
+            .with_flatten_structs(true)
+            .infer_columns(&conn, &path)
+            .unwrap();
+
+        assert_eq!(names(&columns), ["a_b_c_d_e_f"]);
+        assert!(columns[0].data_type.starts_with("STRUCT"));
+    }
+
+    #[test]
+    fn renames_flattened_fields_that_clash_with_existing_columns() {
+        let dir = TempDir::new();
+        let path = dir.file(
+            "clash.json",
+            r#"[{"a": {"b": 1, "B_2": 2}, "a_b": 3, "A_b_2": 4}]"#,
+        );
+
+        let (names, rows) = load(&JsonIngestor::new().with_flatten_structs(true), &path);
+        assert_eq!(names, ["a_b_3", "a_B_2_2", "a_b", "A_b_2"]);
+        assert_eq!(rows, [["1", "2", "3", "4"]]);
+    }
+}
@@ -1,7 +1,13 @@
 pub mod csv;
+pub mod excel;
+pub mod jobs;
+pub mod json;
 pub mod parquet;
 pub mod schema;
+pub mod writer;
 
+use crate::config::IngestConfig;
+use serde::Deserialize;
 use std::error::Error;
 use std::fmt;
 use std::path::Path;
@@ -11,6 +17,9 @@ pub enum IngestError {
     IoError(std::io::Error),
     DatabaseError(String),
     UnsupportedFileType(String),
+    ParseError(String),
+    SchemaMismatch(String),
+    InvalidOptions(String),
 }
 
 impl fmt::Display for IngestError {
@@ -19,6 +28,9 @@ impl fmt::Display for IngestError {
             IngestError::IoError(err) => write!(f, "IO error: {}", err),
             IngestError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
             IngestError::UnsupportedFileType(ext) => write!(f, "Unsupported file type: {}", ext),
+            IngestError::ParseError(msg) => write!(f, "Parse error: {}", msg),
+            IngestError::SchemaMismatch(msg) => write!(f, "Schema mismatch: {}", msg),
+            IngestError::InvalidOptions(msg) => write!(f, "Invalid upload options: {}", msg),
         }
     }
 }
@@ -38,12 +50,53 @@ pub trait FileIngestor: Send + Sync {
         path: &Path,
         table_name: &str,
         subject: &str,
+        options: &IngestOptions,
     ) -> Result<schema::TableSchema, IngestError>;
 }
 
+/// How an upload is written when its table already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    #[default]
+    Replace,
+    Append,
+    Upsert,
+}
+
+impl std::str::FromStr for WriteMode {
+    type Err = IngestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "replace" => Ok(WriteMode::Replace),
+            "append" => Ok(WriteMode::Append),
+            "upsert" => Ok(WriteMode::Upsert),
+            other => Err(IngestError::InvalidOptions(format!(
+                "Unknown upload mode '{}', expected replace, append or upsert",
+                other
+            ))),
+        }
+    }
+}
+
+/// Per-upload options sent alongside the files
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IngestOptions {
+    // Only load this sheet from Excel workbooks
+    pub sheet: Option<String>,
+    #[serde(default)]
+    pub mode: WriteMode,
+    // Columns identifying a row when upserting
+    #[serde(default)]
+    pub key_columns: Vec<String>,
+}
+
 pub struct IngestManager {
     csv_ingestor: csv::CsvIngestor,
     parquet_ingestor: parquet::ParquetIngestor,
+    json_ingestor: json::JsonIngestor,
+    excel_ingestor: excel::ExcelIngestor,
 }
 
 impl IngestManager {
@@ -51,23 +104,29 @@ impl IngestManager {
         Self {
             csv_ingestor: csv::CsvIngestor::new(),
             parquet_ingestor: parquet::ParquetIngestor::new(),
+            json_ingestor: json::JsonIngestor::new(),
+            excel_ingestor: excel::ExcelIngestor::new(),
         }
     }
 
-    pub fn with_connection_string() -> Self {
+    pub fn with_config(config: &IngestConfig) -> Self {
         Self {
             csv_ingestor: csv::CsvIngestor::new(),
             parquet_ingestor: parquet::ParquetIngestor::new(),
+            json_ingestor: json::JsonIngestor::new().with_flatten_structs(config.flatten_json),
+            excel_ingestor: excel::ExcelIngestor::new(),
         }
     }
 
     // Updated to include subject parameter and use schema-based table access
+    // Returns every table created, workbooks can produce one per sheet
     pub fn ingest_file(
         &self,
         path: &Path,
         table_name: &str,
         subject: &str,
-    ) -> Result<schema::TableSchema, IngestError> {
+        options: &IngestOptions,
+    ) -> Result<Vec<schema::TableSchema>, IngestError> {
         let extension = path
             .extension()
             .and_then(|ext| ext.to_str())
@@ -77,7 +136,7 @@ impl IngestManager {
         let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
         let subject_dir = Path::new(&data_dir).join(subject);
         if !subject_dir.exists() {
-            std::fs::create_dir_all(&subject_dir).map_err(|e| IngestError::IoError(e))?;
+            std::fs::create_dir_all(&subject_dir).map_err(IngestError::IoError)?;
         }
 
         // Log that we've ensured the subject directory exists
@@ -85,8 +144,10 @@ impl IngestManager {
 
         // Proceed with ingestion based on file type
         match extension.to_lowercase().as_str() {
-            "csv" => self.csv_ingestor.ingest(path, table_name, subject),
-            "parquet" => self.parquet_ingestor.ingest(path, table_name, subject),
+            "csv" => Ok(vec![self.csv_ingestor.ingest(path, table_name, subject, options)?]),
+            "parquet" => Ok(vec![self.parquet_ingestor.ingest(path, table_name, subject, options)?]),
+            "json" | "ndjson" | "jsonl" => Ok(vec![self.json_ingestor.ingest(path, table_name, subject, options)?]),
+            "xlsx" | "xlsm" | "xls" => self.excel_ingestor.ingest_sheets(path, table_name, subject, options),
             _ => Err(IngestError::UnsupportedFileType(extension.to_string())),
         }
     }
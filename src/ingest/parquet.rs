@@ -1,5 +1,5 @@
 use crate::ingest::schema::{ColumnSchema, DataType, TableSchema};
-use crate::ingest::{FileIngestor, IngestError};
+use crate::ingest::{writer, FileIngestor, IngestError, IngestOptions};
 use duckdb::Connection;
 use std::path::Path;
 
@@ -56,16 +56,7 @@ impl ParquetIngestor {
 
                 Ok(ColumnSchema {
                     name: row.get(1)?,
-                    data_type: match row.get::<_, String>(2)?.to_lowercase().as_str() {
-                        "integer" => DataType::Integer,
-                        "bigint" => DataType::BigInt,
-                        "double" => DataType::Double,
-                        "varchar" | "text" => DataType::String,
-                        "boolean" => DataType::Boolean,
-                        "date" => DataType::Date,
-                        "timestamp" => DataType::Timestamp,
-                        other => DataType::Unknown(other.to_string()),
-                    },
+                    data_type: DataType::from_duckdb_type(&row.get::<_, String>(2)?),
                     nullable: !is_not_null, // If is_not_null is true, then the column is not nullable
                 })
             })
@@ -81,6 +72,7 @@ impl ParquetIngestor {
         Ok(TableSchema {
             name: file_stem,
             columns,
+            rows_written: None,
         })
     }
 }
@@ -95,19 +87,20 @@ impl FileIngestor for ParquetIngestor {
         path: &Path,
         table_name: &str,
         subject: &str,
+        options: &IngestOptions,
     ) -> Result<TableSchema, IngestError> {
         // First infer the schema
         let mut schema = self.infer_schema(path)?;
         schema.name = table_name.to_string();
 
         // Get the absolute path to the Parquet file for DuckDB
-        let absolute_path = path.canonicalize().map_err(|e| IngestError::IoError(e))?;
+        let absolute_path = path.canonicalize().map_err(IngestError::IoError)?;
 
         // Build the path to the subject database
         let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
         let subject_dir = Path::new(&data_dir).join(subject);
         if !subject_dir.exists() {
-            std::fs::create_dir_all(&subject_dir).map_err(|e| IngestError::IoError(e))?;
+            std::fs::create_dir_all(&subject_dir).map_err(IngestError::IoError)?;
         }
 
         let db_path = subject_dir.join(format!("{}.duckdb", subject));
@@ -125,49 +118,21 @@ impl FileIngestor for ParquetIngestor {
             absolute_path.display()
         );
 
-        // Create a more robust create table statement with explicit DROP IF EXISTS
-        let drop_sql = format!("DROP TABLE IF EXISTS \"{}\"", table_name);
-
-        // First drop the table if it exists
-        conn.execute(&drop_sql, []).map_err(|e| {
-            IngestError::DatabaseError(format!("Failed to drop existing table: {}", e))
-        })?;
-
-        // Now use DuckDB's Parquet reading to create the table directly
+        // Use DuckDB's Parquet reading to load the table directly
         // Add additional options to handle large Parquet files better
-        let create_sql = format!(
-            "CREATE TABLE \"{}\" AS SELECT * FROM read_parquet('{}', BINARY_AS_STRING=TRUE, FILENAME=TRUE)",
-            table_name,
+        let source_sql = format!(
+            "SELECT * FROM read_parquet('{}', BINARY_AS_STRING=TRUE, FILENAME=TRUE)",
             absolute_path.to_string_lossy()
         );
 
-        tracing::info!("Executing SQL: {}", create_sql);
-
-        conn.execute(&create_sql, [])
-            .map_err(|e| IngestError::DatabaseError(format!("Failed to create table: {}", e)))?;
-
-        // Verify table was created
-        let verify_sql = format!("SELECT COUNT(*) FROM \"{}\"", table_name);
-
-        match conn.query_row(&verify_sql, [], |row| row.get::<_, i64>(0)) {
-            Ok(count) => {
-                tracing::info!(
-                    "Successfully created table {} with {} rows",
-                    table_name,
-                    count
-                );
-            }
-            Err(e) => {
-                tracing::error!("Table creation verification failed: {}", e);
-                return Err(IngestError::DatabaseError(format!(
-                    "Table verification failed: {}",
-                    e
-                )));
-            }
-        }
-
-        // Wait a small amount of time for DuckDB to complete any background operations
-        std::thread::sleep(std::time::Duration::from_millis(300));
+        let count = writer::write_table(&conn, table_name, &source_sql, options)?;
+        tracing::info!(
+            "Successfully wrote {} rows to table {} ({:?})",
+            count,
+            table_name,
+            options.mode
+        );
+        schema.rows_written = Some(count);
 
         Ok(schema)
     }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     Integer,
     BigInt,
@@ -12,7 +12,21 @@ pub enum DataType {
     Unknown(String),
 }
 
-impl DataType {}
+impl DataType {
+    /// Map a DuckDB column type name onto the types we track
+    pub fn from_duckdb_type(type_name: &str) -> Self {
+        match type_name.to_lowercase().as_str() {
+            "integer" => DataType::Integer,
+            "bigint" => DataType::BigInt,
+            "double" => DataType::Double,
+            "varchar" | "text" => DataType::String,
+            "boolean" => DataType::Boolean,
+            "date" => DataType::Date,
+            "timestamp" => DataType::Timestamp,
+            other => DataType::Unknown(other.to_string()),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColumnSchema {
@@ -27,6 +41,9 @@ impl ColumnSchema {}
 pub struct TableSchema {
     pub name: String,
     pub columns: Vec<ColumnSchema>,
+    // Rows written to the table from the file
+    #[serde(default)]
+    pub rows_written: Option<i64>,
 }
 
 impl TableSchema {}
@@ -0,0 +1,461 @@
+use crate::ingest::{IngestError, IngestOptions, WriteMode};
+use duckdb::Connection;
+
+const STAGING_TABLE: &str = "nl_cube_staging";
+
+/// Write the rows of `source_sql` (a SELECT over the uploaded file) into `table_name`
+/// according to the upload's write mode. Returns the number of rows the upload wrote, which for
+/// an upsert includes the rows that replaced stored ones.
+pub fn write_table(
+    conn: &Connection,
+    table_name: &str,
+    source_sql: &str,
+    options: &IngestOptions,
+) -> Result<i64, IngestError> {
+    let table = quote(table_name);
+    let exists = table_exists(conn, table_name)?;
+
+    match options.mode {
+        WriteMode::Replace => {
+            execute(conn, &format!("DROP TABLE IF EXISTS {}", table), "Failed to drop existing table")?;
+            create_from(conn, &table, source_sql)
+        }
+        WriteMode::Append if !exists => create_from(conn, &table, source_sql),
+        WriteMode::Append => {
+            check_compatible(conn, table_name, source_sql, &[])?;
+
+            let insert_sql = format!("INSERT INTO {} BY NAME {}", table, source_sql);
+            tracing::info!("Executing SQL: {}", insert_sql);
+            execute(conn, &insert_sql, "Failed to append rows")
+        }
+        WriteMode::Upsert => {
+            if options.key_columns.is_empty() {
+                return Err(IngestError::InvalidOptions(
+                    "Upsert mode needs at least one key column".to_string(),
+                ));
+            }
+
+            if !exists {
+                create_from(conn, &table, source_sql)
+            } else {
+                check_compatible(conn, table_name, source_sql, &options.key_columns)?;
+                upsert(conn, &table, source_sql, &options.key_columns)
+            }
+        }
+    }
+}
+
+fn upsert(
+    conn: &Connection,
+    table: &str,
+    source_sql: &str,
+    key_columns: &[String],
+) -> Result<i64, IngestError> {
+    let keys = key_columns.iter().map(|k| quote(k)).collect::<Vec<_>>().join(", ");
+    // Rows with a NULL key still replace the stored row with the same NULL key
+    let key_match = key_columns
+        .iter()
+        .map(|k| format!("{}.{} IS NOT DISTINCT FROM s.{}", table, quote(k), quote(k)))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+
+    // Stage the file once so it isn't read twice, then swap matching rows in a transaction
+    execute(
+        conn,
+        &format!("CREATE OR REPLACE TEMP TABLE {} AS {}", STAGING_TABLE, source_sql),
+        "Failed to stage upload",
+    )?;
+
+    let duplicate = conn.query_row(
+        &format!(
+            "SELECT CAST(ROW({}) AS VARCHAR) FROM {} GROUP BY ALL HAVING COUNT(*) > 1 LIMIT 1",
+            keys, STAGING_TABLE
+        ),
+        [],
+        |row| row.get::<_, String>(0),
+    );
+    let duplicate = match duplicate {
+        Ok(key) => Some(key),
+        Err(duckdb::Error::QueryReturnedNoRows) => None,
+        Err(e) => {
+            return Err(IngestError::DatabaseError(format!(
+                "Failed to check key columns: {}",
+                e
+            )))
+        }
+    };
+    if let Some(duplicate) = duplicate {
+        let _ = conn.execute(&format!("DROP TABLE IF EXISTS {}", STAGING_TABLE), []);
+        return Err(IngestError::InvalidOptions(format!(
+            "Key {} appears more than once in the uploaded file",
+            duplicate
+        )));
+    }
+
+    let staged = count_rows(conn, STAGING_TABLE)?;
+
+    let result = conn.execute_batch(&format!(
+        "BEGIN TRANSACTION;
+         DELETE FROM {table} USING {staging} s WHERE {key_match};
+         INSERT INTO {table} BY NAME SELECT * FROM {staging};
+         COMMIT;",
+        table = table,
+        staging = STAGING_TABLE,
+        key_match = key_match
+    ));
+
+    if let Err(e) = result {
+        let _ = conn.execute_batch("ROLLBACK");
+        let _ = conn.execute(&format!("DROP TABLE IF EXISTS {}", STAGING_TABLE), []);
+        return Err(IngestError::DatabaseError(format!("Failed to upsert rows: {}", e)));
+    }
+
+    execute(conn, &format!("DROP TABLE IF EXISTS {}", STAGING_TABLE), "Failed to drop staging table")?;
+    Ok(staged)
+}
+
+// Make sure the upload has the same columns as the table, with types that can be stored in it
+fn check_compatible(
+    conn: &Connection,
+    table_name: &str,
+    source_sql: &str,
+    key_columns: &[String],
+) -> Result<(), IngestError> {
+    let existing = describe(conn, &format!("SELECT * FROM {}", quote(table_name)))?;
+    let incoming = describe(conn, source_sql)?;
+
+    let mut problems = Vec::new();
+
+    for (name, _) in &existing {
+        if !incoming.iter().any(|(n, _)| n == name) {
+            problems.push(format!("missing column '{}'", name));
+        }
+    }
+
+    for (name, incoming_type) in &incoming {
+        match existing.iter().find(|(n, _)| n == name) {
+            None => problems.push(format!("unexpected column '{}'", name)),
+            Some((_, existing_type)) if !types_compatible(existing_type, incoming_type) => {
+                problems.push(format!(
+                    "column '{}' is {} in the file but {} in the table",
+                    name, incoming_type, existing_type
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    for key in key_columns {
+        if !existing.iter().any(|(n, _)| n == key) {
+            problems.push(format!("key column '{}' is not in the table", key));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(IngestError::SchemaMismatch(format!(
+            "Upload doesn't match table '{}': {}",
+            table_name,
+            problems.join("; ")
+        )))
+    }
+}
+
+// Whether every value of the incoming type can be stored in the existing column unchanged
+fn types_compatible(existing: &str, incoming: &str) -> bool {
+    if existing == incoming || existing == "VARCHAR" || (existing == "TIMESTAMP" && incoming == "DATE") {
+        return true;
+    }
+
+    // Numbers may only widen, narrowing would round or overflow them on insert
+    match (numeric_type(incoming), numeric_type(existing)) {
+        (Some(incoming), Some(existing)) => incoming.widens_to(existing),
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Numeric {
+    Integer { signed: bool, bits: u32 },
+    Float { mantissa_bits: u32 },
+    Decimal { precision: u32, scale: u32 },
+}
+
+impl Numeric {
+    fn widens_to(self, target: Numeric) -> bool {
+        match (self, target) {
+            (Numeric::Integer { signed, bits }, Numeric::Integer { signed: target_signed, bits: target_bits }) => {
+                match (signed, target_signed) {
+                    (true, false) => false,
+                    // An unsigned value needs the sign bit of a signed column free
+                    (false, true) => bits < target_bits,
+                    _ => bits <= target_bits,
+                }
+            }
+            (Numeric::Integer { signed, bits }, Numeric::Float { mantissa_bits }) => {
+                bits - u32::from(signed) <= mantissa_bits
+            }
+            (Numeric::Integer { signed, bits }, Numeric::Decimal { precision, scale }) => {
+                integer_digits(signed, bits) <= precision - scale
+            }
+            (Numeric::Float { mantissa_bits }, Numeric::Float { mantissa_bits: target_bits }) => {
+                mantissa_bits <= target_bits
+            }
+            (Numeric::Decimal { precision, scale }, Numeric::Decimal { precision: target_precision, scale: target_scale }) => {
+                scale <= target_scale && precision - scale <= target_precision - target_scale
+            }
+            (Numeric::Decimal { precision, .. }, Numeric::Float { mantissa_bits }) => {
+                precision <= float_digits(mantissa_bits)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn numeric_type(type_name: &str) -> Option<Numeric> {
+    let integer = |signed, bits| Some(Numeric::Integer { signed, bits });
+    match type_name {
+        "TINYINT" => integer(true, 8),
+        "SMALLINT" => integer(true, 16),
+        "INTEGER" => integer(true, 32),
+        "BIGINT" => integer(true, 64),
+        "HUGEINT" => integer(true, 128),
+        "UTINYINT" => integer(false, 8),
+        "USMALLINT" => integer(false, 16),
+        "UINTEGER" => integer(false, 32),
+        "UBIGINT" => integer(false, 64),
+        "UHUGEINT" => integer(false, 128),
+        "FLOAT" => Some(Numeric::Float { mantissa_bits: 24 }),
+        "DOUBLE" => Some(Numeric::Float { mantissa_bits: 53 }),
+        _ => {
+            let (precision, scale) = type_name
+                .strip_prefix("DECIMAL(")?
+                .strip_suffix(')')?
+                .split_once(',')?;
+            Some(Numeric::Decimal {
+                precision: precision.trim().parse().ok()?,
+                scale: scale.trim().parse().ok()?,
+            })
+        }
+    }
+}
+
+// Decimal digits needed for the largest value of an integer type
+fn integer_digits(signed: bool, bits: u32) -> u32 {
+    match (signed, bits) {
+        (_, 8) => 3,
+        (_, 16) => 5,
+        (_, 32) => 10,
+        (true, 64) => 19,
+        (false, 64) => 20,
+        _ => 39,
+    }
+}
+
+// Decimal digits a float type holds exactly
+fn float_digits(mantissa_bits: u32) -> u32 {
+    (mantissa_bits - 1) * 30103 / 100_000
+}
+
+fn describe(conn: &Connection, select_sql: &str) -> Result<Vec<(String, String)>, IngestError> {
+    let mut stmt = conn
+        .prepare(&format!("DESCRIBE {}", select_sql))
+        .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+        .map_err(|e| IngestError::DatabaseError(e.to_string()))?;
+
+    let columns: Result<Vec<_>, _> = rows.collect();
+    columns.map_err(|e| IngestError::DatabaseError(e.to_string()))
+}
+
+fn table_exists(conn: &Connection, table_name: &str) -> Result<bool, IngestError> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM information_schema.tables WHERE table_name = ?",
+        [table_name],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+    .map_err(|e| IngestError::DatabaseError(e.to_string()))
+}
+
+fn create_from(conn: &Connection, table: &str, source_sql: &str) -> Result<i64, IngestError> {
+    let create_sql = format!("CREATE TABLE {} AS {}", table, source_sql);
+    tracing::info!("Executing SQL: {}", create_sql);
+    execute(conn, &create_sql, "Failed to create table")?;
+    count_rows(conn, table)
+}
+
+fn count_rows(conn: &Connection, table: &str) -> Result<i64, IngestError> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map_err(|e| IngestError::DatabaseError(format!("Table verification failed: {}", e)))
+}
+
+// Runs a statement, returning how many rows it changed
+fn execute(conn: &Connection, sql: &str, context: &str) -> Result<i64, IngestError> {
+    conn.execute(sql, [])
+        .map(|changed| changed as i64)
+        .map_err(|e| IngestError::DatabaseError(format!("{}: {}", context, e)))
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(mode: WriteMode, key_columns: &[&str]) -> IngestOptions {
+        IngestOptions {
+            mode,
+            key_columns: key_columns.iter().map(|k| k.to_string()).collect(),
+            ..IngestOptions::default()
+        }
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<(Option<i32>, String)> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn only_accepts_widening_types() {
+        for (existing, incoming) in [
+            ("BIGINT", "INTEGER"),
+            ("INTEGER", "SMALLINT"),
+            ("BIGINT", "UINTEGER"),
+            ("DOUBLE", "INTEGER"),
+            ("DOUBLE", "FLOAT"),
+            ("DECIMAL(18,2)", "INTEGER"),
+            ("DECIMAL(18,4)", "DECIMAL(10,2)"),
+            ("DOUBLE", "DECIMAL(10,2)"),
+            ("TIMESTAMP", "DATE"),
+            ("VARCHAR", "DOUBLE"),
+            ("DATE", "DATE"),
+        ] {
+            assert!(types_compatible(existing, incoming), "{} into {}", incoming, existing);
+        }
+
+        for (existing, incoming) in [
+            ("INTEGER", "DOUBLE"),
+            ("INTEGER", "BIGINT"),
+            ("SMALLINT", "BIGINT"),
+            ("INTEGER", "UINTEGER"),
+            ("UINTEGER", "INTEGER"),
+            ("DOUBLE", "BIGINT"),
+            ("FLOAT", "DOUBLE"),
+            ("DECIMAL(10,2)", "DECIMAL(18,2)"),
+            ("DECIMAL(10,2)", "DECIMAL(10,4)"),
+            ("DECIMAL(9,2)", "INTEGER"),
+            ("INTEGER", "DECIMAL(5,2)"),
+            ("DATE", "TIMESTAMP"),
+            ("INTEGER", "VARCHAR"),
+        ] {
+            assert!(!types_compatible(existing, incoming), "{} into {}", incoming, existing);
+        }
+    }
+
+    #[test]
+    fn appends_rows_to_new_and_existing_tables() {
+        let conn = Connection::open_in_memory().unwrap();
+        let source = "SELECT * FROM (VALUES (1, 'a'), (2, 'b')) t(id, name)";
+
+        assert_eq!(write_table(&conn, "items", source, &options(WriteMode::Append, &[])).unwrap(), 2);
+        assert_eq!(write_table(&conn, "items", source, &options(WriteMode::Append, &[])).unwrap(), 2);
+
+        // Columns are matched by name, not position
+        let reordered = "SELECT 'c' AS name, 3 AS id";
+        assert_eq!(write_table(&conn, "items", reordered, &options(WriteMode::Append, &[])).unwrap(), 1);
+        assert_eq!(rows(&conn, "SELECT id, name FROM items WHERE id = 3"), vec![(Some(3), "c".to_string())]);
+        assert_eq!(count_rows(&conn, "items").unwrap(), 5);
+    }
+
+    #[test]
+    fn replaces_tables_with_the_uploaded_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE items (id INTEGER); INSERT INTO items VALUES (1), (2), (3)")
+            .unwrap();
+
+        let source = "SELECT 'a' AS name";
+        assert_eq!(write_table(&conn, "items", source, &options(WriteMode::Replace, &[])).unwrap(), 1);
+        assert_eq!(count_rows(&conn, "items").unwrap(), 1);
+    }
+
+    #[test]
+    fn widens_appended_values_into_wider_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE items (id BIGINT, name VARCHAR)").unwrap();
+
+        let source = "SELECT 1::INTEGER AS id, 'a' AS name";
+        assert_eq!(write_table(&conn, "items", source, &options(WriteMode::Append, &[])).unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_uploads_that_would_narrow_or_change_columns() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE items (id INTEGER, name VARCHAR)").unwrap();
+
+        for source in [
+            "SELECT 1.5::DOUBLE AS id, 'a' AS name",
+            "SELECT 5000000000::BIGINT AS id, 'a' AS name",
+            "SELECT 1 AS id",
+            "SELECT 1 AS id, 'a' AS name, 'x' AS extra",
+        ] {
+            for mode in [WriteMode::Append, WriteMode::Upsert] {
+                let result = write_table(&conn, "items", source, &options(mode, &["id"]));
+                assert!(matches!(result, Err(IngestError::SchemaMismatch(_))), "{}: {:?}", source, result);
+            }
+        }
+        assert!(rows(&conn, "SELECT id, name FROM items").is_empty());
+    }
+
+    #[test]
+    fn upserts_rows_by_key() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE items (id INTEGER, name VARCHAR);
+             INSERT INTO items VALUES (1, 'a'), (2, 'b'), (NULL, 'unknown')",
+        )
+        .unwrap();
+
+        let source = "SELECT * FROM (VALUES (2, 'B'), (3, 'c'), (NULL, 'still unknown')) t(id, name)";
+        // Every uploaded row is written, whether it replaced a stored row or not
+        assert_eq!(write_table(&conn, "items", source, &options(WriteMode::Upsert, &["id"])).unwrap(), 3);
+        assert_eq!(
+            rows(&conn, "SELECT id, name FROM items ORDER BY id NULLS LAST"),
+            vec![
+                (Some(1), "a".to_string()),
+                (Some(2), "B".to_string()),
+                (Some(3), "c".to_string()),
+                (None, "still unknown".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_upserts_with_duplicate_or_missing_keys() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE items (id INTEGER, name VARCHAR); INSERT INTO items VALUES (1, 'a')")
+            .unwrap();
+
+        let duplicated = "SELECT * FROM (VALUES (2, 'b'), (2, 'c')) t(id, name)";
+        let result = write_table(&conn, "items", duplicated, &options(WriteMode::Upsert, &["id"]));
+        assert!(matches!(result, Err(IngestError::InvalidOptions(_))), "{:?}", result);
+
+        let source = "SELECT 2 AS id, 'b' AS name";
+        let result = write_table(&conn, "items", source, &options(WriteMode::Upsert, &[]));
+        assert!(matches!(result, Err(IngestError::InvalidOptions(_))), "{:?}", result);
+        let result = write_table(&conn, "items", source, &options(WriteMode::Upsert, &["code"]));
+        assert!(matches!(result, Err(IngestError::SchemaMismatch(_))), "{:?}", result);
+
+        assert_eq!(rows(&conn, "SELECT id, name FROM items"), vec![(Some(1), "a".to_string())]);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::db::db_pool::DuckDBConnectionManager;
+use chrono::{DateTime, Duration, Utc};
+use duckdb::params;
+use r2d2::Pool;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Clone)]
+struct CachedSql {
+    sql: String,
+    created_at: DateTime<Utc>,
+}
+
+/// SQL previously generated for a question, kept in memory and in the main database.
+/// Entries are keyed on the schema the question was asked against and the provider and
+/// model that answered it, so they stop matching as soon as a subject's tables change
+/// or another model is switched to.
+pub struct SqlCache {
+    pool: Pool<DuckDBConnectionManager>,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CachedSql>>,
+}
+
+impl SqlCache {
+    pub fn new(pool: Pool<DuckDBConnectionManager>, ttl_hours: u64) -> Self {
+        Self {
+            pool,
+            ttl: Duration::hours(ttl_hours as i64),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Create the sql_cache table if this is a fresh database
+    pub fn init(&self) -> StoreResult<()> {
+        let conn = self.pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sql_cache (
+                cache_key VARCHAR PRIMARY KEY,
+                question VARCHAR NOT NULL,
+                sql VARCHAR NOT NULL,
+                created_at VARCHAR NOT NULL
+            );",
+        )?;
+
+        info!("SQL cache initialized");
+        Ok(())
+    }
+
+    pub fn get(&self, question: &str, schema: &str, provider: &str) -> Option<String> {
+        let key = cache_key(question, schema, provider);
+
+        let cached = self.entries.lock().unwrap().get(&key).cloned();
+        let cached = match cached {
+            Some(cached) => Some(cached),
+            // Not in memory after a restart, so try the database and remember what it has
+            None => match self.load(&key) {
+                Ok(Some(cached)) => {
+                    self.entries.lock().unwrap().insert(key.clone(), cached.clone());
+                    Some(cached)
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    warn!("Failed to read SQL cache: {}", e);
+                    None
+                }
+            },
+        }?;
+
+        if Utc::now() - cached.created_at > self.ttl {
+            self.remove(question, schema, provider);
+            return None;
+        }
+
+        Some(cached.sql)
+    }
+
+    pub fn insert(&self, question: &str, schema: &str, provider: &str, sql: &str) {
+        let key = cache_key(question, schema, provider);
+        let cached = CachedSql {
+            sql: sql.to_string(),
+            created_at: Utc::now(),
+        };
+
+        let stored = self.pool.get().map_err(|e| e.to_string()).and_then(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sql_cache (cache_key, question, sql, created_at) VALUES (?, ?, ?, ?)",
+                params![key, normalize_question(question), cached.sql, cached.created_at.to_rfc3339()],
+            )
+            .map_err(|e| e.to_string())
+        });
+        if let Err(e) = stored {
+            warn!("Failed to persist cached SQL: {}", e);
+        }
+
+        self.entries.lock().unwrap().insert(key, cached);
+    }
+
+    /// Drop an entry whose SQL no longer works
+    pub fn remove(&self, question: &str, schema: &str, provider: &str) {
+        let key = cache_key(question, schema, provider);
+        self.entries.lock().unwrap().remove(&key);
+
+        if let Err(e) = self
+            .pool
+            .get()
+            .map_err(|e| e.to_string())
+            .and_then(|conn| {
+                conn.execute("DELETE FROM sql_cache WHERE cache_key = ?", [key.as_str()])
+                    .map_err(|e| e.to_string())
+            })
+        {
+            warn!("Failed to remove cached SQL: {}", e);
+        }
+    }
+
+    /// Forget every cached translation, returning how many were stored
+    pub fn purge(&self) -> StoreResult<usize> {
+        self.entries.lock().unwrap().clear();
+
+        let conn = self.pool.get()?;
+        let purged = conn.execute("DELETE FROM sql_cache", [])?;
+
+        info!("Purged {} cached SQL translations", purged);
+        Ok(purged)
+    }
+
+    fn load(&self, key: &str) -> StoreResult<Option<CachedSql>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT sql, created_at FROM sql_cache WHERE cache_key = ?")?;
+        let mut rows = stmt.query_map([key], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let Some((sql, created_at)) = rows.next().transpose()? else {
+            return Ok(None);
+        };
+
+        Ok(Some(CachedSql {
+            sql,
+            created_at: DateTime::parse_from_rfc3339(&created_at)?.with_timezone(&Utc),
+        }))
+    }
+}
+
+// Questions differing only in case, spacing or trailing punctuation share an entry
+fn normalize_question(question: &str) -> String {
+    question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches(['?', '.', '!'])
+        .trim()
+        .to_lowercase()
+}
+
+fn cache_key(question: &str, schema: &str, provider: &str) -> String {
+    let schema_hash = format!("{:x}", Sha256::digest(schema.as_bytes()));
+    format!(
+        "{:x}",
+        Sha256::digest(format!("{}\n{}\n{}", normalize_question(question), schema_hash, provider).as_bytes())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_spacing_and_trailing_punctuation() {
+        assert_eq!(normalize_question("  How many   ORDERS\tper region?? "), "how many orders per region");
+        assert_eq!(normalize_question("Total sales."), "total sales");
+        assert_eq!(normalize_question("Top 5 customers !"), "top 5 customers");
+        // Punctuation inside the question changes its meaning
+        assert_eq!(normalize_question("Sales in U.S. vs. E.U.?"), "sales in u.s. vs. e.u");
+    }
+
+    #[test]
+    fn keys_on_question_schema_and_provider() {
+        let schema = "CREATE TABLE orders (id INTEGER);";
+        let key = cache_key("How many orders?", schema, "ollama (sqlcoder)");
+
+        assert_eq!(key, cache_key("how many   orders", schema, "ollama (sqlcoder)"));
+        assert_ne!(key, cache_key("How many customers?", schema, "ollama (sqlcoder)"));
+        assert_ne!(key, cache_key("How many orders?", "CREATE TABLE orders (id BIGINT);", "ollama (sqlcoder)"));
+        assert_ne!(key, cache_key("How many orders?", schema, "ollama (llama3)"));
+        assert_ne!(key, cache_key("How many orders?", schema, "anthropic (sqlcoder)"));
+    }
+}
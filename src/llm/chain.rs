@@ -0,0 +1,320 @@
+use crate::config::LlmConfig;
+use crate::llm::prompts::{PromptContext, PromptTemplates};
+use crate::llm::{LlmError, SqlGenerator};
+use futures_util::future::join_all;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+// Failures in a row before a provider is skipped, and how long it is skipped for
+const BREAKER_FAILURES: u32 = 3;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Latencies kept per provider for the status page
+const RECENT_LATENCIES: usize = 20;
+
+/// A configured provider and what its recent calls say about its health
+pub struct ChainedProvider {
+    backend: String,
+    model: String,
+    generator: Box<dyn SqlGenerator + Send + Sync>,
+    timeout: Duration,
+    health: Mutex<ProviderHealth>,
+}
+
+#[derive(Default)]
+struct ProviderHealth {
+    consecutive_failures: u32,
+    // While set and in the future the breaker is open and the provider is skipped
+    open_until: Option<Instant>,
+    latencies: VecDeque<Duration>,
+    last_succeeded: Option<bool>,
+    // Result of the last background health check, None if the provider can't be checked
+    last_check: Option<bool>,
+    last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    // Cooled down, the next call decides whether it closes again
+    HalfOpen,
+}
+
+/// How a provider is doing, reported by /api/status
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderStatus {
+    pub backend: String,
+    pub model: String,
+    // From the last background health check when the provider has one, otherwise from its last call
+    pub reachable: Option<bool>,
+    pub circuit: CircuitState,
+    pub consecutive_failures: u32,
+    pub last_latency_ms: Option<u64>,
+    pub avg_latency_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl ChainedProvider {
+    pub fn new(config: &LlmConfig, generator: Box<dyn SqlGenerator + Send + Sync>) -> Self {
+        Self {
+            backend: config.backend.clone(),
+            model: config.model.clone(),
+            generator,
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            health: Mutex::new(ProviderHealth::default()),
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("{} ({})", self.backend, self.model)
+    }
+
+    // Whether the provider may be called now. After the cooldown one caller gets to try it,
+    // everyone else keeps skipping it until that call is over.
+    fn try_acquire(&self) -> bool {
+        let mut health = self.health.lock().unwrap();
+        if health.consecutive_failures < BREAKER_FAILURES {
+            return true;
+        }
+
+        let now = Instant::now();
+        match health.open_until {
+            Some(until) if until > now => false,
+            _ => {
+                health.open_until = Some(now + BREAKER_COOLDOWN);
+                true
+            }
+        }
+    }
+
+    fn record_success(&self, latency: Duration) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.open_until = None;
+        health.last_succeeded = Some(true);
+        health.latencies.push_back(latency);
+        if health.latencies.len() > RECENT_LATENCIES {
+            health.latencies.pop_front();
+        }
+    }
+
+    fn record_failure(&self, error: &LlmError) {
+        let mut health = self.health.lock().unwrap();
+        health.last_succeeded = Some(false);
+        health.last_error = Some(error.to_string());
+
+        // Only an unreachable provider trips the breaker, one that answered badly is still up
+        // and skipping it wouldn't help. Timeouts arrive here as connection errors too.
+        if !matches!(error, LlmError::ConnectionError(_)) {
+            health.consecutive_failures = 0;
+            health.open_until = None;
+            return;
+        }
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= BREAKER_FAILURES {
+            if health.consecutive_failures == BREAKER_FAILURES {
+                warn!("Circuit breaker opened for LLM provider {}", self.name());
+            }
+            health.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
+
+    async fn check_health(&self) {
+        let reachable = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, self.generator.health_check()).await {
+            Ok(Some(result)) => Some(result.is_ok()),
+            Ok(None) => None,
+            Err(_) => Some(false),
+        };
+        self.health.lock().unwrap().last_check = reachable;
+    }
+
+    fn status(&self) -> ProviderStatus {
+        let health = self.health.lock().unwrap();
+        let circuit = match health.open_until {
+            _ if health.consecutive_failures < BREAKER_FAILURES => CircuitState::Closed,
+            Some(until) if until > Instant::now() => CircuitState::Open,
+            _ => CircuitState::HalfOpen,
+        };
+        let avg_latency_ms = (!health.latencies.is_empty()).then(|| {
+            (health.latencies.iter().sum::<Duration>() / health.latencies.len() as u32).as_millis() as u64
+        });
+
+        ProviderStatus {
+            backend: self.backend.clone(),
+            model: self.model.clone(),
+            reachable: health.last_check.or(health.last_succeeded),
+            circuit,
+            consecutive_failures: health.consecutive_failures,
+            last_latency_ms: health.latencies.back().map(|latency| latency.as_millis() as u64),
+            avg_latency_ms,
+            last_error: health.last_error.clone(),
+        }
+    }
+}
+
+/// Providers in the order they are tried, each one a fallback for those before it
+pub struct ProviderChain {
+    providers: Vec<Arc<ChainedProvider>>,
+}
+
+impl ProviderChain {
+    pub fn new(providers: Vec<ChainedProvider>) -> Self {
+        let providers = providers.into_iter().map(Arc::new).collect::<Vec<_>>();
+
+        // Checked in the background so the status page never waits on a slow provider
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(check_health(providers.iter().map(Arc::downgrade).collect()));
+        }
+
+        Self { providers }
+    }
+
+    /// Backends in the chain, in order
+    pub fn names(&self) -> String {
+        self.providers
+            .iter()
+            .map(|provider| provider.backend.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// How each provider is doing, from its recent calls and health checks
+    pub fn status(&self) -> Vec<ProviderStatus> {
+        self.providers.iter().map(|provider| provider.status()).collect()
+    }
+
+    // Try each provider whose breaker lets it, returning the first answer. The prompt is
+    // rendered per provider since each backend can have its own template.
+    pub async fn generate_sql(
+        &self,
+        prompts: &PromptTemplates,
+        context: &PromptContext,
+    ) -> Result<String, LlmError> {
+        let mut failures = Vec::new();
+
+        for provider in &self.providers {
+            // A template that doesn't render is a configuration error, not the provider's fault
+            let prompt = prompts.render_within(context, &provider.backend, |prompt| {
+                provider.generator.prompt_overflow(prompt)
+            })?;
+
+            if !provider.try_acquire() {
+                failures.push(format!("{}: circuit open", provider.name()));
+                continue;
+            }
+
+            let started = Instant::now();
+            let error = match tokio::time::timeout(provider.timeout, provider.generator.generate_sql(&prompt)).await {
+                Ok(Ok(sql)) => {
+                    provider.record_success(started.elapsed());
+                    return Ok(sql);
+                }
+                Ok(Err(e)) => e,
+                Err(_) => LlmError::ConnectionError(format!(
+                    "No response within {} seconds",
+                    provider.timeout.as_secs()
+                )),
+            };
+
+            provider.record_failure(&error);
+            if self.providers.len() == 1 {
+                return Err(error);
+            }
+            info!("LLM provider {} failed, trying the next one: {}", provider.name(), error);
+            failures.push(format!("{}: {}", provider.name(), error));
+        }
+
+        Err(LlmError::ConnectionError(format!(
+            "Every LLM provider failed - {}",
+            failures.join("; ")
+        )))
+    }
+}
+
+// Health checks every provider now and then, until the chain is dropped when another provider is
+// switched to
+async fn check_health(providers: Vec<Weak<ChainedProvider>>) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let live = providers.iter().filter_map(Weak::upgrade).collect::<Vec<_>>();
+        if live.is_empty() {
+            return;
+        }
+        join_all(live.iter().map(|provider| provider.check_health())).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    // Fails every call with a copy of `error`
+    struct Failing(LlmError);
+
+    #[async_trait]
+    impl SqlGenerator for Failing {
+        async fn generate_sql(&self, _prompt: &str) -> Result<String, LlmError> {
+            Err(match &self.0 {
+                LlmError::ConnectionError(msg) => LlmError::ConnectionError(msg.clone()),
+                LlmError::ResponseError(msg) => LlmError::ResponseError(msg.clone()),
+                LlmError::ConfigError(msg) => LlmError::ConfigError(msg.clone()),
+            })
+        }
+    }
+
+    fn chain_failing_with(error: LlmError) -> ProviderChain {
+        let config: LlmConfig =
+            serde_json::from_value(serde_json::json!({ "backend": "stub", "model": "stub" })).unwrap();
+        ProviderChain::new(vec![ChainedProvider::new(&config, Box::new(Failing(error)))])
+    }
+
+    async fn fail_repeatedly(chain: &ProviderChain) {
+        let context = PromptContext {
+            question: "How many orders?".to_string(),
+            ..PromptContext::default()
+        };
+        for _ in 0..BREAKER_FAILURES + 2 {
+            assert!(chain.generate_sql(&PromptTemplates::builtin(), &context).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_providers_open_the_breaker() {
+        let chain = chain_failing_with(LlmError::ConnectionError("refused".to_string()));
+        fail_repeatedly(&chain).await;
+
+        let status = &chain.status()[0];
+        assert_eq!(status.circuit, CircuitState::Open);
+        assert_eq!(status.reachable, Some(false));
+    }
+
+    #[tokio::test]
+    async fn providers_that_answer_keep_the_breaker_closed() {
+        for error in [
+            LlmError::ResponseError("no code block".to_string()),
+            LlmError::ConfigError("invalid x-api-key".to_string()),
+        ] {
+            let chain = chain_failing_with(error);
+            fail_repeatedly(&chain).await;
+
+            let status = &chain.status()[0];
+            assert_eq!(status.circuit, CircuitState::Closed);
+            assert_eq!(status.consecutive_failures, 0);
+            assert!(status.last_error.is_some());
+        }
+    }
+}
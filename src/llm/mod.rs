@@ -1,12 +1,25 @@
+pub mod cache;
+pub mod chain;
 pub mod models;
+pub mod prompts;
 pub mod providers;
 
 use crate::config::LlmConfig;
+use crate::llm::cache::SqlCache;
+use crate::llm::chain::{ChainedProvider, ProviderChain, ProviderStatus};
+use crate::llm::models::SqlAttempt;
+use crate::llm::prompts::{PromptContext, PromptTemplates};
 use async_trait::async_trait;
 use std::error::Error;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum LlmError {
     ConnectionError(String),
     ResponseError(String),
@@ -27,30 +40,508 @@ impl Error for LlmError {}
 
 #[async_trait]
 pub trait SqlGenerator: Send + Sync {
-    async fn generate_sql(&self, question: &str, schema: &str) -> Result<String, LlmError>;
+    /// `prompt` is the rendered prompt template, providers only wrap it the way their API expects.
+    /// Repairs of rejected SQL are asked for through the same template.
+    async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError>;
+
+    /// How many tokens `prompt` is over what the provider can read, for providers with a context
+    /// window small enough to matter
+    fn prompt_overflow(&self, _prompt: &str) -> Result<usize, LlmError> {
+        Ok(0)
+    }
+
+    /// A cheap request showing whether the provider can be reached, None when it has no way to tell
+    async fn health_check(&self) -> Option<Result<(), LlmError>> {
+        None
+    }
+}
+
+/// Every attempt made while generating SQL for a question
+#[derive(Debug)]
+pub struct SqlGeneration {
+    pub attempts: Vec<SqlAttempt>,
+    // The SQL was found in the cache rather than generated
+    pub cached: bool,
+}
+
+impl SqlGeneration {
+    /// The SQL from the last attempt, if it passed validation
+    pub fn sql(&self) -> Option<&str> {
+        self.attempts
+            .last()
+            .filter(|attempt| attempt.error.is_none())
+            .map(|attempt| attempt.sql.as_str())
+    }
+
+    pub fn last_error(&self) -> Option<&str> {
+        self.attempts.last().and_then(|attempt| attempt.error.as_deref())
+    }
 }
 
 pub struct LlmManager {
-    generator: Box<dyn SqlGenerator + Send + Sync>,
+    // What the chain was built from, the active provider first
+    config: LlmConfig,
+    chain: ProviderChain,
+    prompts: Arc<PromptTemplates>,
+    max_attempts: usize,
+    cache: Option<SqlCache>,
+    // Managers built by `with_model`, kept so a model is only set up (and loaded) once
+    model_overrides: Mutex<HashMap<String, Arc<LlmManager>>>,
+}
+
+/// The provider `config` describes, without its fallbacks
+fn build_generator(config: &LlmConfig) -> Result<Box<dyn SqlGenerator + Send + Sync>, LlmError> {
+    let generator: Box<dyn SqlGenerator + Send + Sync> = match config.backend.as_str() {
+        "remote" => Box::new(providers::remote::RemoteLlmProvider::new(config)?),
+        "ollama" => Box::new(providers::ollama::OllamaProvider::new(config)?),
+        "anthropic" => Box::new(providers::anthropic::AnthropicProvider::new(config)?),
+        #[cfg(feature = "local-llm")]
+        "local" => Box::new(providers::local::LocalLlmProvider::new(config)?),
+        #[cfg(not(feature = "local-llm"))]
+        "local" => {
+            return Err(LlmError::ConfigError(
+                "Local LLM backend requires building with the local-llm feature".to_string(),
+            ))
+        }
+        _ => {
+            return Err(LlmError::ConfigError(format!(
+                "Unsupported LLM backend: {}",
+                config.backend
+            )))
+        }
+    };
+
+    Ok(generator)
+}
+
+fn build_chain(config: &LlmConfig) -> Result<ProviderChain, LlmError> {
+    let mut providers = vec![ChainedProvider::new(config, build_generator(config)?)];
+    for fallback in &config.fallbacks {
+        info!("Adding fallback LLM provider: {} ({})", fallback.backend, fallback.model);
+        providers.push(ChainedProvider::new(fallback, build_generator(fallback)?));
+    }
+
+    Ok(ProviderChain::new(providers))
 }
 
 impl LlmManager {
+    /// Manager for the provider in `config`, falling back to its `fallbacks` in order
     pub fn new(config: &LlmConfig) -> Result<Self, LlmError> {
-        let generator: Box<dyn SqlGenerator + Send + Sync> = match config.backend.as_str() {
-            "remote" => Box::new(providers::remote::RemoteLlmProvider::new(config)?),
-            "ollama" => Box::new(providers::ollama::OllamaProvider::new(config)?),
-            _ => {
-                return Err(LlmError::ConfigError(format!(
-                    "Unsupported LLM backend: {}",
-                    config.backend
-                )))
+        Ok(Self {
+            config: config.clone(),
+            chain: build_chain(config)?,
+            prompts: Arc::new(PromptTemplates::builtin()),
+            max_attempts: config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+            cache: None,
+            model_overrides: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Swap in the providers from `config`, queries already running finish on the old ones
+    pub fn switch(&mut self, config: &LlmConfig) -> Result<(), LlmError> {
+        self.chain = build_chain(config)?;
+        self.config = config.clone();
+        self.model_overrides.lock().unwrap().clear();
+        info!("Switched LLM provider to {} ({})", config.backend, config.model);
+        Ok(())
+    }
+
+    /// Whether queries may ask the active provider for `model`: one of the configured models for
+    /// its backend, or for Ollama one installed on the server. Never an arbitrary name, since the
+    /// local backend reads the model from a file of that name.
+    pub async fn allows_model(&self, model: &str) -> bool {
+        let backend = &self.config.backend;
+        let configured = self
+            .config
+            .providers()
+            .iter()
+            .any(|provider| &provider.backend == backend && provider.model == model);
+        if configured || backend != "ollama" {
+            return configured;
+        }
+
+        let api_url = self
+            .config
+            .api_url
+            .as_deref()
+            .unwrap_or(providers::ollama::DEFAULT_API_URL);
+        match providers::ollama::installed_models(api_url).await {
+            Ok(models) => models.iter().any(|installed| installed == model),
+            Err(e) => {
+                warn!("Failed to list Ollama models at {}: {}", api_url, e);
+                false
+            }
+        }
+    }
+
+    /// A manager running `model` on the active provider, with no fallbacks or cache,
+    /// for comparing models on a single query. Check the model with `allows_model` first.
+    pub fn with_model(&self, model: &str) -> Result<Arc<Self>, LlmError> {
+        if let Some(manager) = self.model_overrides.lock().unwrap().get(model) {
+            return Ok(Arc::clone(manager));
+        }
+
+        let config = LlmConfig {
+            model: model.to_string(),
+            fallbacks: Vec::new(),
+            ..self.config.clone()
+        };
+        let manager = Arc::new(Self {
+            prompts: Arc::clone(&self.prompts),
+            ..Self::new(&config)?
+        });
+
+        // Two queries may have built the same model at once, keep whichever got here first
+        let mut overrides = self.model_overrides.lock().unwrap();
+        Ok(Arc::clone(
+            overrides.entry(model.to_string()).or_insert(manager),
+        ))
+    }
+
+    /// The providers queries go to, the active one first
+    pub fn config(&self) -> &LlmConfig {
+        &self.config
+    }
+
+    /// Reuse SQL generated for earlier questions about the same schema
+    pub fn with_cache(mut self, cache: SqlCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn cache(&self) -> Option<&SqlCache> {
+        self.cache.as_ref()
+    }
+
+    /// Render prompts from these templates instead of only the built-in default
+    pub fn with_prompts(mut self, prompts: Arc<PromptTemplates>) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    pub fn prompts(&self) -> Arc<PromptTemplates> {
+        Arc::clone(&self.prompts)
+    }
+
+    /// Backends queries may go to, reported with errors
+    pub fn provider(&self) -> String {
+        self.chain.names()
+    }
+
+    /// Health and recent latency of every provider, health checks included
+    pub fn provider_status(&self) -> Vec<ProviderStatus> {
+        self.chain.status()
+    }
+
+    pub async fn generate_sql(&self, context: &PromptContext) -> Result<String, LlmError> {
+        self.chain.generate_sql(&self.prompts, context).await
+    }
+
+    /// Generates SQL and checks it with `validate`, feeding any error back to the
+    /// generator until the query passes or `max_attempts` is reached.
+    /// Cached SQL for the same question and schema is used when it still validates.
+    /// Once `cancelled` returns true no further attempt is made.
+    pub async fn generate_validated_sql<F, C>(
+        &self,
+        context: &PromptContext,
+        validate: F,
+        cancelled: C,
+    ) -> Result<SqlGeneration, LlmError>
+    where
+        F: Fn(&str) -> Result<(), String>,
+        C: Fn() -> bool,
+    {
+        let (question, schema) = (context.question.as_str(), context.schema.as_str());
+        // SQL is only reused for the provider and model it was generated for
+        let provider = format!("{} ({})", self.config.backend, self.config.model);
+
+        if let Some(cache) = &self.cache
+            && let Some(sql) = cache.get(question, schema, &provider)
+        {
+            match validate(&sql) {
+                Ok(()) => {
+                    info!("Using cached SQL for question");
+                    return Ok(SqlGeneration {
+                        attempts: vec![SqlAttempt {
+                            attempt: 1,
+                            sql,
+                            error: None,
+                        }],
+                        cached: true,
+                    });
+                }
+                Err(e) => {
+                    warn!("Cached SQL no longer validates, regenerating: {}", e);
+                    cache.remove(question, schema, &provider);
+                }
+            }
+        }
+
+        let mut attempts: Vec<SqlAttempt> = Vec::new();
+
+        for attempt in 1..=self.max_attempts {
+            if attempt > 1 && cancelled() {
+                info!("Query was cancelled, not repairing SQL attempt {}", attempt - 1);
+                break;
+            }
+
+            let raw_sql = match attempts.last() {
+                // The template asks for a fix when it is given the rejected SQL and error
+                Some(SqlAttempt {
+                    sql,
+                    error: Some(error),
+                    ..
+                }) => {
+                    let repair = PromptContext {
+                        failed_sql: Some(sql.clone()),
+                        error: Some(error.clone()),
+                        ..context.clone()
+                    };
+                    match self.generate_sql(&repair).await {
+                        Ok(sql) => sql,
+                        // The attempts so far still explain what went wrong, the provider
+                        // failing is recorded as the attempt it couldn't make
+                        Err(e) => {
+                            warn!("SQL repair attempt {} failed: {}", attempt, e);
+                            attempts.push(SqlAttempt {
+                                attempt,
+                                sql: String::new(),
+                                error: Some(e.to_string()),
+                            });
+                            break;
+                        }
+                    }
+                }
+                _ => self.generate_sql(context).await?,
+            };
+
+            // Models sometimes quote identifiers with backticks, which DuckDB rejects
+            let sql = raw_sql.replace("`", "");
+
+            let error = validate(&sql).err();
+            match &error {
+                Some(e) => warn!("SQL attempt {} failed validation: {}", attempt, e),
+                None => info!("SQL attempt {} passed validation", attempt),
+            }
+
+            let passed = error.is_none();
+            attempts.push(SqlAttempt {
+                attempt,
+                sql,
+                error,
+            });
+
+            if passed {
+                break;
             }
+        }
+
+        let generation = SqlGeneration {
+            attempts,
+            cached: false,
         };
 
-        Ok(Self { generator })
+        if let (Some(cache), Some(sql)) = (&self.cache, generation.sql()) {
+            cache.insert(question, schema, &provider, sql);
+        }
+
+        Ok(generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db_pool::DuckDBConnectionManager;
+    use r2d2::Pool;
+    use std::collections::VecDeque;
+
+    const VALID_SQL: &str = "SELECT count(*) FROM orders";
+
+    // Answers with `replies` in order and keeps every prompt it was sent
+    struct Scripted {
+        replies: Mutex<VecDeque<Result<String, LlmError>>>,
+        prompts: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl SqlGenerator for Scripted {
+        async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError> {
+            self.prompts.lock().unwrap().push(prompt.to_string());
+            self.replies
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Err(LlmError::ResponseError("no reply left".to_string())))
+        }
+    }
+
+    fn manager(replies: Vec<Result<String, LlmError>>) -> (LlmManager, Arc<Mutex<Vec<String>>>) {
+        let config: LlmConfig =
+            serde_json::from_value(serde_json::json!({ "backend": "stub", "model": "stub" })).unwrap();
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let generator = Scripted {
+            replies: Mutex::new(replies.into()),
+            prompts: Arc::clone(&prompts),
+        };
+
+        let manager = LlmManager {
+            chain: ProviderChain::new(vec![ChainedProvider::new(&config, Box::new(generator))]),
+            config,
+            prompts: Arc::new(PromptTemplates::builtin()),
+            max_attempts: 3,
+            cache: None,
+            model_overrides: Mutex::new(HashMap::new()),
+        };
+        (manager, prompts)
+    }
+
+    fn cache() -> SqlCache {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(DuckDBConnectionManager::new(":memory:".to_string()))
+            .unwrap();
+        let cache = SqlCache::new(pool, 24);
+        cache.init().unwrap();
+        cache
+    }
+
+    fn context() -> PromptContext {
+        PromptContext {
+            question: "How many orders?".to_string(),
+            schema: "CREATE TABLE orders (id INTEGER);".to_string(),
+            ..PromptContext::default()
+        }
+    }
+
+    fn validate(sql: &str) -> Result<(), String> {
+        if sql == VALID_SQL {
+            Ok(())
+        } else {
+            Err(format!("Catalog Error: can't bind {}", sql))
+        }
     }
 
-    pub async fn generate_sql(&self, question: &str, schema: &str) -> Result<String, LlmError> {
-        self.generator.generate_sql(question, schema).await
+    async fn generate(manager: &LlmManager) -> Result<SqlGeneration, LlmError> {
+        manager.generate_validated_sql(&context(), validate, || false).await
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn repairs_rejected_sql_with_the_error() {
+        let (manager, prompts) = manager(vec![
+            Ok("SELECT count(*) FROM `order`".to_string()),
+            Ok(VALID_SQL.to_string()),
+        ]);
+
+        let generation = generate(&manager).await.unwrap();
+
+        assert_eq!(generation.sql(), Some(VALID_SQL));
+        assert!(!generation.cached);
+        assert_eq!(generation.attempts.len(), 2);
+        // Backticks are dropped before the SQL is checked
+        assert_eq!(generation.attempts[0].sql, "SELECT count(*) FROM order");
+        assert!(generation.attempts[0].error.is_some());
+
+        let prompts = prompts.lock().unwrap();
+        assert!(!prompts[0].contains("which failed with the DuckDB error"));
+        assert!(prompts[1].contains(
+            "`SELECT count(*) FROM order` which failed with the DuckDB error: Catalog Error: can't bind SELECT count(*) FROM order"
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let (manager, prompts) = manager(vec![
+            Ok("SELECT 1 FROM a".to_string()),
+            Ok("SELECT 1 FROM b".to_string()),
+            Ok("SELECT 1 FROM c".to_string()),
+            Ok(VALID_SQL.to_string()),
+        ]);
+
+        let generation = generate(&manager).await.unwrap();
+
+        assert_eq!(generation.sql(), None);
+        assert_eq!(generation.attempts.len(), 3);
+        assert_eq!(generation.last_error(), Some("Catalog Error: can't bind SELECT 1 FROM c"));
+        assert_eq!(prompts.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn keeps_earlier_attempts_when_a_repair_fails() {
+        let (manager, _) = manager(vec![
+            Ok("SELECT 1 FROM a".to_string()),
+            Err(LlmError::ConnectionError("connection refused".to_string())),
+        ]);
+
+        let generation = generate(&manager).await.unwrap();
+
+        assert_eq!(generation.sql(), None);
+        assert_eq!(generation.attempts.len(), 2);
+        assert_eq!(generation.attempts[0].sql, "SELECT 1 FROM a");
+        assert_eq!(generation.attempts[1].attempt, 2);
+        assert_eq!(generation.attempts[1].sql, "");
+        assert!(generation.last_error().unwrap().contains("connection refused"));
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_first_attempt_does() {
+        let (manager, _) = manager(vec![Err(LlmError::ConnectionError("connection refused".to_string()))]);
+
+        assert!(matches!(generate(&manager).await, Err(LlmError::ConnectionError(_))));
+    }
+
+    #[tokio::test]
+    async fn stops_repairing_once_cancelled() {
+        let (manager, prompts) = manager(vec![
+            Ok("SELECT 1 FROM a".to_string()),
+            Ok(VALID_SQL.to_string()),
+        ]);
+
+        let generation = manager
+            .generate_validated_sql(&context(), validate, || true)
+            .await
+            .unwrap();
+
+        assert_eq!(generation.attempts.len(), 1);
+        assert_eq!(generation.sql(), None);
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reuses_cached_sql_while_it_validates() {
+        let cache = cache();
+        let (first, _) = manager(vec![Ok(VALID_SQL.to_string())]);
+        let first = first.with_cache(cache);
+        assert!(!generate(&first).await.unwrap().cached);
+
+        // Answered from the cache without asking the provider
+        let (second, prompts) = manager(Vec::new());
+        let second = second.with_cache(first.cache.unwrap());
+        let generation = generate(&second).await.unwrap();
+        assert!(generation.cached);
+        assert_eq!(generation.sql(), Some(VALID_SQL));
+        assert!(prompts.lock().unwrap().is_empty());
+
+        // Cached SQL that stopped validating is generated again
+        let cache = second.cache.unwrap();
+        let (third, prompts) = manager(vec![Ok(VALID_SQL.to_string())]);
+        let third = third.with_cache(cache);
+        let generation = third
+            .generate_validated_sql(
+                &context(),
+                |sql| {
+                    if prompts.lock().unwrap().is_empty() {
+                        Err(format!("stale: {}", sql))
+                    } else {
+                        validate(sql)
+                    }
+                },
+                || false,
+            )
+            .await
+            .unwrap();
+        assert!(!generation.cached);
+        assert_eq!(generation.sql(), Some(VALID_SQL));
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+    }
+}
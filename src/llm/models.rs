@@ -1,45 +1,52 @@
 use serde::{Deserialize, Serialize};
 
-// Input data for SQL generation
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SqlGenerationInput {
-    pub question: String,
-    pub schema: String,
-}
-
-// Output from SQL generation
+// Output from SQL generation, the shape structured responses from the model must have
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SqlGenerationOutput {
     pub sql: String,
     pub explanation: Option<String>,
+    pub tables_used: Vec<String>,
+    // How sure the model is the SQL answers the question, from 0 to 1
+    pub confidence: f64,
 }
 
-// Structure to represent a query to be executed
-#[derive(Debug, Serialize, Deserialize)]
-pub struct NlQuery {
-    pub question: String,
-    pub generated_sql: Option<String>,
-    pub error: Option<String>,
-    pub created_at: chrono::DateTime<chrono::Utc>,
+// Whether a history entry came from a natural language question or hand-written SQL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryKind {
+    Nl,
+    Sql,
 }
 
-// Structure to represent a saved query with results
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SavedQuery {
-    pub id: String,
-    pub name: String,
-    pub question: String,
-    pub sql: String,
-    pub created_at: chrono::DateTime<chrono::Utc>,
-    pub updated_at: chrono::DateTime<chrono::Utc>,
+impl QueryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QueryKind::Nl => "nl",
+            QueryKind::Sql => "sql",
+        }
+    }
 }
 
-// History item for tracking query execution
-#[derive(Debug, Serialize, Deserialize)]
+// History item for tracking query execution, failed runs included
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueryHistoryItem {
-    pub question: String,
-    pub sql: String,
+    pub id: String,
+    pub kind: QueryKind,
+    pub user: Option<String>,
+    pub subject: Option<String>,
+    pub question: Option<String>,
+    pub sql: Option<String>,
     pub execution_time_ms: u64,
-    pub row_count: usize,
+    pub row_count: Option<usize>,
+    pub error: Option<String>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
+
+// A single SQL generation attempt and the DuckDB error it produced, if any
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqlAttempt {
+    pub attempt: usize,
+    pub sql: String,
+    pub error: Option<String>,
+}
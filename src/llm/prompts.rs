@@ -0,0 +1,414 @@
+use crate::db::db_pool::DuckDBConnectionManager;
+use crate::llm::LlmError;
+use duckdb::params;
+use minijinja::{context, Environment};
+use r2d2::Pool;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const DEFAULT_TEMPLATE: &str = "default";
+const BUILTIN_DEFAULT: &str = include_str!("../../templates/prompts/default.jinja");
+
+const DIALECT: &str = "DuckDB";
+
+// Instructions a render may run, so an edited template with a runaway loop fails instead of
+// tying up the server
+const TEMPLATE_FUEL: u64 = 100_000;
+
+/// Appended to the prompt for completion models, so their reply starts inside a SQL code block
+pub const COMPLETION_SUFFIX: &str =
+    "\n\n### Response:\nHere is the SQL query that answers the question:\n```sql\n";
+
+/// A question answered before, shown to the model as an example
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptExample {
+    pub question: String,
+    pub sql: String,
+}
+
+/// Everything a prompt template can refer to, apart from the dialect and date
+#[derive(Debug, Clone, Default)]
+pub struct PromptContext {
+    pub question: String,
+    pub schema: String,
+    pub subject: Option<String>,
+    pub examples: Vec<PromptExample>,
+    // Set when asking for a fix to SQL that DuckDB rejected
+    pub failed_sql: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptSource {
+    Builtin,
+    File,
+    Database,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub source: PromptSource,
+    pub template: String,
+}
+
+/// Prompt templates by name: `default`, `provider.<backend>` or `subject.<subject>`.
+/// Templates edited through the API are kept in the main database and win over
+/// files in the prompts directory, which win over the built-in default.
+pub struct PromptTemplates {
+    pool: Option<Pool<DuckDBConnectionManager>>,
+    files: HashMap<String, String>,
+    edited: RwLock<HashMap<String, String>>,
+}
+
+impl PromptTemplates {
+    /// Only the built-in default, nothing can be edited
+    pub fn builtin() -> Self {
+        Self {
+            pool: None,
+            files: HashMap::new(),
+            edited: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Templates from `<name>.jinja` files in `dir`, with edits stored through `pool`
+    pub fn new(pool: Pool<DuckDBConnectionManager>, dir: Option<&Path>) -> Self {
+        Self {
+            pool: Some(pool),
+            files: dir.map(load_dir).unwrap_or_default(),
+            edited: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Create the prompt_templates table if this is a fresh database and load the edited templates
+    pub fn init(&self) -> StoreResult<()> {
+        let Some(pool) = &self.pool else {
+            return Ok(());
+        };
+
+        let conn = pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS prompt_templates (
+                name VARCHAR PRIMARY KEY,
+                template VARCHAR NOT NULL,
+                updated_at VARCHAR NOT NULL
+            );",
+        )?;
+
+        let mut stmt = conn.prepare("SELECT name, template FROM prompt_templates")?;
+        let edited = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        info!("Prompt templates initialized ({} edited, {} from files)", edited.len(), self.files.len());
+        *self.edited.write().unwrap() = edited;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<PromptTemplate> {
+        let (source, template) = if let Some(template) = self.edited.read().unwrap().get(name) {
+            (PromptSource::Database, template.clone())
+        } else if let Some(template) = self.files.get(name) {
+            (PromptSource::File, template.clone())
+        } else if name == DEFAULT_TEMPLATE {
+            (PromptSource::Builtin, BUILTIN_DEFAULT.to_string())
+        } else {
+            return None;
+        };
+
+        Some(PromptTemplate {
+            name: name.to_string(),
+            source,
+            template,
+        })
+    }
+
+    pub fn list(&self) -> Vec<PromptTemplate> {
+        let mut names: Vec<String> = self.files.keys().cloned().collect();
+        names.extend(self.edited.read().unwrap().keys().cloned());
+        names.push(DEFAULT_TEMPLATE.to_string());
+        names.sort();
+        names.dedup();
+
+        names.iter().filter_map(|name| self.get(name)).collect()
+    }
+
+    /// The template used for questions about `subject` sent to `backend`
+    pub fn resolve(&self, subject: Option<&str>, backend: &str) -> PromptTemplate {
+        let subject_template = subject.and_then(|subject| self.get(&format!("subject.{}", subject)));
+        subject_template
+            .or_else(|| self.get(&format!("provider.{}", backend)))
+            .or_else(|| self.get(DEFAULT_TEMPLATE))
+            .expect("the default template always exists")
+    }
+
+    pub fn render(&self, context: &PromptContext, backend: &str) -> Result<String, LlmError> {
+        let template = self.resolve(context.subject.as_deref(), backend);
+        render_template(&template.template, context).map_err(|e| {
+            LlmError::ConfigError(format!("Prompt template '{}' failed to render: {}", template.name, e))
+        })
+    }
+
+    /// Render for `backend`, leaving tables off the end of the schema for as long as `overflow`
+    /// says the prompt is too long for the provider
+    pub fn render_within(
+        &self,
+        context: &PromptContext,
+        backend: &str,
+        overflow: impl Fn(&str) -> Result<usize, LlmError>,
+    ) -> Result<String, LlmError> {
+        let mut prompt = self.render(context, backend)?;
+
+        // Tables are separated by blank lines in the schema
+        let mut tables = context.schema.split("\n\n").collect::<Vec<_>>();
+        let total = tables.len();
+        let mut shortened = context.clone();
+        while !tables.is_empty() && overflow(&prompt)? > 0 {
+            tables.pop();
+            let note = format!(
+                "-- {} of {} tables left out to fit the model's context window",
+                total - tables.len(),
+                total
+            );
+            shortened.schema = tables
+                .iter()
+                .copied()
+                .chain(std::iter::once(note.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            prompt = self.render(&shortened, backend)?;
+        }
+
+        if tables.len() < total {
+            warn!(
+                "Left {} of {} tables out of the prompt for {} to fit its context window",
+                total - tables.len(),
+                total,
+                backend
+            );
+        }
+        Ok(prompt)
+    }
+
+    pub fn save(&self, name: &str, template: &str) -> StoreResult<PromptTemplate> {
+        let Some(pool) = &self.pool else {
+            return Err("Prompt templates can't be edited without a database".into());
+        };
+
+        let conn = pool.get()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO prompt_templates (name, template, updated_at) VALUES (?, ?, ?)",
+            params![name, template, chrono::Utc::now().to_rfc3339()],
+        )?;
+        self.edited
+            .write()
+            .unwrap()
+            .insert(name.to_string(), template.to_string());
+
+        info!("Saved prompt template {}", name);
+        Ok(PromptTemplate {
+            name: name.to_string(),
+            source: PromptSource::Database,
+            template: template.to_string(),
+        })
+    }
+
+    /// Drop the edited version of a template, returns false if it wasn't edited
+    pub fn remove(&self, name: &str) -> StoreResult<bool> {
+        let Some(pool) = &self.pool else {
+            return Ok(false);
+        };
+
+        let conn = pool.get()?;
+        let deleted = conn.execute("DELETE FROM prompt_templates WHERE name = ?", [name])?;
+        self.edited.write().unwrap().remove(name);
+
+        if deleted > 0 {
+            info!("Removed edited prompt template {}", name);
+        }
+        Ok(deleted > 0)
+    }
+}
+
+/// Whether `name` is one the templates are looked up by
+pub fn is_valid_name(name: &str) -> bool {
+    if name == DEFAULT_TEMPLATE {
+        return true;
+    }
+
+    let Some(suffix) = name
+        .strip_prefix("provider.")
+        .or_else(|| name.strip_prefix("subject."))
+    else {
+        return false;
+    };
+    !suffix.is_empty()
+        && suffix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Check a template compiles before it is saved
+pub fn validate(template: &str) -> Result<(), String> {
+    environment()
+        .template_from_str(template)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+pub fn render_template(template: &str, context: &PromptContext) -> Result<String, String> {
+    environment()
+        .render_str(
+            template,
+            context! {
+                question => &context.question,
+                schema => &context.schema,
+                subject => &context.subject,
+                examples => &context.examples,
+                failed_sql => &context.failed_sql,
+                error => &context.error,
+                dialect => DIALECT,
+                date => chrono::Utc::now().date_naive().to_string(),
+            },
+        )
+        .map(|prompt| prompt.trim().to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn environment<'source>() -> Environment<'source> {
+    let mut env = Environment::new();
+    // Block tags on their own line leave no blank lines behind
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_fuel(Some(TEMPLATE_FUEL));
+    env
+}
+
+fn load_dir(dir: &Path) -> HashMap<String, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read prompt templates from {}: {}", dir.display(), e);
+            return HashMap::new();
+        }
+    };
+
+    let mut templates = HashMap::new();
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if path.extension().and_then(|ext| ext.to_str()) != Some("jinja") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if !is_valid_name(name) {
+            warn!("Skipping prompt template {} with an unknown name", path.display());
+            continue;
+        }
+
+        match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|template| {
+            validate(&template)?;
+            Ok(template)
+        }) {
+            Ok(template) => {
+                templates.insert(name.to_string(), template);
+            }
+            Err(e) => warn!("Skipping prompt template {}: {}", path.display(), e),
+        }
+    }
+
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> PromptContext {
+        PromptContext {
+            question: "How many orders per region?".to_string(),
+            schema: "CREATE TABLE orders (id INTEGER, region VARCHAR);".to_string(),
+            subject: Some("sales".to_string()),
+            ..PromptContext::default()
+        }
+    }
+
+    #[test]
+    fn accepts_only_lookup_names() {
+        for name in ["default", "provider.ollama", "subject.sales", "subject.east_2024", "provider.my-llm"] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+        for name in ["", "other", "provider.", "subject.", "subject.../etc", "subject.a b", "defaults"] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn renders_the_builtin_default() {
+        let prompt = PromptTemplates::builtin().render(&context(), "ollama").unwrap();
+        assert!(prompt.contains("CREATE TABLE orders"));
+        assert!(prompt.contains("`How many orders per region?`"));
+        assert!(prompt.contains("DuckDB"));
+        assert!(!prompt.contains("answered before"));
+        assert!(!prompt.contains("previous attempt"));
+    }
+
+    #[test]
+    fn renders_examples_and_repairs() {
+        let context = PromptContext {
+            examples: vec![PromptExample {
+                question: "Total sales".to_string(),
+                sql: "SELECT sum(amount) FROM orders".to_string(),
+            }],
+            failed_sql: Some("SELECT regoin FROM orders".to_string()),
+            error: Some("column regoin not found".to_string()),
+            ..context()
+        };
+
+        let prompt = render_template(BUILTIN_DEFAULT, &context).unwrap();
+        assert!(prompt.contains("- `Total sales`: `SELECT sum(amount) FROM orders`"));
+        assert!(prompt.contains("`SELECT regoin FROM orders` which failed with the DuckDB error: column regoin not found"));
+    }
+
+    #[test]
+    fn renders_custom_templates() {
+        let prompt = render_template("{{ subject }}: {{ question }}\n\n", &context()).unwrap();
+        assert_eq!(prompt, "sales: How many orders per region?");
+
+        assert!(validate("{% if question %}").is_err());
+    }
+
+    #[test]
+    fn leaves_tables_out_until_the_prompt_fits() {
+        let context = PromptContext {
+            schema: ["CREATE TABLE a (x INTEGER);", "CREATE TABLE b (y INTEGER);", "CREATE TABLE c (z INTEGER);"]
+                .join("\n\n"),
+            ..context()
+        };
+        let templates = PromptTemplates::builtin();
+
+        let prompt = templates
+            .render_within(&context, "local", |prompt| Ok(usize::from(prompt.contains("TABLE b"))))
+            .unwrap();
+        assert!(prompt.contains("CREATE TABLE a"));
+        assert!(!prompt.contains("CREATE TABLE b") && !prompt.contains("CREATE TABLE c"));
+        assert!(prompt.contains("-- 2 of 3 tables left out"));
+        assert!(prompt.contains("`How many orders per region?`"));
+
+        let unchanged = templates.render_within(&context, "local", |_| Ok(0)).unwrap();
+        assert_eq!(unchanged, templates.render(&context, "local").unwrap());
+    }
+
+    #[test]
+    fn stops_templates_that_run_too_long() {
+        let template = "{% for i in range(10000) %}{% for j in range(10000) %}{{ j }}{% endfor %}{% endfor %}";
+        assert!(validate(template).is_ok());
+        assert!(render_template(template, &context()).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::config::LlmConfig;
+use crate::llm::{LlmError, SqlGenerator};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, error};
+
+const DEFAULT_API_URL: &str = "https://api.anthropic.com/v1/messages";
+const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: usize = 1024;
+
+// The rules for the query come from the prompt template
+const SYSTEM_PROMPT: &str = "Reply with a single SQL query in a ```sql code block and nothing else.";
+
+/// Talks to the Anthropic Messages API
+pub struct AnthropicProvider {
+    client: reqwest::Client,
+    api_url: String,
+    api_key: String,
+    model: String,
+    max_tokens: usize,
+    temperature: f64,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: usize,
+    system: &'a str,
+    messages: Vec<Message>,
+    temperature: f64,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlock {
+    #[serde(rename = "type")]
+    block_type: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    #[serde(rename = "type")]
+    error_type: String,
+    message: String,
+}
+
+impl AnthropicProvider {
+    pub fn new(config: &LlmConfig) -> Result<Self, LlmError> {
+        let api_key = config.api_key.clone().ok_or_else(|| {
+            LlmError::ConfigError("API key is required for the Anthropic provider".to_string())
+        })?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()
+            .map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+
+        Ok(Self {
+            client,
+            api_url: config
+                .api_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_API_URL.to_string()),
+            api_key,
+            model: config.model.clone(),
+            max_tokens: config.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: config.temperature.unwrap_or(0.1),
+        })
+    }
+
+    // Error bodies look like {"type": "error", "error": {"type": "overloaded_error", "message": "..."}}
+    fn api_error(status: reqwest::StatusCode, body: &str) -> LlmError {
+        let Ok(ErrorResponse { error }) = serde_json::from_str::<ErrorResponse>(body) else {
+            return if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                LlmError::ConnectionError(format!("Anthropic API responded with status code: {}", status))
+            } else {
+                LlmError::ResponseError(format!(
+                    "Anthropic API responded with status code: {} - Response body: {}",
+                    status, body
+                ))
+            };
+        };
+
+        let message = format!("Anthropic API {} ({}): {}", error.error_type, status, error.message);
+        match error.error_type.as_str() {
+            // Worth trying again later
+            "overloaded_error" | "rate_limit_error" | "api_error" | "timeout_error" => {
+                LlmError::ConnectionError(message)
+            }
+            // Won't work until the configuration is fixed
+            "authentication_error" | "permission_error" | "not_found_error" | "invalid_request_error" => {
+                LlmError::ConfigError(message)
+            }
+            _ => LlmError::ResponseError(message),
+        }
+    }
+
+    // The query has to come in a code block, anything else is the model talking instead of answering
+    fn extract_sql(text: &str) -> Option<String> {
+        let start = text.find("```sql")?;
+        let rest = &text[start + 6..];
+        // A missing closing fence still leaves a usable query
+        let sql = rest.find("```").map_or(rest, |end| &rest[..end]).trim();
+        (!sql.is_empty()).then(|| sql.to_string())
+    }
+}
+
+#[async_trait]
+impl SqlGenerator for AnthropicProvider {
+    async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError> {
+        let request = MessagesRequest {
+            model: &self.model,
+            max_tokens: self.max_tokens,
+            system: SYSTEM_PROMPT,
+            messages: vec![Message {
+                role: "user",
+                content: prompt.to_string(),
+            }],
+            temperature: self.temperature,
+        };
+
+        debug!("Sending request to Anthropic with model: {}", self.model);
+
+        let response = self
+            .client
+            .post(&self.api_url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| LlmError::ConnectionError(format!("Failed to read response body: {}", e)))?;
+
+        if !status.is_success() {
+            error!("Anthropic API responded with status code: {} - {}", status, body);
+            return Err(Self::api_error(status, &body));
+        }
+
+        let reply: MessagesResponse = serde_json::from_str(&body).map_err(|e| {
+            LlmError::ResponseError(format!("Failed to parse Anthropic response: {}", e))
+        })?;
+
+        let text = reply
+            .content
+            .iter()
+            .filter(|block| block.block_type == "text")
+            .map(|block| block.text.as_str())
+            .collect::<String>();
+
+        match reply.stop_reason.as_deref() {
+            Some("end_turn") | Some("stop_sequence") | None => {}
+            // A cut off query can look whole, so let the repair loop or the next provider have a go
+            Some("max_tokens") => {
+                return Err(LlmError::ResponseError(format!(
+                    "Reply stopped at the {} token limit, raise max_tokens if queries are cut short",
+                    self.max_tokens
+                )));
+            }
+            Some("refusal") => {
+                return Err(LlmError::ResponseError("The model declined to answer".to_string()));
+            }
+            Some(other) => {
+                return Err(LlmError::ResponseError(format!("Unexpected stop reason: {}", other)));
+            }
+        }
+
+        Self::extract_sql(&text).ok_or_else(|| {
+            LlmError::ResponseError("Failed to extract valid SQL from response".to_string())
+        })
+    }
+
+    // Listing models checks the key as well as the connection without spending tokens
+    async fn health_check(&self) -> Option<Result<(), LlmError>> {
+        let models_url = format!("{}/models", self.api_url.strip_suffix("/messages")?);
+
+        let result = match self
+            .client
+            .get(models_url)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                Err(Self::api_error(status, &body))
+            }
+            Err(e) => Err(LlmError::ConnectionError(e.to_string())),
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    const API_KEY: &str = "stub-api-key";
+
+    // Answers every request with the same reply, as long as it carries the key and version
+    async fn start_stub(status: StatusCode, reply: Value) -> AnthropicProvider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/v1/messages", listener.local_addr().unwrap());
+
+        let app = Router::new()
+            .route("/v1/messages", post(messages))
+            .with_state(Arc::new((status, reply)));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config: LlmConfig = serde_json::from_value(json!({
+            "backend": "anthropic",
+            "model": "claude-stub",
+            "api_key": API_KEY,
+            "api_url": api_url,
+            "max_tokens": 64,
+        }))
+        .unwrap();
+        AnthropicProvider::new(&config).unwrap()
+    }
+
+    async fn messages(
+        State(reply): State<Arc<(StatusCode, Value)>>,
+        headers: HeaderMap,
+        Json(request): Json<Value>,
+    ) -> (StatusCode, Json<Value>) {
+        assert_eq!(headers["x-api-key"], API_KEY);
+        assert_eq!(headers["anthropic-version"], API_VERSION);
+        assert_eq!(request["model"], "claude-stub");
+        assert_eq!(request["max_tokens"], 64);
+        assert_eq!(request["messages"][0]["content"], "How many orders?");
+
+        let (status, body) = &*reply;
+        (*status, Json(body.clone()))
+    }
+
+    fn text_reply(text: &str, stop_reason: &str) -> Value {
+        json!({
+            "type": "message",
+            "role": "assistant",
+            "content": [{ "type": "text", "text": text }],
+            "stop_reason": stop_reason,
+        })
+    }
+
+    fn error_reply(error_type: &str) -> Value {
+        json!({
+            "type": "error",
+            "error": { "type": error_type, "message": "stub error" },
+        })
+    }
+
+    #[tokio::test]
+    async fn returns_the_fenced_query() {
+        let reply = text_reply("Here you go:\n```sql\nSELECT count(*) FROM orders\n```", "end_turn");
+        let provider = start_stub(StatusCode::OK, reply).await;
+
+        let sql = provider.generate_sql("How many orders?").await.unwrap();
+        assert_eq!(sql, "SELECT count(*) FROM orders");
+    }
+
+    #[tokio::test]
+    async fn maps_error_types_to_error_kinds() {
+        let cases = [
+            (StatusCode::SERVICE_UNAVAILABLE, "overloaded_error", "connection"),
+            (StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", "connection"),
+            (StatusCode::INTERNAL_SERVER_ERROR, "api_error", "connection"),
+            (StatusCode::GATEWAY_TIMEOUT, "timeout_error", "connection"),
+            (StatusCode::UNAUTHORIZED, "authentication_error", "config"),
+            (StatusCode::FORBIDDEN, "permission_error", "config"),
+            (StatusCode::NOT_FOUND, "not_found_error", "config"),
+            (StatusCode::BAD_REQUEST, "invalid_request_error", "config"),
+            (StatusCode::BAD_REQUEST, "some_new_error", "response"),
+        ];
+
+        for (status, error_type, expected) in cases {
+            let provider = start_stub(status, error_reply(error_type)).await;
+            let kind = match provider.generate_sql("How many orders?").await {
+                Err(LlmError::ConnectionError(_)) => "connection",
+                Err(LlmError::ConfigError(_)) => "config",
+                Err(LlmError::ResponseError(_)) => "response",
+                Ok(sql) => panic!("{} gave a query: {}", error_type, sql),
+            };
+            assert_eq!(kind, expected, "{}", error_type);
+        }
+    }
+
+    #[test]
+    fn maps_bodies_without_an_error_type_by_status() {
+        assert!(matches!(
+            AnthropicProvider::api_error(StatusCode::BAD_GATEWAY, "<html>"),
+            LlmError::ConnectionError(_)
+        ));
+        assert!(matches!(
+            AnthropicProvider::api_error(StatusCode::TOO_MANY_REQUESTS, ""),
+            LlmError::ConnectionError(_)
+        ));
+        assert!(matches!(
+            AnthropicProvider::api_error(StatusCode::BAD_REQUEST, "not json"),
+            LlmError::ResponseError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_replies_that_did_not_finish() {
+        let fenced = "```sql\nSELECT count(*) FROM orders\n```";
+        for stop_reason in ["max_tokens", "refusal", "pause_turn"] {
+            let provider = start_stub(StatusCode::OK, text_reply(fenced, stop_reason)).await;
+            let result = provider.generate_sql("How many orders?").await;
+            assert!(
+                matches!(result, Err(LlmError::ResponseError(_))),
+                "{} was accepted",
+                stop_reason
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_replies_without_a_code_block() {
+        let reply = text_reply("I'd need to know which table holds the orders.", "end_turn");
+        let provider = start_stub(StatusCode::OK, reply).await;
+
+        let result = provider.generate_sql("How many orders?").await;
+        assert!(matches!(result, Err(LlmError::ResponseError(_))));
+    }
+
+    #[test]
+    fn extracts_sql_from_code_blocks() {
+        assert_eq!(
+            AnthropicProvider::extract_sql("```sql\nSELECT 1\n```\nThat counts them.").as_deref(),
+            Some("SELECT 1")
+        );
+        // Cut off before the closing fence
+        assert_eq!(AnthropicProvider::extract_sql("```sql\nSELECT 1").as_deref(), Some("SELECT 1"));
+        assert_eq!(AnthropicProvider::extract_sql("```sql\n```"), None);
+        assert_eq!(AnthropicProvider::extract_sql("SELECT 1"), None);
+    }
+}
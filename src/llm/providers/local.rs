@@ -1,57 +1,474 @@
 use crate::config::LlmConfig;
+use crate::llm::prompts::COMPLETION_SUFFIX;
 use crate::llm::{LlmError, SqlGenerator};
 use async_trait::async_trait;
+use candle_core::quantized::gguf_file;
+use candle_core::{Device, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::quantized_llama::{ModelWeights, MAX_SEQ_LEN};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokenizers::Tokenizer;
+use tracing::{debug, info, warn};
 
+const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+const DEFAULT_MAX_TOKENS: usize = 512;
+const DEFAULT_TEMPERATURE: f64 = 0.1;
+const DEFAULT_SEED: u64 = 299_792_458;
+
+// Tokens used by common GGUF model families to mark the end of a completion
+const EOS_TOKENS: [&str; 4] = ["</s>", "<|endoftext|>", "<|end_of_text|>", "<|eot_id|>"];
+
+/// Sampling and context settings for the local model
+#[derive(Debug, Clone)]
+struct GenerationSettings {
+    context_length: usize,
+    max_tokens: usize,
+    temperature: f64,
+    top_p: Option<f64>,
+    seed: u64,
+}
+
+impl GenerationSettings {
+    fn from_config(config: &LlmConfig) -> Self {
+        let mut context_length = config.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH);
+        if context_length > MAX_SEQ_LEN {
+            warn!(
+                "Context length {} exceeds the supported maximum, using {}",
+                context_length, MAX_SEQ_LEN
+            );
+            context_length = MAX_SEQ_LEN;
+        }
+
+        Self {
+            context_length,
+            max_tokens: config.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: config.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            top_p: config.top_p,
+            seed: config.seed.unwrap_or(DEFAULT_SEED),
+        }
+    }
+
+    // Prompt tokens that fit in the context window next to the completion
+    fn prompt_budget(&self) -> usize {
+        self.context_length.saturating_sub(self.max_tokens).max(1)
+    }
+
+    fn sampling(&self) -> Sampling {
+        if self.temperature <= 0.0 {
+            return Sampling::ArgMax;
+        }
+        match self.top_p {
+            Some(p) => Sampling::TopP {
+                p,
+                temperature: self.temperature,
+            },
+            None => Sampling::All {
+                temperature: self.temperature,
+            },
+        }
+    }
+}
+
+// The model file, and the tokenizer next to it unless one is configured
+fn model_files(config: &LlmConfig) -> (PathBuf, PathBuf) {
+    let model_path = PathBuf::from(
+        config
+            .model_path
+            .clone()
+            .unwrap_or_else(|| config.model.clone()),
+    );
+
+    let tokenizer_path = config
+        .tokenizer_path
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| model_path.with_file_name("tokenizer.json"));
+
+    (model_path, tokenizer_path)
+}
+
+/// A quantized model, loaded into memory
+struct LoadedModel {
+    weights: ModelWeights,
+    eos_token: Option<u32>,
+}
+
+/// Runs a quantized GGUF model in-process on the CPU, so no network access is needed
 pub struct LocalLlmProvider {
-    model_path: String,
-    // Here we'd add fields for the local model, likely using ezllama or similar
+    model_path: PathBuf,
+    settings: GenerationSettings,
+    // Loaded up front, prompts are measured with it before they are sent
+    tokenizer: Arc<Tokenizer>,
+    thread_pool: Arc<rayon::ThreadPool>,
+    // Loaded on the first query so server startup isn't held up by multi-GB model files
+    model: Arc<Mutex<Option<LoadedModel>>>,
 }
 
 impl LocalLlmProvider {
     pub fn new(config: &LlmConfig) -> Result<Self, LlmError> {
-        // In a full implementation, this would load a local model using ezllama
-        // For now, just validate config requirements
+        let (model_path, tokenizer_path) = model_files(config);
+
+        if !model_path.is_file() {
+            return Err(LlmError::ConfigError(format!(
+                "Local model file not found: {}",
+                model_path.display()
+            )));
+        }
+
+        if !tokenizer_path.is_file() {
+            return Err(LlmError::ConfigError(format!(
+                "Tokenizer file not found: {}",
+                tokenizer_path.display()
+            )));
+        }
+
+        let tokenizer = Tokenizer::from_file(&tokenizer_path).map_err(|e| {
+            LlmError::ConfigError(format!(
+                "Failed to load tokenizer {}: {}",
+                tokenizer_path.display(),
+                e
+            ))
+        })?;
+
+        let threads = config.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+
+        // A dedicated pool keeps inference from starving the rest of the server
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("nl-cube-llm-{}", i))
+            .build()
+            .map_err(|e| LlmError::ConfigError(format!("Failed to create thread pool: {}", e)))?;
+
+        let settings = GenerationSettings::from_config(config);
+
+        info!(
+            "Configured local LLM: model={}, threads={}, settings={:?}",
+            model_path.display(),
+            threads,
+            settings
+        );
 
         Ok(Self {
-            model_path: config.model.clone(),
+            model_path,
+            settings,
+            tokenizer: Arc::new(tokenizer),
+            thread_pool: Arc::new(thread_pool),
+            model: Arc::new(Mutex::new(None)),
         })
     }
+}
+
+fn model_error(e: impl std::fmt::Display) -> LlmError {
+    LlmError::ResponseError(format!("Local model error: {}", e))
+}
+
+fn load_model(model_path: &Path, tokenizer: &Tokenizer) -> Result<LoadedModel, LlmError> {
+    info!("Loading local model from {}", model_path.display());
+    let start = std::time::Instant::now();
 
-    fn prepare_prompt(&self, question: &str, schema: &str) -> String {
-        format!(
-            r#"
-### Instructions:
-Your task is to convert a question into a SQL query, given a database schema.
-Adhere to these rules:
-- **Deliberately go through the question and database schema word by word** to appropriately answer the question
-- **Use Table Aliases** to prevent ambiguity. For example, `SELECT table1.col1, table2.col1 FROM table1 JOIN table2 ON table1.id = table2.id`.
-- When creating a ratio, always cast the numerator as float
+    let mut file = std::fs::File::open(model_path).map_err(|e| {
+        LlmError::ConfigError(format!("Failed to open {}: {}", model_path.display(), e))
+    })?;
 
-### Input:
-Generate a SQL query that answers the question `{}`.
-This query will run on a database whose schema is represented in this string:
-{}
+    let content = gguf_file::Content::read(&mut file).map_err(|e| {
+        LlmError::ConfigError(format!(
+            "Failed to read GGUF file {}: {}",
+            model_path.display(),
+            e
+        ))
+    })?;
+
+    let weights = ModelWeights::from_gguf(content, &mut file, &Device::Cpu).map_err(|e| {
+        LlmError::ConfigError(format!("Failed to load model weights: {}", e))
+    })?;
+
+    info!("Local model loaded in {}ms", start.elapsed().as_millis());
+
+    Ok(LoadedModel {
+        weights,
+        eos_token: eos_token(tokenizer),
+    })
+}
+
+fn eos_token(tokenizer: &Tokenizer) -> Option<u32> {
+    EOS_TOKENS
+        .iter()
+        .find_map(|token| tokenizer.token_to_id(token))
+}
 
-### Response:
-Based on your instructions, here is the SQL query I have generated to answer the question `{}`:
-```sql
-"#,
-            question, schema, question
-        )
+// How many tokens `prompt` is over the budget once the completion suffix is added
+fn prompt_overflow(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    settings: &GenerationSettings,
+) -> Result<usize, LlmError> {
+    let prompt = format!("{}{}", prompt, COMPLETION_SUFFIX);
+    let encoding = tokenizer.encode(prompt, true).map_err(model_error)?;
+    Ok(encoding.len().saturating_sub(settings.prompt_budget()))
+}
+
+fn generate(
+    model: &mut LoadedModel,
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    settings: &GenerationSettings,
+) -> Result<String, LlmError> {
+    let encoding = tokenizer.encode(prompt, true).map_err(model_error)?;
+    let tokens = encoding.get_ids().to_vec();
+
+    // The schema has already been cut down to fit, so whatever is left is the template itself.
+    // Cutting that would lose the instructions or the question.
+    if tokens.len() > settings.prompt_budget() {
+        return Err(LlmError::ConfigError(format!(
+            "Prompt is {} tokens even without the schema, only {} fit next to max_tokens in the context window",
+            tokens.len(),
+            settings.prompt_budget()
+        )));
     }
+
+    let mut logits_processor = LogitsProcessor::from_sampling(settings.seed, settings.sampling());
+
+    let device = Device::Cpu;
+    let start = std::time::Instant::now();
+
+    // Process the whole prompt in one pass, then generate token by token
+    let input = Tensor::new(tokens.as_slice(), &device)
+        .and_then(|t| t.unsqueeze(0))
+        .map_err(model_error)?;
+    let logits = model
+        .weights
+        .forward(&input, 0)
+        .and_then(|l| l.squeeze(0))
+        .map_err(model_error)?;
+    let mut next_token = logits_processor.sample(&logits).map_err(model_error)?;
+
+    let mut generated = Vec::new();
+    let mut completion = String::new();
+
+    for index in 0..settings.max_tokens {
+        if Some(next_token) == model.eos_token {
+            break;
+        }
+
+        generated.push(next_token);
+        completion = tokenizer
+            .decode(&generated, true)
+            .map_err(model_error)?;
+
+        // The prompt opens a ```sql block, so the closing fence ends the query
+        if completion.contains("```") {
+            break;
+        }
+
+        let position = tokens.len() + index;
+        if position + 1 >= settings.context_length {
+            warn!("Reached the end of the context window while generating");
+            break;
+        }
+
+        let input = Tensor::new(&[next_token], &device)
+            .and_then(|t| t.unsqueeze(0))
+            .map_err(model_error)?;
+        let logits = model
+            .weights
+            .forward(&input, position)
+            .and_then(|l| l.squeeze(0))
+            .map_err(model_error)?;
+        next_token = logits_processor.sample(&logits).map_err(model_error)?;
+    }
+
+    info!(
+        "Generated {} tokens in {}ms",
+        generated.len(),
+        start.elapsed().as_millis()
+    );
+    debug!("Local model completion: {}", completion);
+
+    Ok(completion)
+}
+
+fn extract_sql(completion: &str) -> String {
+    let sql = match completion.find("```") {
+        Some(end) => &completion[..end],
+        None => completion,
+    };
+
+    sql.trim().to_string()
 }
 
 #[async_trait]
 impl SqlGenerator for LocalLlmProvider {
-    async fn generate_sql(&self, question: &str, schema: &str) -> Result<String, LlmError> {
-        // This is a placeholder. In a real implementation, this would:
-        // 1. Format the prompt
-        // 2. Send it to the local model through ezllama
-        // 3. Process the response
+    async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError> {
+        let prompt = format!("{}{}", prompt, COMPLETION_SUFFIX);
+
+        let model = Arc::clone(&self.model);
+        let thread_pool = Arc::clone(&self.thread_pool);
+        let model_path = self.model_path.clone();
+        let tokenizer = Arc::clone(&self.tokenizer);
+        let settings = self.settings.clone();
+
+        // Inference is CPU bound, so keep it off the async runtime
+        let completion = tokio::task::spawn_blocking(move || -> Result<String, LlmError> {
+            let mut guard = model
+                .lock()
+                .map_err(|_| LlmError::ResponseError("Local model lock poisoned".to_string()))?;
+
+            if guard.is_none() {
+                *guard = Some(load_model(&model_path, &tokenizer)?);
+            }
+
+            let loaded = guard.as_mut().expect("model was loaded above");
+            thread_pool.install(|| generate(loaded, &tokenizer, &prompt, &settings))
+        })
+        .await
+        .map_err(|e| LlmError::ResponseError(format!("Local model task failed: {}", e)))??;
 
-        let _prompt = self.prepare_prompt(question, schema);
+        let sql = extract_sql(&completion);
 
-        // To be implemented when local LLM feature is enabled
-        Err(LlmError::ConfigError("Local LLM provider not fully implemented".to_string()))
+        if sql.is_empty() {
+            return Err(LlmError::ResponseError(
+                "Failed to extract valid SQL from local model output".to_string(),
+            ));
+        }
+
+        Ok(sql)
+    }
+
+    fn prompt_overflow(&self, prompt: &str) -> Result<usize, LlmError> {
+        prompt_overflow(&self.tokenizer, prompt, &self.settings)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, feature = "local-llm"))]
+mod tests {
+    use super::*;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+
+    fn config(settings: serde_json::Value) -> LlmConfig {
+        let mut config = serde_json::json!({ "backend": "local", "model": "/models/sqlcoder.gguf" });
+        config.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    // Splits on whitespace, every word it doesn't know is one unknown token
+    fn tokenizer() -> Tokenizer {
+        let vocab = ["[UNK]", "SELECT", "<|eot_id|>", "<|endoftext|>"]
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(WhitespaceSplit));
+        tokenizer
+    }
+
+    #[test]
+    fn reads_settings_with_defaults() {
+        let settings = GenerationSettings::from_config(&config(serde_json::json!({})));
+        assert_eq!(settings.context_length, DEFAULT_CONTEXT_LENGTH);
+        assert_eq!(settings.max_tokens, DEFAULT_MAX_TOKENS);
+        assert_eq!(settings.temperature, DEFAULT_TEMPERATURE);
+        assert_eq!(settings.top_p, None);
+        assert_eq!(settings.seed, DEFAULT_SEED);
+
+        let settings = GenerationSettings::from_config(&config(serde_json::json!({
+            "context_length": 2048, "max_tokens": 256, "temperature": 0.0, "top_p": 0.9, "seed": 7
+        })));
+        assert_eq!(settings.context_length, 2048);
+        assert_eq!(settings.max_tokens, 256);
+        assert_eq!(settings.top_p, Some(0.9));
+        assert_eq!(settings.seed, 7);
+    }
+
+    #[test]
+    fn caps_the_context_at_what_the_model_supports() {
+        let settings = GenerationSettings::from_config(&config(serde_json::json!({
+            "context_length": MAX_SEQ_LEN * 2
+        })));
+        assert_eq!(settings.context_length, MAX_SEQ_LEN);
+    }
+
+    #[test]
+    fn finds_the_tokenizer_next_to_the_model() {
+        let (model, tokenizer) = model_files(&config(serde_json::json!({})));
+        assert_eq!(model, PathBuf::from("/models/sqlcoder.gguf"));
+        assert_eq!(tokenizer, PathBuf::from("/models/tokenizer.json"));
+
+        let (model, tokenizer) = model_files(&config(serde_json::json!({
+            "model_path": "/data/model.gguf", "tokenizer_path": "/data/vocab/tokenizer.json"
+        })));
+        assert_eq!(model, PathBuf::from("/data/model.gguf"));
+        assert_eq!(tokenizer, PathBuf::from("/data/vocab/tokenizer.json"));
+    }
+
+    #[test]
+    fn leaves_room_for_the_completion() {
+        let settings = |context_length, max_tokens| GenerationSettings {
+            context_length,
+            max_tokens,
+            ..GenerationSettings::from_config(&config(serde_json::json!({})))
+        };
+
+        assert_eq!(settings(4096, 512).prompt_budget(), 3584);
+        // Never nothing, a prompt always has to fit
+        assert_eq!(settings(512, 512).prompt_budget(), 1);
+        assert_eq!(settings(256, 512).prompt_budget(), 1);
+    }
+
+    #[test]
+    fn samples_greedily_without_a_temperature() {
+        let settings = |temperature, top_p| GenerationSettings {
+            temperature,
+            top_p,
+            ..GenerationSettings::from_config(&config(serde_json::json!({})))
+        };
+
+        assert!(matches!(settings(0.0, Some(0.9)).sampling(), Sampling::ArgMax));
+        assert!(matches!(settings(0.2, Some(0.9)).sampling(), Sampling::TopP { p, .. } if p == 0.9));
+        assert!(matches!(settings(0.2, None).sampling(), Sampling::All { temperature } if temperature == 0.2));
+    }
+
+    #[test]
+    fn measures_prompts_against_the_budget() {
+        let tokenizer = tokenizer();
+        let suffix = tokenizer.encode(COMPLETION_SUFFIX, true).unwrap().len();
+        let settings = GenerationSettings {
+            context_length: suffix + 3 + DEFAULT_MAX_TOKENS,
+            ..GenerationSettings::from_config(&config(serde_json::json!({})))
+        };
+
+        assert_eq!(prompt_overflow(&tokenizer, "SELECT from orders", &settings).unwrap(), 0);
+        assert_eq!(prompt_overflow(&tokenizer, "SELECT count from all orders", &settings).unwrap(), 2);
+    }
+
+    #[test]
+    fn finds_the_end_of_text_token() {
+        assert_eq!(eos_token(&tokenizer()), Some(3));
+
+        let model = WordLevel::builder()
+            .vocab([("[UNK]".to_string(), 0)].into_iter().collect())
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(eos_token(&Tokenizer::new(model)), None);
+    }
+
+    #[test]
+    fn reads_sql_up_to_the_closing_fence() {
+        assert_eq!(extract_sql("  SELECT 1\n```\nThis query selects one."), "SELECT 1");
+        assert_eq!(extract_sql("SELECT *\nFROM orders\n"), "SELECT *\nFROM orders");
+        assert_eq!(extract_sql("```"), "");
+    }
+}
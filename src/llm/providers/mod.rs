@@ -1,2 +1,5 @@
+pub mod anthropic;
+#[cfg(feature = "local-llm")]
+pub mod local;
 pub mod ollama;
 pub mod remote;
@@ -1,9 +1,13 @@
 use crate::config::LlmConfig;
+use crate::llm::prompts::COMPLETION_SUFFIX;
 use crate::llm::{LlmError, SqlGenerator};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tracing::{debug, error, info};
 
+pub const DEFAULT_API_URL: &str = "http://localhost:11434/api/generate";
+
 pub struct OllamaProvider {
     client: reqwest::Client,
     api_url: String,
@@ -23,12 +27,57 @@ struct OllamaResponse {
     response: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct TagsResponse {
+    models: Vec<InstalledModel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstalledModel {
+    name: String,
+}
+
+// The tags endpoint next to the generate endpoint at `api_url`
+fn tags_url(api_url: &str) -> String {
+    match api_url.find("/api/") {
+        Some(pos) => format!("{}/api/tags", &api_url[..pos]),
+        None => format!("{}/api/tags", api_url.trim_end_matches('/')),
+    }
+}
+
+/// Names of the models installed in the Ollama server whose generate endpoint is `api_url`
+pub async fn installed_models(api_url: &str) -> Result<Vec<String>, LlmError> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+
+    let response = client
+        .get(tags_url(api_url))
+        .send()
+        .await
+        .map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(LlmError::ResponseError(format!(
+            "Ollama API responded with status code: {}",
+            response.status()
+        )));
+    }
+
+    let tags: TagsResponse = response
+        .json()
+        .await
+        .map_err(|e| LlmError::ResponseError(format!("Failed to parse Ollama tags: {}", e)))?;
+    Ok(tags.models.into_iter().map(|model| model.name).collect())
+}
+
 impl OllamaProvider {
     pub fn new(config: &LlmConfig) -> Result<Self, LlmError> {
         let api_url = config
             .api_url
             .clone()
-            .unwrap_or_else(|| "http://localhost:11434/api/generate".to_string());
+            .unwrap_or_else(|| DEFAULT_API_URL.to_string());
 
         let client = reqwest::Client::new();
 
@@ -39,52 +88,15 @@ impl OllamaProvider {
         })
     }
 
-    fn prepare_prompt(&self, question: &str, schema: &str) -> String {
-        let prompt = format!(
-            r#"
-### Instructions:
-Your task is to convert a question into a SQL query for DuckDB, given a database schema.
-Adhere to these rules:
-- **Be careful with column names - they are case sensitive**
-- **Use the exact spelling of column names as provided in the schema**
-- **Deliberately go through the question and database schema word by word** to appropriately answer the question
-- **Use Table Aliases** to prevent ambiguity. For example, `SELECT table1.col1, table2.col1 FROM table1 JOIN table2 ON table1.id = table2.id`.
-- When creating a ratio, always cast the numerator as float
-
-### Input:
-Generate a SQL query that answers the question `{}`.
-This query will run on a DuckDB database with the following tables and columns:
-
-{}
-
-### Expected SQL Format:
-- Use lowercase for SQL keywords (SELECT, FROM, WHERE, etc.)
-- Reference column names exactly as shown in the schema
-- Take extreme care to identify column names in the user's question
-- Take extreme care over the names of the available tables make sure you check that your SQL is only using tables from the above list
-- Make sure to use double quotes around column names with spaces or special characters
-- End your query with a semicolon
-
-### Response:
-Based on your instructions, here is the SQL query I have generated to answer the question `{}`:
-```sql
-"#,
-            question, schema, question
-        );
-
-        info!("Prepared LLM prompt: {}", prompt);
-        prompt
-    }
-
     fn extract_sql(&self, content: &str) -> String {
         // Try to extract SQL from between ```sql and ``` markers
-        if let Some(start) = content.find("```sql") {
-            if let Some(end) = content.rfind("```") {
-                let sql = &content[start + 6..end].trim();
-                info!("Successfully extracted SQL from Ollama response using code block markers");
-                debug!("Extracted SQL: {}", sql);
-                return sql.to_string();
-            }
+        if let Some(start) = content.find("```sql")
+            && let Some(end) = content.rfind("```")
+        {
+            let sql = &content[start + 6..end].trim();
+            info!("Successfully extracted SQL from Ollama response using code block markers");
+            debug!("Extracted SQL: {}", sql);
+            return sql.to_string();
         }
 
         // Try alternate syntax without a language specifier: ``` and ```
@@ -99,10 +111,8 @@ Based on your instructions, here is the SQL query I have generated to answer the
         }
 
         // If we couldn't find explicit code blocks, try to extract SQL statements directly
-        // Look for specific SQL patterns
-        let sql_patterns = [
-            "SELECT", "WITH", "INSERT", "UPDATE", "DELETE", "CREATE", "ALTER", "DROP"
-        ];
+        // Only read-only statements are looked for, anything else is refused at execution
+        let sql_patterns = ["SELECT", "WITH", "DESCRIBE", "EXPLAIN"];
 
         // First try line by line
         let lines: Vec<&str> = content.lines().collect();
@@ -113,8 +123,8 @@ Based on your instructions, here is the SQL query I have generated to answer the
                 let mut sql = line.trim().to_string();
 
                 // Collect subsequent lines that appear to be part of the SQL
-                for j in (i + 1)..lines.len() {
-                    let next_line = lines[j].trim();
+                for next_line in lines.iter().skip(i + 1) {
+                    let next_line = next_line.trim();
 
                     // Stop if we hit a markdown code block end
                     if next_line == "```" {
@@ -172,8 +182,9 @@ Based on your instructions, here is the SQL query I have generated to answer the
 
 #[async_trait]
 impl SqlGenerator for OllamaProvider {
-    async fn generate_sql(&self, question: &str, schema: &str) -> Result<String, LlmError> {
-        let prompt = self.prepare_prompt(question, schema);
+    async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError> {
+        let prompt = format!("{}{}", prompt, COMPLETION_SUFFIX);
+        info!("Prepared LLM prompt: {}", prompt);
 
         info!("Sending request to Ollama with model: {}", self.model);
         debug!("API URL: {}", self.api_url);
@@ -242,4 +253,17 @@ impl SqlGenerator for OllamaProvider {
 
         Ok(sql)
     }
+
+    async fn health_check(&self) -> Option<Result<(), LlmError>> {
+        // Anything the tags endpoint answers shows the server is up
+        let result = match self.client.get(tags_url(&self.api_url)).send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(LlmError::ResponseError(format!(
+                "Ollama API responded with status code: {}",
+                response.status()
+            ))),
+            Err(e) => Err(LlmError::ConnectionError(e.to_string())),
+        };
+        Some(result)
+    }
 }
\ No newline at end of file
@@ -1,14 +1,25 @@
 use crate::config::LlmConfig;
+use crate::llm::models::SqlGenerationOutput;
+use crate::llm::prompts::COMPLETION_SUFFIX;
 use crate::llm::{LlmError, SqlGenerator};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use tracing::{debug, warn};
+
+// The rules for the query come from the prompt template, this only asks for the JSON fields
+const STRUCTURED_SYSTEM_PROMPT: &str = r#"Answer with a JSON object holding the SQL query in `sql`, a short `explanation` of it,
+the tables it reads in `tables_used`, and your `confidence` that it answers the question from 0 to 1."#;
 
 pub struct RemoteLlmProvider {
     client: reqwest::Client,
     api_url: String,
     api_key: String,
     model: String,
+    // Cleared for good once the server turns down a response format
+    structured_output: AtomicBool,
 }
 
 #[derive(Serialize)]
@@ -17,6 +28,8 @@ struct PromptRequest {
     messages: Vec<Message>,
     temperature: f32,
     max_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<Value>,
 }
 
 #[derive(Serialize)]
@@ -60,61 +73,57 @@ impl RemoteLlmProvider {
             api_url,
             api_key,
             model: config.model.clone(),
+            structured_output: AtomicBool::new(config.structured_output.unwrap_or(true)),
         })
     }
 
-    fn prepare_prompt(&self, question: &str, schema: &str) -> String {
-        format!(
-            r#"
-### Instructions:
-Your task is to convert a question into a SQL query, given a database schema.
-Adhere to these rules:
-- **Deliberately go through the question and database schema word by word** to appropriately answer the question
-- **Use Table Aliases** to prevent ambiguity. For example, `SELECT table1.col1, table2.col1 FROM table1 JOIN table2 ON table1.id = table2.id`.
-- When creating a ratio, always cast the numerator as float
-
-### Input:
-Generate a SQL query that answers the question `{}`.
-This query will run on a database whose schema is represented in this string:
-{}
-
-### Response:
-Based on your instructions, here is the SQL query I have generated to answer the question `{}`:
-```sql
-"#,
-            question, schema, question
-        )
+    // JSON schema of `SqlGenerationOutput`, strict so the server holds the model to it
+    fn response_format() -> Value {
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "sql_generation",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "sql": { "type": "string" },
+                        "explanation": { "type": "string" },
+                        "tables_used": { "type": "array", "items": { "type": "string" } },
+                        "confidence": { "type": "number" }
+                    },
+                    "required": ["sql", "explanation", "tables_used", "confidence"],
+                    "additionalProperties": false
+                }
+            }
+        })
     }
-}
-
-#[async_trait]
-impl SqlGenerator for RemoteLlmProvider {
-    async fn generate_sql(&self, question: &str, schema: &str) -> Result<String, LlmError> {
-        let prompt = self.prepare_prompt(question, schema);
 
+    async fn send(&self, messages: Vec<Message>, response_format: Option<Value>) -> Result<reqwest::Response, LlmError> {
         let request = PromptRequest {
             model: self.model.clone(),
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: prompt,
-            }],
+            messages,
             temperature: 0.1,
             max_tokens: 2000,
+            response_format,
         };
 
-        let response = self
-            .client
+        self.client
             .post(&self.api_url)
             .header("Authorization", format!("Bearer {}", self.api_key))
             .json(&request)
             .send()
             .await
-            .map_err(|e| LlmError::ConnectionError(e.to_string()))?;
+            .map_err(|e| LlmError::ConnectionError(e.to_string()))
+    }
 
-        if !response.status().is_success() {
+    // The content of the first choice in a chat completion
+    async fn read_content(response: reqwest::Response) -> Result<String, LlmError> {
+        let status = response.status();
+        if !status.is_success() {
             return Err(LlmError::ResponseError(format!(
                 "API responded with status code: {}",
-                response.status()
+                status
             )));
         }
 
@@ -123,21 +132,134 @@ impl SqlGenerator for RemoteLlmProvider {
             .await
             .map_err(|e| LlmError::ResponseError(e.to_string()))?;
 
-        if prompt_response.choices.is_empty() {
-            return Err(LlmError::ResponseError("No choices in response".to_string()));
+        prompt_response
+            .choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message.content)
+            .ok_or_else(|| LlmError::ResponseError("No choices in response".to_string()))
+    }
+
+    async fn complete(&self, messages: Vec<Message>) -> Result<String, LlmError> {
+        Self::read_content(self.send(messages, None).await?).await
+    }
+
+    // Asks for the reply as JSON matching `response_format`, None if the server turns the format down
+    async fn complete_structured(&self, messages: Vec<Message>) -> Result<Option<String>, LlmError> {
+        let response = self.send(messages, Some(Self::response_format())).await?;
+
+        let status = response.status();
+        if status.is_client_error() {
+            let body = response.text().await.unwrap_or_default();
+            // Servers without response formats reject the request and usually name the field
+            if body.contains("response_format") || body.contains("json_schema") {
+                return Ok(None);
+            }
+            return Err(LlmError::ResponseError(format!(
+                "API responded with status code: {}",
+                status
+            )));
         }
 
-        let content = &prompt_response.choices[0].message.content;
+        Self::read_content(response).await.map(Some)
+    }
+
+    fn parse_structured(content: &str) -> Result<SqlGenerationOutput, LlmError> {
+        let output: SqlGenerationOutput = serde_json::from_str(content).map_err(|e| {
+            LlmError::ResponseError(format!("Response doesn't match the requested format: {}", e))
+        })?;
 
-        // Extract SQL from the response
-        if let Some(start) = content.find("```sql") {
-            if let Some(end) = content.rfind("```") {
-                let sql = &content[start + 6..end].trim();
-                return Ok(sql.to_string());
+        if output.sql.trim().is_empty() {
+            return Err(LlmError::ResponseError("Response has no SQL".to_string()));
+        }
+        if !(0.0..=1.0).contains(&output.confidence) {
+            return Err(LlmError::ResponseError(format!(
+                "Response confidence {} is outside 0 to 1",
+                output.confidence
+            )));
+        }
+
+        Ok(output)
+    }
+
+    // The reply either continues the ```sql block the prompt opened or opens its own
+    fn extract_fenced_sql(content: &str) -> String {
+        let sql = content.find("```sql").map_or(content, |start| &content[start + 6..]);
+        let sql = sql.find("```").map_or(sql, |end| &sql[..end]);
+        sql.trim().to_string()
+    }
+}
+
+#[async_trait]
+impl SqlGenerator for RemoteLlmProvider {
+    async fn generate_sql(&self, prompt: &str) -> Result<String, LlmError> {
+        if self.structured_output.load(Ordering::Relaxed) {
+            let messages = vec![
+                Message {
+                    role: "system".to_string(),
+                    content: STRUCTURED_SYSTEM_PROMPT.to_string(),
+                },
+                Message {
+                    role: "user".to_string(),
+                    content: prompt.to_string(),
+                },
+            ];
+
+            match self.complete_structured(messages).await? {
+                Some(content) => match Self::parse_structured(&content) {
+                    Ok(output) => {
+                        debug!(
+                            "Generated SQL with confidence {} using tables {:?}: {}",
+                            output.confidence,
+                            output.tables_used,
+                            output.explanation.as_deref().unwrap_or_default()
+                        );
+                        return Ok(output.sql.trim().to_string());
+                    }
+                    // Some servers accept a response format and then ignore it, answering in text
+                    Err(_) if serde_json::from_str::<Value>(&content).is_err() => {
+                        warn!("LLM server ignored the response format, extracting SQL from code blocks instead");
+                        self.structured_output.store(false, Ordering::Relaxed);
+                        if content.contains("```sql") {
+                            return Ok(Self::extract_fenced_sql(&content));
+                        }
+                    }
+                    Err(e) => return Err(e),
+                },
+                None => {
+                    warn!("LLM server doesn't support response formats, extracting SQL from code blocks instead");
+                    self.structured_output.store(false, Ordering::Relaxed);
+                }
             }
         }
 
-        // If we couldn't find explicit SQL code block, return the whole thing
-        Ok(content.clone())
+        let messages = vec![Message {
+            role: "user".to_string(),
+            content: format!("{}{}", prompt, COMPLETION_SUFFIX),
+        }];
+
+        let content = self.complete(messages).await?;
+        Ok(Self::extract_fenced_sql(&content))
+    }
+
+    // OpenAI-compatible servers list their models next to the chat completions endpoint
+    async fn health_check(&self) -> Option<Result<(), LlmError>> {
+        let models_url = self.api_url.strip_suffix("/chat/completions")?;
+
+        let result = match self
+            .client
+            .get(format!("{}/models", models_url))
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(LlmError::ResponseError(format!(
+                "API responded with status code: {}",
+                response.status()
+            ))),
+            Err(e) => Err(LlmError::ConnectionError(e.to_string())),
+        };
+        Some(result)
     }
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 use crate::db::multi_db_pool::MultiDbConnectionManager;
 use clap::Parser;
 use r2d2::Pool;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::{error, info};
 
@@ -9,15 +9,20 @@ mod config;
 mod db;
 mod ingest;
 mod llm;
+mod roles;
 mod util;
 mod web;
 
 use crate::config::{AppConfig, CliArgs};
 use crate::db::db_pool::DuckDBConnectionManager;
+use crate::llm::cache::SqlCache;
+use crate::llm::prompts::PromptTemplates;
 use crate::llm::LlmManager;
 use crate::util::logging::init_tracing;
 use crate::web::state::AppState;
 
+const DEFAULT_SQL_CACHE_TTL_HOURS: u64 = 24;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
@@ -57,7 +62,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Initialize LLM manager
     info!("Initializing LLM manager with backend: {}", config.llm.backend);
-    let llm_manager = LlmManager::new(&config.llm)?;
+    let mut llm_manager = LlmManager::new(&config.llm)?;
+
+    // Cache generated SQL in the main database so repeated questions skip the LLM
+    let cache_ttl_hours = config.llm.cache_ttl_hours.unwrap_or(DEFAULT_SQL_CACHE_TTL_HOURS);
+    if cache_ttl_hours > 0 {
+        let sql_cache = SqlCache::new(pool.clone(), cache_ttl_hours);
+        if let Err(e) = sql_cache.init() {
+            error!("Failed to initialize SQL cache: {}", e);
+            return Err(e.to_string().into());
+        }
+        llm_manager = llm_manager.with_cache(sql_cache);
+    }
+
+    // Prompt templates from the prompts directory, with the ones edited through the API
+    let prompts = PromptTemplates::new(pool.clone(), config.llm.prompts_dir.as_deref().map(Path::new));
+    if let Err(e) = prompts.init() {
+        error!("Failed to initialize prompt templates: {}", e);
+        return Err(e.to_string().into());
+    }
+    llm_manager = llm_manager.with_prompts(Arc::new(prompts));
 
     // Create application state with the multi-db manager
     let app_state = Arc::new(AppState::new_with_multi_db(
@@ -75,6 +99,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         // Continue anyway, it will be refreshed later
     }
 
+    // Initialize report storage in the main database
+    info!("Initializing report store");
+    if let Err(e) = app_state.report_store.init() {
+        error!("Failed to initialize report store: {}", e);
+        return Err(e.to_string().into());
+    }
+
+    // Initialize query history storage in the main database
+    info!("Initializing query history store");
+    if let Err(e) = app_state.history_store.init() {
+        error!("Failed to initialize query history store: {}", e);
+        return Err(e.to_string().into());
+    }
+
+    // Initialize API keys, making sure there is an admin key to manage the others with
+    info!("Initializing API key store");
+    if let Err(e) = web::auth::init_api_keys(&app_state) {
+        error!("Failed to initialize API key store: {}", e);
+        return Err(e.to_string().into());
+    }
+
     // Initialize subjects
     info!("Initializing subjects");
     if let Err(e) = app_state.refresh_subjects().await {
@@ -88,7 +133,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(_) => info!("Server stopped gracefully"),
         Err(e) => {
             error!("Server error: {}", e);
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())) as Box<dyn std::error::Error>);
+            return Err(Box::new(std::io::Error::other(e.to_string())) as Box<dyn std::error::Error>);
         }
     }
 
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Subject name that grants a role on every subject
+pub const ALL_SUBJECTS: &str = "*";
+
+/// What an API key or signed-in user may do with a subject, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Query,
+    Upload,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Read => write!(f, "read"),
+            Role::Query => write!(f, "query"),
+            Role::Upload => write!(f, "upload"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Whether a set of per-subject grants includes `role` (or a higher one) on `subject`
+pub fn roles_allow(roles: &HashMap<String, Role>, subject: &str, role: Role) -> bool {
+    [roles.get(subject), roles.get(ALL_SUBJECTS)]
+        .into_iter()
+        .flatten()
+        .any(|granted| *granted >= role)
+}
@@ -0,0 +1,228 @@
+use crate::roles::{roles_allow, Role, ALL_SUBJECTS};
+use crate::web::error::AppError;
+use crate::web::oidc::cookie_value;
+use crate::web::state::AppState;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::{header, request::Parts, HeaderMap};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Header API clients send their key in, `Authorization: Bearer <key>` works too
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Cookie the web UI keeps its key in, so EventSource and uploads are authenticated as well
+pub const API_KEY_COOKIE: &str = "nl_cube_api_key";
+
+/// Sent with 401s when browsers should sign in with the identity provider instead of using a key
+pub const LOGIN_URL_HEADER: &str = "x-nl-cube-login";
+
+/// File in the data directory a generated bootstrap admin key is written to
+const BOOTSTRAP_KEY_FILE: &str = "bootstrap-admin.key";
+
+/// Who is making a request, attached by `require_api_key`
+#[derive(Debug, Clone)]
+pub struct Caller {
+    // Key name or signed-in user, None when authentication is turned off
+    user: Option<String>,
+    // None when authentication is turned off and everything is allowed
+    roles: Option<HashMap<String, Role>>,
+}
+
+impl Caller {
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn can(&self, subject: &str, role: Role) -> bool {
+        match &self.roles {
+            Some(roles) => roles_allow(roles, subject, role),
+            None => true,
+        }
+    }
+
+    pub fn require(&self, subject: &str, role: Role) -> Result<(), AppError> {
+        if self.can(subject, role) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Caller lacks {} access to subject '{}'",
+                role, subject
+            )))
+        }
+    }
+
+    /// Managing keys and anything spanning every subject needs admin on all subjects
+    pub fn require_global(&self, role: Role) -> Result<(), AppError> {
+        if self.can(ALL_SUBJECTS, role) {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden(format!(
+                "Caller lacks {} access to all subjects",
+                role
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+impl Caller {
+    /// A caller holding `roles`, as `require_api_key` would attach for a key
+    pub fn with_roles(user: &str, roles: HashMap<String, Role>) -> Self {
+        Self {
+            user: Some(user.to_string()),
+            roles: Some(roles),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Caller>()
+            .cloned()
+            .ok_or_else(|| AppError::Unauthorized("API key required".to_string()))
+    }
+}
+
+/// Middleware for /api routes that checks the caller's key or sign-in session and records who they are
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if !state.config.auth.enabled {
+        request.extensions_mut().insert(Caller {
+            user: None,
+            roles: None,
+        });
+        return Ok(next.run(request).await);
+    }
+
+    let Some(secret) = presented_key(request.headers()) else {
+        let session = state
+            .oidc
+            .as_ref()
+            .and_then(|oidc| oidc.session(request.headers()));
+
+        return match (session, &state.oidc) {
+            (Some(session), _) => {
+                request.extensions_mut().insert(Caller {
+                    user: Some(session.user),
+                    roles: Some(session.roles),
+                });
+                Ok(next.run(request).await)
+            }
+            (None, Some(_)) => Ok((
+                [(LOGIN_URL_HEADER, "/auth/login")],
+                AppError::Unauthorized("Sign-in or API key required".to_string()),
+            )
+                .into_response()),
+            (None, None) => Err(AppError::Unauthorized("API key required".to_string())),
+        };
+    };
+
+    let key = state
+        .api_keys
+        .authenticate(&secret)
+        .map_err(|e| {
+            error!("Failed to check API key: {}", e);
+            AppError::Internal("Failed to check API key".to_string())
+        })?
+        .ok_or_else(|| {
+            warn!("Rejected request with unknown API key");
+            AppError::Unauthorized("Invalid API key".to_string())
+        })?;
+
+    request.extensions_mut().insert(Caller {
+        user: Some(key.name),
+        roles: Some(key.roles),
+    });
+    Ok(next.run(request).await)
+}
+
+/// Middleware for the UI routes that sends browsers without a session to the identity provider
+pub async fn require_login(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match &state.oidc {
+        Some(oidc) if state.config.auth.enabled && oidc.session(request.headers()).is_none() => {
+            Redirect::to("/auth/login").into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+fn presented_key(headers: &HeaderMap) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string());
+
+    let api_key_header = || {
+        headers
+            .get(API_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+    };
+
+    let cookie = || cookie_value(headers, API_KEY_COOKIE);
+
+    bearer
+        .or_else(api_key_header)
+        .or_else(cookie)
+        .filter(|key| !key.is_empty())
+}
+
+/// Create the key table and, when authentication is on, an admin key if there are none yet
+pub fn init_api_keys(state: &AppState) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    state.api_keys.init()?;
+
+    if !state.config.auth.enabled || state.api_keys.count()? > 0 {
+        return Ok(());
+    }
+
+    let roles = HashMap::from([(ALL_SUBJECTS.to_string(), Role::Admin)]);
+    match &state.config.auth.bootstrap_key {
+        Some(secret) => {
+            state.api_keys.insert("bootstrap-admin", secret, roles)?;
+            info!("Stored the configured bootstrap admin key");
+        }
+        None => {
+            let (_, secret) = state.api_keys.create("bootstrap-admin", roles)?;
+            // Only chance to see this key, it is stored hashed, so hand it over in a file only the
+            // server's user can read rather than in the logs
+            let path = state.data_dir.join(BOOTSTRAP_KEY_FILE);
+            write_private_file(&path, &secret)?;
+            warn!(
+                "No API keys exist, created an admin key and wrote it to {}, delete the file once it is saved elsewhere",
+                path.display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn write_private_file(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(contents.as_bytes())?;
+    writeln!(file)
+}
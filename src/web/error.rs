@@ -0,0 +1,251 @@
+use crate::db::running_queries::QueryInterrupted;
+use crate::ingest::IngestError;
+use crate::llm::models::SqlAttempt;
+use crate::llm::LlmError;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+/// Everything an API request can fail with. Each variant has a stable code clients can
+/// match on, sent alongside a readable message and any details that help explain it.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    SubjectNotFound(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    /// The sign-in provider couldn't be reached or gave an unusable answer
+    IdentityProvider(String),
+    /// Statements the read-only guard refused to run
+    QueryNotAllowed(String),
+    /// DuckDB couldn't prepare or run the SQL, holds DuckDB's message
+    Sql(String),
+    QueryInterrupted(QueryInterrupted),
+    /// No attempt at generating SQL for a question passed validation
+    SqlGenerationFailed(Vec<SqlAttempt>),
+    Llm { provider: String, error: LlmError },
+    Ingest(IngestError),
+    Database(String),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+}
+
+impl AppError {
+    /// Machine-readable error code, these never change once released
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::SubjectNotFound(_) => "subject_not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::UnsupportedMediaType(_) => "unsupported_media_type",
+            AppError::IdentityProvider(_) => "identity_provider_error",
+            AppError::QueryNotAllowed(_) => "query_not_allowed",
+            AppError::Sql(_) => "sql_error",
+            AppError::QueryInterrupted(QueryInterrupted::Cancelled) => "query_cancelled",
+            AppError::QueryInterrupted(QueryInterrupted::TimedOut(_)) => "query_timeout",
+            AppError::SqlGenerationFailed(_) => "sql_generation_failed",
+            AppError::Llm { error, .. } => match error {
+                LlmError::ConnectionError(_) => "llm_unavailable",
+                LlmError::ResponseError(_) => "llm_bad_response",
+                LlmError::ConfigError(_) => "llm_misconfigured",
+            },
+            AppError::Ingest(error) => match error {
+                IngestError::UnsupportedFileType(_) => "unsupported_file_type",
+                IngestError::ParseError(_) => "file_parse_error",
+                IngestError::SchemaMismatch(_) => "schema_mismatch",
+                IngestError::InvalidOptions(_) => "invalid_upload_options",
+                IngestError::IoError(_) | IngestError::DatabaseError(_) => "ingest_failed",
+            },
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) | AppError::Sql(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) | AppError::QueryNotAllowed(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) | AppError::SubjectNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::IdentityProvider(_) => StatusCode::BAD_GATEWAY,
+            AppError::QueryInterrupted(QueryInterrupted::Cancelled) => StatusCode::CONFLICT,
+            AppError::QueryInterrupted(QueryInterrupted::TimedOut(_)) => StatusCode::REQUEST_TIMEOUT,
+            AppError::SqlGenerationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Llm { error, .. } => match error {
+                LlmError::ConnectionError(_) => StatusCode::SERVICE_UNAVAILABLE,
+                LlmError::ResponseError(_) => StatusCode::BAD_GATEWAY,
+                LlmError::ConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Ingest(error) => match error {
+                IngestError::UnsupportedFileType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                IngestError::ParseError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+                IngestError::SchemaMismatch(_) => StatusCode::CONFLICT,
+                IngestError::InvalidOptions(_) => StatusCode::BAD_REQUEST,
+                IngestError::IoError(_) | IngestError::DatabaseError(_) => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            },
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Structured context for the error, when there is any
+    pub fn details(&self) -> Option<Value> {
+        match self {
+            AppError::SubjectNotFound(subject) => Some(json!({ "subject": subject })),
+            AppError::Sql(message) => sql_error_details(message),
+            AppError::QueryInterrupted(QueryInterrupted::TimedOut(timeout)) => {
+                Some(json!({ "timeout_secs": timeout.as_secs() }))
+            }
+            AppError::SqlGenerationFailed(attempts) => Some(json!({ "attempts": attempts })),
+            AppError::Llm { provider, .. } => Some(json!({ "provider": provider })),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::UnsupportedMediaType(msg)
+            | AppError::IdentityProvider(msg)
+            | AppError::QueryNotAllowed(msg)
+            | AppError::Database(msg)
+            | AppError::Internal(msg) => write!(f, "{}", msg),
+            AppError::SubjectNotFound(subject) => write!(f, "Subject '{}' not found", subject),
+            AppError::Sql(msg) => write!(f, "SQL error: {}", msg),
+            AppError::QueryInterrupted(interrupted) => write!(f, "{}", interrupted),
+            AppError::SqlGenerationFailed(attempts) => {
+                write!(f, "Failed to generate valid SQL after {} attempts", attempts.len())
+            }
+            AppError::Llm { error, .. } => write!(f, "{}", error),
+            AppError::Ingest(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl From<QueryInterrupted> for AppError {
+    fn from(interrupted: QueryInterrupted) -> Self {
+        AppError::QueryInterrupted(interrupted)
+    }
+}
+
+impl From<IngestError> for AppError {
+    fn from(error: IngestError) -> Self {
+        AppError::Ingest(error)
+    }
+}
+
+// DuckDB names the kind of error first and, for errors it can place, echoes the offending
+// line as `LINE n: ...` with a caret under the position on the next line
+fn sql_error_details(message: &str) -> Option<Value> {
+    let mut details = Map::new();
+
+    if let Some((kind, _)) = message.split_once(": ")
+        && kind.ends_with(" Error")
+        && !kind.contains('\n')
+    {
+        details.insert("error_type".to_string(), json!(kind));
+    }
+
+    let lines: Vec<&str> = message.lines().collect();
+    for (index, line) in lines.iter().enumerate() {
+        let Some((number, _)) = line.strip_prefix("LINE ").and_then(|rest| rest.split_once(": ")) else {
+            continue;
+        };
+        let Ok(line_number) = number.parse::<usize>() else {
+            continue;
+        };
+        details.insert("line".to_string(), json!(line_number));
+
+        let prefix_len = "LINE ".len() + number.len() + ": ".len();
+        if let Some(caret) = lines.get(index + 1).and_then(|next| next.find('^'))
+            && caret >= prefix_len
+        {
+            details.insert("column".to_string(), json!(caret - prefix_len + 1));
+        }
+        break;
+    }
+
+    (!details.is_empty()).then_some(Value::Object(details))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_error_type_and_position() {
+        let message = "Parser Error: syntax error at or near \"FORM\"\n\nLINE 1: SELECT * FORM orders\n                 ^";
+        assert_eq!(
+            sql_error_details(message),
+            Some(json!({ "error_type": "Parser Error", "line": 1, "column": 10 }))
+        );
+    }
+
+    #[test]
+    fn leaves_out_what_the_message_does_not_say() {
+        assert_eq!(
+            sql_error_details("Catalog Error: Table with name orders does not exist!"),
+            Some(json!({ "error_type": "Catalog Error" }))
+        );
+        // A line without a caret under it
+        assert_eq!(
+            sql_error_details("Binder Error: Referenced column \"regoin\" not found\nLINE 3: WHERE regoin = 'EU'"),
+            Some(json!({ "error_type": "Binder Error", "line": 3 }))
+        );
+        assert_eq!(sql_error_details("Something went wrong: LINE 2: not really"), None);
+        assert_eq!(sql_error_details("database is locked"), None);
+    }
+
+    #[test]
+    fn reads_duckdb_errors() {
+        let conn = duckdb::Connection::open_in_memory().unwrap();
+        let error = conn.execute("SELECT\n  regoin FROM range(3)", []).unwrap_err().to_string();
+
+        let details = sql_error_details(&error).unwrap();
+        assert_eq!(details["error_type"], "Binder Error");
+        assert_eq!(details["line"], 2);
+        assert_eq!(details["column"], 3);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::web::error::AppError;
+use axum::extract::multipart::MultipartRejection;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// axum's own extractors answer a bad request with a plain-text body, these wrap them so
+// the client gets the same JSON error as from any handler
+
+/// JSON request body, or a JSON response
+#[derive(Debug, Clone, Default)]
+pub struct Json<T>(pub T);
+
+/// Deserialized query string
+#[derive(Debug, Clone, Default)]
+pub struct Query<T>(pub T);
+
+/// Deserialized path parameters
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+/// `multipart/form-data` request body
+pub struct Multipart(pub axum::extract::Multipart);
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req, state).await?;
+        Ok(Json(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) = axum::extract::Query::<T>::from_request_parts(parts, state).await?;
+        Ok(Query(value))
+    }
+}
+
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Path(value) = axum::extract::Path::<T>::from_request_parts(parts, state).await?;
+        Ok(Path(value))
+    }
+}
+
+impl<S: Send + Sync> FromRequest<S> for Multipart {
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Multipart(axum::extract::Multipart::from_request(req, state).await?))
+    }
+}
+
+// Keeps axum's explanation of what was wrong, under one of our codes
+fn rejected(status: StatusCode, message: String) -> AppError {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge(message),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => AppError::UnsupportedMediaType(message),
+        status if status.is_server_error() => AppError::Internal(message),
+        _ => AppError::BadRequest(message),
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<MultipartRejection> for AppError {
+    fn from(rejection: MultipartRejection) -> Self {
+        rejected(rejection.status(), rejection.body_text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::{to_bytes, Body};
+    use axum::routing::post;
+    use axum::Router;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    #[derive(Deserialize)]
+    struct Limit {
+        #[allow(dead_code)]
+        limit: usize,
+    }
+
+    async fn limit(Query(_): Query<Limit>, Json(body): Json<Value>) -> Json<Value> {
+        Json(body)
+    }
+
+    async fn send(request: Request) -> (StatusCode, Value) {
+        let app = Router::new().route("/", post(limit));
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn request(uri: &str, content_type: &str, body: &str) -> Request {
+        Request::post(uri)
+            .header("content-type", content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_bad_requests_with_json_errors() {
+        let (status, body) = send(request("/?limit=10", "application/json", "{\"a\": 1}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["a"], 1);
+
+        let (status, body) = send(request("/?limit=ten", "application/json", "{}")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "bad_request");
+
+        let (status, body) = send(request("/?limit=10", "application/json", "{")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "bad_request");
+        assert!(body["message"].as_str().unwrap().contains("JSON"));
+
+        let (status, body) = send(request("/?limit=10", "text/plain", "{}")).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["code"], "unsupported_media_type");
+    }
+}
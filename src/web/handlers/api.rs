@@ -1,36 +1,160 @@
 use axum::{
-    extract::{Path, State},
+    body::{Body, Bytes},
+    extract::State,
     http::{header, HeaderMap, HeaderValue, StatusCode},
-    response::{IntoResponse, Response},
-    Json,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
 };
+use futures_util::stream::{self, Stream, StreamExt};
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Instant;
 use tracing::{debug, error, info, warn};
 
+use crate::db::api_key_store::ApiKey;
+use crate::db::arrow_stream::{body_channel, ResultColumn, ResultMeta, ResultWindow, WindowedQuery};
+use crate::db::export::{export_query, ExportFormat};
+use crate::db::history_store::HistoryFilter;
+use crate::db::result_cache::{etag_matches, CachedResult};
+use crate::db::running_queries::{QueryGuard, RunningQueryInfo};
+use crate::db::query_guard::{ensure_read_only, open_for_query};
+use crate::db::report_store::{NewReport, Report, ReportChanges};
+use crate::ingest::jobs::IngestJob;
+use crate::llm::chain::ProviderStatus;
+use crate::llm::models::{QueryHistoryItem, QueryKind, SqlAttempt};
+use crate::llm::prompts::{self, PromptContext, PromptExample, PromptTemplate};
+use crate::llm::providers::ollama;
+use crate::roles::{Role, ALL_SUBJECTS};
+use crate::web::auth::Caller;
+use crate::web::error::AppError;
+use crate::web::extract::{Json, Path, Query};
+use crate::web::query_id::{QueryId, QUERY_ID_HEADER};
 use crate::web::state::AppState;
+use crate::web::subject::{is_valid_subject_name, subject_cookie, SelectedSubject};
 
 // Query types
 
 #[derive(Debug, Deserialize)]
 pub struct ExecuteQueryRequest {
     pub query: String,
+    // Overrides the subject selected for this session
+    #[serde(default)]
+    pub subject: Option<String>,
+    // Page of the result to return, capped by the server's row limit
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: Option<usize>,
 }
 
+// Results are sent as an Arrow IPC stream, written batch by batch
+const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+// Everything known about a query result besides its rows, sent as headers or as the
+// JSON part of a multipart response
 #[derive(Debug, Serialize)]
 pub struct QueryMetadata {
-    pub columns: Vec<String>,
-    pub row_count: usize,
+    pub query_id: String,
+    pub subject: String,
+    pub sql: String,
+    pub columns: Vec<ResultColumn>,
+    // Left out when the rows are streamed without a limit, they aren't counted before they are sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_count: Option<usize>,
+    pub truncated: bool,
+    pub offset: usize,
+    pub limit: Option<usize>,
     pub execution_time_ms: u64,
+    pub warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_cache: Option<&'static str>,
+    // Natural language queries only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub question: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql_cache: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<SqlAttempt>,
+}
+
+impl QueryMetadata {
+    fn new(
+        query_id: &QueryId,
+        subject: &str,
+        sql: &str,
+        meta: &ResultMeta,
+        window: ResultWindow,
+        started: Instant,
+    ) -> Self {
+        let mut warnings = Vec::new();
+        if let Some(rows) = meta.row_count.filter(|_| meta.truncated) {
+            warnings.push(format!(
+                "The result has more rows than the {} returned, request the next page to see them",
+                rows
+            ));
+        }
+
+        Self {
+            query_id: query_id.0.clone(),
+            subject: subject.to_string(),
+            sql: sql.to_string(),
+            columns: meta.columns.clone(),
+            row_count: meta.row_count,
+            total_count: meta.total_rows,
+            truncated: meta.truncated,
+            offset: window.offset,
+            limit: window.limit,
+            execution_time_ms: started.elapsed().as_millis() as u64,
+            warnings,
+            result_cache: None,
+            question: None,
+            sql_cache: None,
+            attempts: Vec::new(),
+        }
+    }
+}
+
+// How the client wants result metadata sent, picked with the Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultFormat {
+    // Arrow stream body, metadata in X- headers
+    Headers,
+    // A JSON metadata part followed by an Arrow stream part
+    Multipart,
+}
+
+impl ResultFormat {
+    fn from_request(headers: &HeaderMap) -> Self {
+        let wants_multipart = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("multipart/mixed"));
+
+        if wants_multipart {
+            ResultFormat::Multipart
+        } else {
+            ResultFormat::Headers
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NlQueryRequest {
     pub question: String,
+    // Overrides the subject selected for this session
+    #[serde(default)]
+    pub subject: Option<String>,
+    // Runs this query on another model of the active provider, for comparing models
+    #[serde(default)]
+    pub model: Option<String>,
 }
 
 // Report types
@@ -41,19 +165,83 @@ pub struct SaveReportRequest {
     pub category: String,
     pub question: Option<String>,
     pub sql: String,
+    #[serde(default)]
     pub config: serde_json::Value,
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateReportRequest {
+    pub name: Option<String>,
+    pub category: Option<String>,
+    pub question: Option<String>,
+    pub sql: Option<String>,
+    pub config: Option<serde_json::Value>,
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportFilter {
+    pub subject: Option<String>,
+    pub category: Option<String>,
+}
+
+// History types
+
+// Largest page of history entries returned at once
+const MAX_HISTORY_PAGE: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    // Text to look for in the question or SQL
+    pub q: Option<String>,
+    pub subject: Option<String>,
+    pub user: Option<String>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct Report {
-    pub id: String,
+pub struct HistoryPage {
+    pub entries: Vec<QueryHistoryItem>,
+    pub total: usize,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+// What a query has found out about itself by the time it finishes or fails
+#[derive(Debug, Default)]
+struct HistoryDraft {
+    subject: Option<String>,
+    sql: Option<String>,
+    row_count: Option<usize>,
+}
+
+// Export types
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    pub report: Option<String>,
+    pub sql: Option<String>,
+    pub table: Option<String>,
+    pub subject: Option<String>,
+}
+
+// API key types
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
     pub name: String,
-    pub category: String,
-    pub question: Option<String>,
-    pub sql: String,
-    pub config: serde_json::Value,
-    pub created_at: String,
-    pub updated_at: String,
+    // Subject name (or "*" for all subjects) to role
+    pub roles: HashMap<String, Role>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    // Only returned here, the store keeps a hash
+    pub secret: String,
 }
 
 // Subject types
@@ -74,31 +262,107 @@ pub struct SystemStatus {
     pub subject_count: usize,
     pub table_count: usize,
     pub report_count: usize,
+    pub llm_providers: Vec<ProviderStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LlmProviderInfo {
+    pub backend: String,
+    pub model: String,
+    pub api_url: Option<String>,
+    // Models the server has installed, only listed for Ollama
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installed_models: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LlmSettings {
+    // Providers queries go to now, in the order they are tried
+    pub active: Vec<LlmProviderInfo>,
+    // Providers in the config file that can be switched to
+    pub configured: Vec<LlmProviderInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwitchLlmRequest {
+    pub backend: String,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SavePromptRequest {
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromptPreviewRequest {
+    pub question: String,
+    pub subject: Option<String>,
+    // Defaults to the active provider
+    pub backend: Option<String>,
+    // Preview an unsaved template instead of the one the question would use
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PromptPreview {
+    // The template that was rendered, None for an unsaved one
+    pub name: Option<String>,
+    pub prompt: String,
 }
 
 // API Implementations
 
-// Query execution
+// Query execution, every run is kept in the query history
 pub async fn execute_query(
     state: State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
+    query_id: QueryId,
+    request_headers: HeaderMap,
     Json(payload): Json<ExecuteQueryRequest>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let mut draft = HistoryDraft {
+        sql: Some(payload.query.clone()),
+        ..Default::default()
+    };
+
+    let result = run_query(&state, &caller, selected, payload, &query_id, &request_headers, &mut draft).await;
+    record_history(&state, &caller, QueryKind::Sql, None, draft, started, &result);
+    result.map(|response| with_query_id(response, &query_id))
+}
+
+async fn run_query(
+    state: &Arc<AppState>,
+    caller: &Caller,
+    selected: SelectedSubject,
+    payload: ExecuteQueryRequest,
+    query_id: &QueryId,
+    request_headers: &HeaderMap,
+    draft: &mut HistoryDraft,
+) -> Result<Response, AppError> {
     let start_time = Instant::now();
     info!("Executing SQL query: {}", payload.query);
 
-    // Always use the currently selected subject for direct table queries
-    let subject_name = match state.current_subject.read().await.clone() {
+    let format = ResultFormat::from_request(request_headers);
+    let if_none_match = request_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+
+    // Use the subject chosen for this request or session for direct table queries
+    let requested_subject = selected.or_explicit(payload.subject.clone())?;
+    let subject_guessed = requested_subject.is_none();
+    let subject_name = match requested_subject {
         Some(subject) => subject,
         None => extract_schema_from_query(&payload.query)
             .or_else(|| {
-                if let Some(first_subject) = state.subjects.try_read().ok().and_then(|s| s.first().cloned()) {
-                    Some(first_subject)
-                } else {
-                    None
-                }
+                state.subjects.try_read().ok().and_then(|s| s.first().cloned())
             })
             .unwrap_or_else(|| "main".to_string())
     };
+    draft.subject = Some(subject_name.clone());
+    caller.require(&subject_name, Role::Query)?;
 
     info!("Using subject '{}' for direct query", subject_name);
 
@@ -111,127 +375,340 @@ pub async fn execute_query(
     // Make sure subject directory exists
     if !subject_dir.exists() {
         error!("Subject directory does not exist: {}", subject_dir.display());
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Subject '{}' not found", subject_name)
-        ));
+        return Err(AppError::SubjectNotFound(subject_name));
     }
 
+    let allow_writes = state.config.database.allow_writes;
+
     // Create the database file if it doesn't exist yet
     if !db_path.exists() {
         info!("Creating new database file at: {}", db_path.display());
         std::fs::create_dir_all(&subject_dir).map_err(|e| {
             error!("Failed to create subject directory: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to create subject directory: {}", e)
-            )
+            AppError::Internal(format!("Failed to create subject directory: {}", e))
+        })?;
+        duckdb::Connection::open(&db_path).map_err(|e| {
+            error!("Failed to create database at {}: {}", db_path.display(), e);
+            AppError::Database(format!("Database connection error: {}", e))
         })?;
     }
 
-    // Make a direct connection to the subject database
-    let conn = match duckdb::Connection::open(&db_path) {
-        Ok(conn) => conn,
-        Err(e) => {
-            error!("Failed to open database at {}: {}", db_path.display(), e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database connection error: {}", e)
-            ));
-        }
-    };
+    // Refuse anything that could modify the subject's data
+    if let Err(e) = ensure_read_only(&payload.query, allow_writes) {
+        warn!("Rejected query: {}", e);
+        return Err(AppError::QueryNotAllowed(e));
+    }
+
+    let window = ResultWindow::new(payload.offset, payload.limit, state.config.database.max_result_rows);
+
+    // Results of read-only queries only change when the subject's data does,
+    // each page of a result is cached on its own
+    let read_only = ensure_read_only(&payload.query, false).is_ok();
+    let result_key = format!("{}\n-- {}", payload.query, window);
+    let etag = state.result_cache.etag(&subject_name, &result_key);
 
     // Simplify the query - remove schema qualifiers as they aren't needed when connecting directly
     let simplified_sql = simplify_query_for_direct_connection(&payload.query);
-    info!("Qualified SQL: {}", simplified_sql);
 
-    // Execute the query with the direct connection
-    let mut stmt = match conn.prepare(&simplified_sql) {
-        Ok(stmt) => stmt,
-        Err(e) => {
-            error!("Failed to prepare query: {}", e);
-            return Err((StatusCode::BAD_REQUEST, format!("SQL error: {}", e)));
+    if read_only {
+        if if_none_match.is_some_and(|tag| etag_matches(tag, &etag)) {
+            debug!("Client already has the result for this query");
+            return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
         }
-    };
 
-    // Get result as an Arrow batch
-    let arrow_batch = match stmt.query_arrow([]) {
-        Ok(batch) => batch,
-        Err(e) => {
-            error!("Failed to execute query: {}", e);
-            return Err((StatusCode::BAD_REQUEST, format!("SQL error: {}", e)));
+        if let Some(cached) = state.result_cache.get(&subject_name, &result_key) {
+            info!("Serving cached result ({} rows)", cached.meta.row_count.unwrap_or_default());
+            draft.row_count = cached.meta.row_count;
+
+            let mut metadata =
+                QueryMetadata::new(query_id, &subject_name, &simplified_sql, &cached.meta, window, start_time);
+            metadata.result_cache = Some("hit");
+            if subject_guessed {
+                metadata.warnings.push(guessed_subject_warning(&subject_name));
+            }
+            return Ok(with_etag(result_response(format, &metadata, Body::from(cached.body)), &etag));
         }
-    };
+    }
 
-    let schema = arrow_batch.get_schema();
+    info!("Qualified SQL: {}", simplified_sql);
 
-    // Collect the Arrow batch into a Vec of RecordBatch
-    let record_batches = arrow_batch.collect::<Vec<_>>().to_vec();
+    let running = state
+        .running_queries
+        .register(RunningQueryInfo {
+            id: query_id.0.clone(),
+            user: caller.user().map(str::to_string),
+            subject: subject_name.clone(),
+            question: None,
+            sql: payload.query.clone(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+        })
+        .map_err(AppError::Conflict)?;
+    let cache_slot = read_only.then(|| CacheSlot {
+        subject: subject_name.clone(),
+        key: result_key,
+        etag: etag.clone(),
+    });
 
-    // Get row count for metadata
-    let row_count: usize = record_batches.iter().map(|batch| batch.num_rows()).sum();
+    let (meta, body) = stream_query(state, db_path, simplified_sql.clone(), window, running, cache_slot).await?;
+    draft.row_count = meta.row_count;
 
-    // Get column names for metadata
-    let columns = schema
-        .fields()
-        .iter()
-        .map(|field| field.name().clone())
-        .collect::<Vec<String>>();
+    // Allowed writes change the data behind every cached result for this subject
+    if !read_only {
+        state.result_cache.bump_version(&subject_name);
+    }
 
-    // Serialize record batches to IPC format
-    let mut buffer = Vec::new();
+    match meta.row_count {
+        Some(rows) => info!("Query executed successfully. Row count: {}{}, Execution time: {}ms",
+                            rows, if meta.truncated { " (truncated)" } else { "" }, start_time.elapsed().as_millis()),
+        None => info!("Query executed successfully, streaming its rows. Execution time: {}ms",
+                      start_time.elapsed().as_millis()),
+    }
 
-    // Create a file writer with the schema
-    let mut file_writer = match arrow::ipc::writer::FileWriter::try_new(&mut buffer, schema.deref()) {
-        Ok(writer) => writer,
-        Err(e) => {
-            error!("Failed to create Arrow file writer: {}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to serialize Arrow data: {}", e)));
+    let mut metadata = QueryMetadata::new(query_id, &subject_name, &simplified_sql, &meta, window, start_time);
+    metadata.result_cache = Some("miss");
+    if subject_guessed {
+        metadata.warnings.push(guessed_subject_warning(&subject_name));
+    }
+    Ok(with_etag(result_response(format, &metadata, body), &etag))
+}
+
+fn guessed_subject_warning(subject: &str) -> String {
+    format!("No subject was selected, the query ran against '{}'", subject)
+}
+
+// Where a streamed result is cached once all of it has been sent
+struct CacheSlot {
+    subject: String,
+    key: String,
+    // Tag of the data the query started against, a result outdated by a write isn't kept
+    etag: String,
+}
+
+// Run `sql` on a blocking thread and stream its rows back as Arrow IPC while they are read.
+// Returns once the rows of the window are known, before the first one is sent.
+async fn stream_query(
+    state: &Arc<AppState>,
+    db_path: PathBuf,
+    sql: String,
+    window: ResultWindow,
+    mut running: QueryGuard,
+    cache_slot: Option<CacheSlot>,
+) -> Result<(ResultMeta, Body), AppError> {
+    let allow_writes = state.config.database.allow_writes;
+    let copy_limit = cache_slot
+        .as_ref()
+        .map(|_| state.result_cache.max_entry_bytes())
+        .filter(|limit| *limit > 0);
+    let (mut sender, body) = body_channel(copy_limit);
+    let (meta_tx, meta_rx) = tokio::sync::oneshot::channel();
+    let task_state = Arc::clone(state);
+
+    tokio::task::spawn_blocking(move || {
+        let query_id = running.id().to_string();
+
+        let conn = match open_for_query(&db_path, allow_writes) {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to open database at {}: {}", db_path.display(), e);
+                let _ = meta_tx.send(Err(AppError::Database(format!("Database connection error: {}", e))));
+                return;
+            }
+        };
+
+        // Cancelling now interrupts the query, as does running for too long
+        running.attach(&conn);
+        if let Some(interrupted) = running.interruption() {
+            warn!("Query {}: {} before it started", query_id, interrupted);
+            let _ = meta_tx.send(Err(AppError::from(interrupted)));
+            return;
         }
-    };
 
-    // Write all record batches to the buffer
-    for batch in &record_batches {
-        if let Err(e) = file_writer.write(batch) {
-            error!("Failed to write Arrow batch: {}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to serialize Arrow data: {}", e)));
+        let mut query = match WindowedQuery::prepare(&conn, &sql, window) {
+            Ok(query) => query,
+            Err(e) => {
+                let _ = meta_tx.send(Err(query_failure(&running, "prepare", e)));
+                return;
+            }
+        };
+
+        let result = match query.execute() {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = meta_tx.send(Err(query_failure(&running, "execute", e)));
+                return;
+            }
+        };
+
+        let mut meta = result.meta.clone();
+        if meta_tx.send(Ok(meta.clone())).is_err() {
+            // The request went away before the query finished
+            return;
+        }
+
+        let written = result.write_to(&mut sender);
+        if let Some(interrupted) = running.interruption() {
+            warn!("Query {}: {} while sending its result", query_id, interrupted);
+            sender.fail(interrupted.to_string());
+            return;
+        }
+        match written {
+            // A streamed result is only counted once it has all been sent
+            Ok(rows) => meta.row_count = Some(rows),
+            Err(e) => {
+                warn!("Failed to stream result of query {}: {}", query_id, e);
+                sender.fail(e.to_string());
+                return;
+            }
+        }
+
+        match sender.finish() {
+            Ok(Some(copy)) => {
+                if let Some(slot) = cache_slot
+                    && task_state.result_cache.etag(&slot.subject, &slot.key) == slot.etag
+                {
+                    task_state.result_cache.insert(
+                        &slot.subject,
+                        &slot.key,
+                        CachedResult { body: copy, meta },
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to stream result of query {}: {}", query_id, e),
+        }
+    });
+
+    match meta_rx.await {
+        Ok(Ok(meta)) => Ok((meta, body)),
+        Ok(Err(failure)) => Err(failure),
+        Err(_) => {
+            error!("Query task ended without a result");
+            Err(AppError::Internal("Database task execution failed".to_string()))
         }
     }
+}
 
-    // Finalize the stream
-    if let Err(e) = file_writer.finish() {
-        error!("Failed to finalize Arrow file: {}", e);
-        return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to serialize Arrow data: {}", e)));
+// A statement that failed because its query was stopped reports why it was stopped
+fn query_failure(running: &QueryGuard, step: &str, e: duckdb::Error) -> AppError {
+    match running.interruption() {
+        Some(interrupted) => {
+            warn!("Query {}: {}", running.id(), interrupted);
+            AppError::from(interrupted)
+        }
+        None => {
+            error!("Failed to {} query: {}", step, e);
+            AppError::Sql(e.to_string())
+        }
     }
+}
 
-    info!("Query executed successfully. Row count: {}, Execution time: {}ms",
-          row_count, start_time.elapsed().as_millis());
+// Tell the client which id the query ran under
+fn with_query_id(mut response: Response, query_id: &QueryId) -> Response {
+    if let Ok(value) = HeaderValue::from_str(&query_id.0) {
+        response.headers_mut().insert(QUERY_ID_HEADER, value);
+    }
+    response
+}
 
-    // Create response with Arrow data and metadata headers
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/vnd.apache.arrow.file"));
+// Tell the client which version of the data the result came from
+fn with_etag(mut response: Response, etag: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(etag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
 
-    if let Ok(count_header) = HeaderValue::from_str(&row_count.to_string()) {
-        headers.insert("X-Total-Count", count_header);
+// Send the rows along with their metadata in the format the client asked for
+fn result_response(format: ResultFormat, metadata: &QueryMetadata, rows: Body) -> Response {
+    match format {
+        ResultFormat::Headers => {
+            let mut headers = metadata_headers(metadata);
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(ARROW_STREAM_CONTENT_TYPE));
+            (headers, rows).into_response()
+        }
+        ResultFormat::Multipart => multipart_response(metadata, rows),
     }
+}
 
-    if let Ok(time_header) = HeaderValue::from_str(&start_time.elapsed().as_millis().to_string()) {
-        headers.insert("X-Execution-Time", time_header);
+// Metadata as the X- headers the UI reads. Values a header can't carry, like SQL spanning
+// several lines, are left out and named in X-Metadata-Omitted rather than dropped silently.
+fn metadata_headers(metadata: &QueryMetadata) -> HeaderMap {
+    let column_names = metadata
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<_>>();
+
+    let mut values = vec![
+        ("X-Truncated", metadata.truncated.to_string()),
+        ("X-Offset", metadata.offset.to_string()),
+        ("X-Execution-Time", metadata.execution_time_ms.to_string()),
+        ("X-Columns", serde_json::to_string(&column_names).unwrap_or_default()),
+        ("X-Generated-SQL", metadata.sql.clone()),
+    ];
+    if let Some(rows) = metadata.row_count {
+        values.push(("X-Page-Rows", rows.to_string()));
+    }
+    if let Some(total) = metadata.total_count {
+        values.push(("X-Total-Count", total.to_string()));
+    }
+    if let Some(limit) = metadata.limit {
+        values.push(("X-Limit", limit.to_string()));
+    }
+    if let Some(status) = metadata.result_cache {
+        values.push(("X-Result-Cache", status.to_string()));
+    }
+    if let Some(status) = metadata.sql_cache {
+        values.push(("X-SQL-Cache", status.to_string()));
+    }
+    // Report every generation attempt so the client can see how the query was repaired
+    if !metadata.attempts.is_empty() {
+        values.push(("X-SQL-Attempts", serde_json::to_string(&metadata.attempts).unwrap_or_default()));
     }
 
-    if let Ok(columns_json) = serde_json::to_string(&columns) {
-        if let Ok(columns_header) = HeaderValue::from_str(&columns_json) {
-            headers.insert("X-Columns", columns_header);
+    let mut headers = HeaderMap::new();
+    let mut omitted = Vec::new();
+    for (name, value) in values {
+        match HeaderValue::from_str(&value) {
+            Ok(value) => {
+                headers.insert(name, value);
+            }
+            Err(_) => omitted.push(name),
         }
     }
 
-    // Add the SQL query as a header for debugging/tracing
-    if let Ok(sql_header) = HeaderValue::from_str(&simplified_sql) {
-        headers.insert("X-Generated-SQL", sql_header);
+    if !omitted.is_empty() {
+        debug!("Left out metadata headers: {}", omitted.join(", "));
+        if let Ok(value) = HeaderValue::from_str(&omitted.join(", ")) {
+            headers.insert("X-Metadata-Omitted", value);
+        }
     }
 
-    // Return the Arrow buffer with appropriate headers
-    Ok((headers, buffer))
+    headers
+}
+
+// The metadata as a JSON part followed by the rows as an Arrow stream part, so nothing has
+// to fit in a header and the rows are still sent as they are read
+fn multipart_response(metadata: &QueryMetadata, rows: Body) -> Response {
+    let boundary = format!("nl-cube-{}", uuid::Uuid::new_v4().simple());
+    let metadata_json = serde_json::to_string(metadata).unwrap_or_else(|_| "{}".to_string());
+
+    let head = format!(
+        "--{0}\r\nContent-Type: application/json\r\n\r\n{1}\r\n--{0}\r\nContent-Type: {2}\r\n\r\n",
+        boundary, metadata_json, ARROW_STREAM_CONTENT_TYPE
+    );
+    let tail = format!("\r\n--{}--\r\n", boundary);
+
+    let body = stream::once(async move { Ok::<_, axum::Error>(Bytes::from(head)) })
+        .chain(rows.into_data_stream())
+        .chain(stream::once(async move { Ok(Bytes::from(tail)) }));
+
+    let mut headers = HeaderMap::new();
+    if let Ok(content_type) = HeaderValue::from_str(&format!("multipart/mixed; boundary={}", boundary)) {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+
+    (headers, Body::from_stream(body)).into_response()
 }
 
 fn simplify_query_for_direct_connection(query: &str) -> String {
@@ -246,189 +723,227 @@ fn simplify_query_for_direct_connection(query: &str) -> String {
 fn extract_schema_from_query(query: &str) -> Option<String> {
     // Simple regex pattern to find schema.table pattern
     let re = regex::Regex::new(r#"["']?([a-zA-Z0-9_]+)["']?\.["']?[a-zA-Z0-9_]+"#).ok()?;
-    if let Some(captures) = re.captures(query) {
-        if let Some(schema_match) = captures.get(1) {
-            let schema = schema_match.as_str().to_string();
-            return Some(schema);
-        }
+    if let Some(captures) = re.captures(query)
+        && let Some(schema_match) = captures.get(1)
+    {
+        let schema = schema_match.as_str().to_string();
+        return Some(schema);
     }
     None
 }
 
-// Natural language query - updated to use table metadata from database directly
+// Natural language query, every question is kept in the query history
 pub async fn nl_query(
     State(app_state): State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
+    query_id: QueryId,
+    request_headers: HeaderMap,
     Json(payload): Json<NlQueryRequest>,
-) -> Result<Response, (StatusCode, String)> {
-    use axum::http::{HeaderName, HeaderValue};
+) -> Result<Response, AppError> {
+    let started = Instant::now();
+    let mut draft = HistoryDraft::default();
+    let format = ResultFormat::from_request(&request_headers);
+
+    let result = run_nl_query(&app_state, &caller, selected, &payload, &query_id, format, &mut draft).await;
+    record_history(
+        &app_state,
+        &caller,
+        QueryKind::Nl,
+        Some(payload.question),
+        draft,
+        started,
+        &result,
+    );
+    result.map(|response| with_query_id(response, &query_id))
+}
+
+// Uses table metadata from the database directly
+async fn run_nl_query(
+    app_state: &Arc<AppState>,
+    caller: &Caller,
+    selected: SelectedSubject,
+    payload: &NlQueryRequest,
+    query_id: &QueryId,
+    format: ResultFormat,
+    draft: &mut HistoryDraft,
+) -> Result<Response, AppError> {
     use tracing::{debug, error, info};
 
     debug!("NL-query: {}", payload.question);
 
     // Find active subject based on the query or use the first available subject
-    let target_subject = determine_query_subject(&app_state).await?;
+    let requested = selected.or_explicit(payload.subject.clone())?;
+    let target_subject = determine_query_subject(app_state, caller, requested).await?;
+    draft.subject = Some(target_subject.clone());
+    caller.require(&target_subject, Role::Query)?;
     info!("Using subject '{}' for query", target_subject);
 
+    // Registered from here on so the query can be cancelled while its SQL is generated
+    let running = app_state
+        .running_queries
+        .register(RunningQueryInfo {
+            id: query_id.0.clone(),
+            user: caller.user().map(str::to_string),
+            subject: target_subject.clone(),
+            question: Some(payload.question.clone()),
+            sql: String::new(),
+            started_at: chrono::Utc::now().to_rfc3339(),
+        })
+        .map_err(AppError::Conflict)?;
+
     // Get the table metadata for the current subject
     let table_metadata = match app_state.get_table_metadata(Some(&target_subject)).await {
         Ok(metadata) => metadata,
         Err(e) => {
             error!("Failed to get table metadata: {}", e);
-            format!("")
+            String::new()
         }
     };
 
     if table_metadata.trim() == "No databases found. Please upload data files first." {
-        return Err((
-            StatusCode::BAD_REQUEST,
+        return Err(AppError::BadRequest(
             "No database tables found – upload some data first".into(),
         ));
     }
 
-    // Generate SQL using LLM
-    let llm = Arc::clone(&app_state.llm_manager);
-    let raw_sql = {
-        let mgr = llm.lock().await;
-        mgr.generate_sql(&payload.question, &table_metadata).await.map_err(|e| {
-            (StatusCode::INTERNAL_SERVER_ERROR, format!("LLM error: {}", e))
-        })?
-    };
-
-    // Validate SQL
-    let sql = raw_sql.replace("`", "");
-
-    let sql_for_headers = sql.clone();
-    info!("Validated SQL: {}", sql);
-
     // Build the path to the subject database
     let subject_dir = app_state.data_dir.join(&target_subject);
     let db_path = subject_dir.join(format!("{}.duckdb", target_subject));
     debug!("Using database at path: {}", db_path.display());
 
-    // Clone for use in the blocking task
-    let db_path_string = db_path.to_string_lossy().to_string();
-    let sql_to_execute = sql.clone();
-
-    // Execute the query and get Arrow data in a blocking task
-    let blocking_task = tokio::task::spawn_blocking(move || -> Result<(Vec<u8>, usize, Vec<String>, u64), Box<dyn std::error::Error + Send + Sync>> {
-        let start_time = std::time::Instant::now();
+    let allow_writes = app_state.config.database.allow_writes;
 
-        // Connect to the database
-        let conn = match duckdb::Connection::open(&db_path_string) {
-            Ok(conn) => conn,
-            Err(e) => return Err(Box::new(e))
-        };
+    // Generate SQL using LLM, letting DuckDB check each attempt before it is executed
+    let generation = {
+        let validation_conn = open_for_query(&db_path, allow_writes).map_err(|e| {
+            error!("Failed to open database at {}: {}", db_path.display(), e);
+            AppError::Database(format!("Database connection error: {}", e))
+        })?;
 
-        // Prepare the statement
-        let mut stmt = match conn.prepare(&sql_to_execute) {
-            Ok(stmt) => stmt,
-            Err(e) => return Err(Box::new(e))
+        let llm = Arc::clone(&app_state.llm_manager);
+        let active = llm.read().await;
+        let overridden = match &payload.model {
+            Some(model) => {
+                if !active.allows_model(model).await {
+                    return Err(AppError::BadRequest(format!(
+                        "Model '{}' isn't configured or installed for the {} provider",
+                        model,
+                        active.config().backend
+                    )));
+                }
+                info!("Using model '{}' for this query", model);
+                Some(active.with_model(model).map_err(|error| AppError::Llm {
+                    provider: active.provider(),
+                    error,
+                })?)
+            }
+            None => None,
         };
-
-        // Execute and get Arrow results
-        let arrow_batch = match stmt.query_arrow([]) {
-            Ok(batch) => batch,
-            Err(e) => return Err(Box::new(e))
+        let mgr = overridden.as_deref().unwrap_or(&*active);
+
+        let context = PromptContext {
+            question: payload.question.clone(),
+            schema: table_metadata,
+            subject: Some(target_subject.clone()),
+            examples: prompt_examples(app_state, &target_subject),
+            ..PromptContext::default()
         };
 
-        let schema = arrow_batch.get_schema();
-
-        // Get column names
-        let columns = schema.fields()
-            .iter()
-            .map(|field| field.name().clone())
-            .collect::<Vec<String>>();
-
-        // Collect the Arrow batch into a Vec of RecordBatch
-        let record_batches = arrow_batch.collect::<Vec<_>>().to_vec();
-
-        // Get row count
-        let row_count: usize = record_batches.iter().map(|batch| batch.num_rows()).sum();
-
-        // Serialize record batches to IPC format
-        let mut buffer = Vec::new();
-
-        // Create a stream writer with the schema
-        let mut stream_writer = match arrow::ipc::writer::FileWriter::try_new(&mut buffer, schema.deref()) {
-            Ok(writer) => writer,
-            Err(e) => return Err(Box::new(e))
-        };
-
-        // Write all record batches to the buffer
-        for batch in &record_batches {
-            if let Err(e) = stream_writer.write(batch) {
-                return Err(Box::new(e));
-            }
-        }
-
-        // Finalize the stream
-        if let Err(e) = stream_writer.finish() {
-            return Err(Box::new(e));
-        }
-
-        let execution_time = start_time.elapsed().as_millis() as u64;
-
-        Ok((buffer, row_count, columns, execution_time))
-    });
-
-    // Properly handle the JoinError
-    let join_result = blocking_task.await;
-    let task_result = match join_result {
-        Ok(result) => result,
-        Err(join_err) => {
-            error!("Task join error: {}", join_err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database task execution failed: {}", join_err)
-            ));
-        }
+        mgr.generate_validated_sql(
+            &context,
+            |sql| validate_sql(&validation_conn, sql, allow_writes),
+            || running.interruption().is_some(),
+        )
+        .await
+        .map_err(|error| AppError::Llm {
+            provider: mgr.provider(),
+            error,
+        })?
     };
 
-    // Handle the actual task result
-    let (arrow_buffer, row_count, columns, execution_time) = match task_result {
-        Ok(result) => result,
-        Err(err) => {
-            error!("Database query error: {}", err);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Database query failed: {}", err)
-            ));
+    if let Some(interrupted) = running.interruption() {
+        warn!("Query {}: {} while generating its SQL", query_id.0, interrupted);
+        return Err(interrupted.into());
+    }
+
+    let attempts = generation.attempts.clone();
+    let cache_status = if generation.cached { "hit" } else { "miss" };
+    draft.sql = attempts.iter().rev().find(|a| !a.sql.is_empty()).map(|a| a.sql.clone());
+    let sql = match generation.sql() {
+        Some(sql) => sql.to_string(),
+        None => {
+            error!(
+                "No valid SQL after {} attempts: {}",
+                attempts.len(),
+                generation.last_error().unwrap_or_default()
+            );
+            return Err(AppError::SqlGenerationFailed(attempts));
         }
     };
 
-    // Create the response with headers
-    let mut headers = HeaderMap::new();
-
-    // Set content type for Arrow data
-    headers.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_static("application/vnd.apache.arrow.file"),
-    );
-
-    // Add metadata headers
-    if let Ok(v) = HeaderValue::from_str(&sql_for_headers) {
-        headers.insert(HeaderName::from_static("x-generated-sql"), v);
+    draft.sql = Some(sql.clone());
+    info!("Validated SQL after {} attempt(s): {}", attempts.len(), sql);
+    running.set_sql(&sql);
+
+    // Stream the rows back as they are read, capped like any other query
+    let started = Instant::now();
+    let window = ResultWindow::new(None, None, app_state.config.database.max_result_rows);
+    let (meta, body) = stream_query(app_state, db_path, sql.clone(), window, running, None).await?;
+    draft.row_count = meta.row_count;
+
+    let mut metadata = QueryMetadata::new(query_id, &target_subject, &sql, &meta, window, started);
+    metadata.question = Some(payload.question.clone());
+    metadata.sql_cache = Some(cache_status);
+    if attempts.len() > 1 {
+        metadata
+            .warnings
+            .push(format!("The generated SQL was repaired after {} attempts", attempts.len()));
     }
+    metadata.attempts = attempts;
 
-    if let Ok(v) = HeaderValue::from_str(&row_count.to_string()) {
-        headers.insert(HeaderName::from_static("x-total-count"), v);
-    }
+    Ok(result_response(format, &metadata, body))
+}
 
-    if let Ok(v) = HeaderValue::from_str(&execution_time.to_string()) {
-        headers.insert(HeaderName::from_static("x-execution-time"), v);
-    }
+// A history write failing shouldn't fail the query, so errors are only logged
+fn record_history(
+    state: &AppState,
+    caller: &Caller,
+    kind: QueryKind,
+    question: Option<String>,
+    draft: HistoryDraft,
+    started: Instant,
+    result: &Result<Response, AppError>,
+) {
+    let item = QueryHistoryItem {
+        id: uuid::Uuid::new_v4().to_string(),
+        kind,
+        user: caller.user().map(str::to_string),
+        subject: draft.subject,
+        question,
+        sql: draft.sql,
+        execution_time_ms: started.elapsed().as_millis() as u64,
+        row_count: draft.row_count,
+        error: result.as_ref().err().map(|e| e.to_string()),
+        timestamp: chrono::Utc::now(),
+    };
 
-    if let Ok(columns_json) = serde_json::to_string(&columns) {
-        if let Ok(v) = HeaderValue::from_str(&columns_json) {
-            headers.insert(HeaderName::from_static("x-columns"), v);
-        }
+    if let Err(e) = state.history_store.record(&item) {
+        error!("Failed to record query history: {}", e);
     }
+}
 
-    // Return the Arrow data with headers
-    Ok((StatusCode::OK, headers, arrow_buffer).into_response())
+// Check that the SQL is allowed and that DuckDB can parse and bind it without running it
+fn validate_sql(conn: &duckdb::Connection, sql: &str, allow_writes: bool) -> Result<(), String> {
+    ensure_read_only(sql, allow_writes)?;
+    conn.prepare(sql).map(|_| ()).map_err(|e| e.to_string())
 }
 
-async fn determine_query_subject(app_state: &Arc<AppState>) -> Result<String, (StatusCode, String)> {
+async fn determine_query_subject(
+    app_state: &Arc<AppState>,
+    caller: &Caller,
+    requested: Option<String>,
+) -> Result<String, AppError> {
     // Refresh schema cache first
     if let Err(e) = app_state.schema_manager.refresh_cache().await {
         error!("Failed to refresh schema cache: {}", e);
@@ -442,10 +957,7 @@ async fn determine_query_subject(app_state: &Arc<AppState>) -> Result<String, (S
         // If subject list is empty, refresh it
         if let Err(e) = app_state.refresh_subjects().await {
             error!("Failed to refresh subjects: {}", e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to determine query subject".to_string(),
-            ));
+            return Err(AppError::Internal("Failed to determine query subject".to_string()));
         }
 
         // Get updated subjects
@@ -453,53 +965,69 @@ async fn determine_query_subject(app_state: &Arc<AppState>) -> Result<String, (S
     }
 
     if subjects.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
+        return Err(AppError::BadRequest(
             "No subjects found. Please create a subject and upload data files.".into(),
         ));
     }
 
-    // Use the currently selected subject if available
-    if let Some(current_subject) = app_state.current_subject.read().await.as_ref() {
+    // Use the subject chosen for this request or session if there is one
+    if let Some(requested) = requested {
         // Verify the subject still exists
-        if subjects.contains(current_subject) {
-            info!("Using currently selected subject '{}'", current_subject);
-            return Ok(current_subject.clone());
+        if subjects.contains(&requested) {
+            info!("Using selected subject '{}'", requested);
+            return Ok(requested);
         }
+        return Err(AppError::SubjectNotFound(requested));
     }
 
-    // If no subject is currently selected, use the first available one
+    // If no subject is selected, use the first available one the caller may query
     // This is a fallback and should only happen if the UI hasn't set a subject yet
-    info!("No subject currently selected, using '{}' as default", subjects[0]);
-    Ok(subjects[0].clone())
+    let default_subject = subjects
+        .iter()
+        .find(|s| caller.can(s, Role::Query))
+        .ok_or_else(|| AppError::Forbidden("API key can't query any subject".to_string()))?;
+    info!("No subject currently selected, using '{}' as default", default_subject);
+    Ok(default_subject.clone())
 }
 
 // Subjects
-pub async fn list_subjects(state: State<Arc<AppState>>) -> Result<Json<Vec<String>>, (StatusCode, String)> {
+pub async fn list_subjects(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<Vec<String>>, AppError> {
     state.refresh_subjects().await.map_err(|e| {
         error!("Failed to refresh subjects: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to list subjects".to_string())
+        AppError::Internal("Failed to list subjects".to_string())
     })?;
 
+    // Only show the subjects this key can see
     let subjects = state.subjects.read().await;
-    Ok(Json(subjects.clone()))
+    Ok(Json(
+        subjects
+            .iter()
+            .filter(|s| caller.can(s, Role::Read))
+            .cloned()
+            .collect(),
+    ))
 }
 
 pub async fn get_subject(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<Json<Subject>, (StatusCode, String)> {
+) -> Result<Json<Subject>, AppError> {
     let subject = path.0;
+    caller.require(&subject, Role::Read)?;
     let subject_path = state.data_dir.join(&subject);
 
     if !subject_path.exists() {
-        return Err((StatusCode::NOT_FOUND, "Subject not found".to_string()));
+        return Err(AppError::SubjectNotFound(subject));
     }
 
     // Count files in the subject directory
     let entries = fs::read_dir(&subject_path).map_err(|e| {
         error!("Failed to read subject directory: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read subject data".to_string())
+        AppError::Internal("Failed to read subject data".to_string())
     })?;
 
     let file_count = entries.count();
@@ -512,10 +1040,7 @@ pub async fn get_subject(
         Ok(conn) => conn,
         Err(e) => {
             error!("Failed to open subject database at {}: {}", db_path.display(), e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Database connection error".to_string()
-            ));
+            return Err(AppError::Database("Database connection error".to_string()));
         }
     };
 
@@ -535,18 +1060,25 @@ pub async fn get_subject(
     }))
 }
 
+// Remembers the subject for this browser session only, other users keep their own selection
 pub async fn select_subject(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<impl IntoResponse, AppError> {
     let subject = path.0;
+    if !is_valid_subject_name(&subject) {
+        return Err(AppError::BadRequest("Invalid subject name".to_string()));
+    }
+    caller.require(&subject, Role::Read)?;
+
     let subject_path = state.data_dir.join(&subject);
     let db_path = subject_path.join(format!("{}.duckdb", subject));
 
     // Check if the database file exists directly
     if !db_path.exists() {
         error!("Subject database file not found at {}", db_path.display());
-        return Err((StatusCode::NOT_FOUND, "Subject database not found".to_string()));
+        return Err(AppError::SubjectNotFound(subject));
     }
 
     info!("Selecting subject: {} (database at {})", subject, db_path.display());
@@ -562,14 +1094,7 @@ pub async fn select_subject(
         }
     }
 
-    // Set the current subject directly, bypassing validation
-    {
-        let mut current = state.current_subject.write().await;
-        *current = Some(subject.clone());
-        info!("Set current subject to: {}", subject);
-    }
-
-    // Also ensure the subject is in the cached list
+    // Ensure the subject is in the cached list
     {
         let mut subjects = state.subjects.write().await;
         if !subjects.contains(&subject) {
@@ -578,7 +1103,8 @@ pub async fn select_subject(
         }
     }
 
-    Ok(StatusCode::OK)
+    info!("Selected subject {} for this session", subject);
+    Ok((StatusCode::OK, [(header::SET_COOKIE, subject_cookie(&subject))]))
 }
 
 // Helper function to get tables from a database connection using multiple approaches
@@ -591,10 +1117,8 @@ fn get_tables_from_database(conn: &duckdb::Connection) -> Result<Vec<String>, Bo
     match conn.prepare(query) {
         Ok(mut stmt) => {
             let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
-            for row in rows {
-                if let Ok(table_name) = row {
-                    tables.push(table_name);
-                }
+            for table_name in rows.flatten() {
+                tables.push(table_name);
             }
         }
         Err(e) => {
@@ -604,10 +1128,8 @@ fn get_tables_from_database(conn: &duckdb::Connection) -> Result<Vec<String>, Bo
             match conn.prepare("SHOW TABLES") {
                 Ok(mut show_stmt) => {
                     let rows = show_stmt.query_map([], |row| row.get::<_, String>(0))?;
-                    for row in rows {
-                        if let Ok(table_name) = row {
-                            tables.push(table_name);
-                        }
+                    for table_name in rows.flatten() {
+                        tables.push(table_name);
                     }
                 }
                 Err(e) => {
@@ -617,10 +1139,8 @@ fn get_tables_from_database(conn: &duckdb::Connection) -> Result<Vec<String>, Bo
                     match conn.prepare("SELECT table_name FROM information_schema.tables WHERE table_schema = 'main'") {
                         Ok(mut info_stmt) => {
                             let rows = info_stmt.query_map([], |row| row.get::<_, String>(0))?;
-                            for row in rows {
-                                if let Ok(table_name) = row {
-                                    tables.push(table_name);
-                                }
+                            for table_name in rows.flatten() {
+                                tables.push(table_name);
                             }
                         }
                         Err(e) => {
@@ -637,14 +1157,12 @@ fn get_tables_from_database(conn: &duckdb::Connection) -> Result<Vec<String>, Bo
         match conn.prepare("PRAGMA table_list") {
             Ok(mut pragma_stmt) => {
                 let rows = pragma_stmt.query_map([], |row| row.get::<_, String>(1))?; // 1 is the name column
-                for row in rows {
-                    if let Ok(table_name) = row {
-                        // Skip internal tables
-                        if !table_name.starts_with("sqlite_") &&
-                            !table_name.starts_with("duck_") &&
-                            !table_name.starts_with("pg_") {
-                            tables.push(table_name);
-                        }
+                for table_name in rows.flatten() {
+                    // Skip internal tables
+                    if !table_name.starts_with("sqlite_") &&
+                        !table_name.starts_with("duck_") &&
+                        !table_name.starts_with("pg_") {
+                        tables.push(table_name);
                     }
                 }
             }
@@ -659,33 +1177,26 @@ fn get_tables_from_database(conn: &duckdb::Connection) -> Result<Vec<String>, Bo
 
 pub async fn create_subject(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<StatusCode, AppError> {
     let subject = path.0;
+    caller.require(&subject, Role::Admin)?;
     // Validate subject name (alphanumeric with underscores)
-    if !subject.chars().all(|c| c.is_alphanumeric() || c == '_') {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Subject name must be alphanumeric with underscores".to_string(),
-        ));
+    if !is_valid_subject_name(&subject) {
+        return Err(AppError::BadRequest("Subject name must be alphanumeric with underscores".to_string()));
     }
 
     // Create the subject directory
     let subject_path = state.data_dir.join(&subject);
 
     if subject_path.exists() {
-        return Err((
-            StatusCode::CONFLICT,
-            "Subject already exists".to_string(),
-        ));
+        return Err(AppError::Conflict("Subject already exists".to_string()));
     }
 
     fs::create_dir_all(&subject_path).map_err(|e| {
         error!("Failed to create subject directory: {}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to create subject".to_string(),
-        )
+        AppError::Internal("Failed to create subject".to_string())
     })?;
 
     // Create the database file
@@ -698,10 +1209,7 @@ pub async fn create_subject(
         },
         Err(e) => {
             error!("Failed to create database file: {}", e);
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to initialize database file: {}", e)
-            ));
+            return Err(AppError::Internal(format!("Failed to initialize database file: {}", e)));
         }
     }
 
@@ -740,39 +1248,41 @@ pub async fn create_subject(
 
 pub async fn delete_subject(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<StatusCode, (StatusCode, String)> {
+) -> Result<StatusCode, AppError> {
     let subject = path.0;
+    if !is_valid_subject_name(&subject) {
+        return Err(AppError::BadRequest("Invalid subject name".to_string()));
+    }
+    caller.require(&subject, Role::Admin)?;
     let subject_path = state.data_dir.join(&subject);
 
     if !subject_path.exists() {
-        return Err((StatusCode::NOT_FOUND, "Subject not found".to_string()));
+        return Err(AppError::SubjectNotFound(subject));
     }
 
     // Delete the subject directory
     fs::remove_dir_all(&subject_path).map_err(|e| {
         error!("Failed to delete subject directory: {}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to delete subject".to_string(),
-        )
+        AppError::Internal("Failed to delete subject".to_string())
     })?;
 
     // Drop the schema in the database
     let conn = state.db_pool.get().map_err(|e| {
         error!("Failed to get DB connection: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error".to_string())
+        AppError::Database("Database connection error".to_string())
     })?;
 
     let drop_schema_sql = format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", subject);
     conn.execute(&drop_schema_sql, []).map_err(|e| {
         error!("Failed to drop database schema: {}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to drop database schema".to_string(),
-        )
+        AppError::Database("Failed to drop database schema".to_string())
     })?;
 
+    // A subject recreated under this name must not see the old results
+    state.result_cache.bump_version(&subject);
+
     // Refresh subjects list
     state.refresh_subjects().await.ok();
 
@@ -780,98 +1290,839 @@ pub async fn delete_subject(
 }
 
 // Schema
+// Spans every subject, so only keys that can read all of them may see it
 pub async fn get_schema(
     state: State<Arc<AppState>>,
-) -> Result<Json<String>, (StatusCode, String)> {
+    caller: Caller,
+) -> Result<Json<String>, AppError> {
+    caller.require_global(Role::Read)?;
+
     let schemas_ddl = state.get_schemas_ddl().await.map_err(|e| {
         error!("Failed to get schema DDL: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        AppError::Database(format!("Database error: {}", e))
     })?;
 
     Ok(Json(schemas_ddl))
 }
 
 // Export
-#[allow(unused)]
 pub async fn export_data(
     state: State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
+    path: Path<String>,
+    Query(params): Query<ExportRequest>,
+) -> Result<Response, AppError> {
+    let format = ExportFormat::from_name(&path.0)
+        .ok_or_else(|| AppError::BadRequest("Unsupported export format".to_string()))?;
+
+    // Work out what to export: a saved report, an ad-hoc query or a whole table
+    let (sql, file_stem, subject) = match (&params.report, &params.sql, &params.table) {
+        (Some(report_id), None, None) => {
+            let report = load_report(&state, report_id)?;
+            caller.require(report_scope(&report), Role::Read)?;
+
+            // A report saved for one subject only runs against it, reading another subject
+            // doesn't allow running its SQL there
+            let subject = match (report.subject, &params.subject) {
+                (Some(saved), Some(requested)) if saved != *requested => {
+                    return Err(AppError::BadRequest(format!(
+                        "Report '{}' belongs to subject '{}'",
+                        report.name, saved
+                    )));
+                }
+                (Some(saved), _) => Some(saved),
+                (None, requested) => requested.clone(),
+            };
+            (report.sql, report.name, subject)
+        }
+        (None, Some(sql), None) => (sql.clone(), "query".to_string(), params.subject.clone()),
+        (None, None, Some(table)) => (
+            format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")),
+            table.clone(),
+            params.subject.clone(),
+        ),
+        _ => {
+            return Err(AppError::BadRequest("Specify exactly one of report, sql or table".to_string()))
+        }
+    };
+
+    if let Err(e) = ensure_read_only(&sql, false) {
+        warn!("Rejected export query: {}", e);
+        return Err(AppError::QueryNotAllowed(e));
+    }
+
+    let subject = selected.or_explicit(subject)?.ok_or_else(|| {
+        AppError::BadRequest("No subject specified for export".to_string())
+    })?;
+
+    // Exporting arbitrary SQL is a query, exporting a table or saved report is a read
+    let role = if params.sql.is_some() { Role::Query } else { Role::Read };
+    caller.require(&subject, role)?;
+
+    let db_path = state.data_dir.join(&subject).join(format!("{}.duckdb", subject));
+    if !db_path.exists() {
+        return Err(AppError::SubjectNotFound(subject));
+    }
+
+    let export_dir = std::env::temp_dir().join("nl-cube-exports");
+    fs::create_dir_all(&export_dir).map_err(|e| {
+        error!("Failed to create export directory: {}", e);
+        AppError::Internal("Failed to prepare export".to_string())
+    })?;
+    let export_path = export_dir.join(format!(
+        "export-{}.{}",
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        format.extension()
+    ));
+
+    info!("Exporting {} from subject '{}' as {}", file_stem, subject, format.extension());
+
+    // Batches are written out as DuckDB produces them, so the result never has to fit in memory
+    let export_task = {
+        let export_path = export_path.clone();
+        tokio::task::spawn_blocking(move || -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+            let conn = open_for_query(&db_path, false)?;
+            export_query(&conn, &sql, format, &export_path)
+        })
+    };
+
+    match export_task.await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            error!("Export failed: {}", e);
+            let _ = fs::remove_file(&export_path);
+            return Err(AppError::BadRequest(format!("Export failed: {}", e)));
+        }
+        Err(e) => {
+            error!("Export task failed: {}", e);
+            let _ = fs::remove_file(&export_path);
+            return Err(AppError::Internal(format!("Export failed: {}", e)));
+        }
+    }
+
+    let file = tokio::fs::File::open(&export_path).await.map_err(|e| {
+        error!("Failed to open export file {}: {}", export_path.display(), e);
+        AppError::Internal("Failed to read export".to_string())
+    })?;
+
+    let safe_stem = file_stem
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    if let Ok(v) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"{}.{}\"",
+        safe_stem,
+        format.extension()
+    )) {
+        headers.insert(header::CONTENT_DISPOSITION, v);
+    }
+
+    // Stream the file back and remove it once the response body is finished with
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(ExportFile {
+        file,
+        path: export_path,
+    }));
+
+    Ok((StatusCode::OK, headers, body).into_response())
+}
+
+// Export file that is deleted once it has been streamed to the client
+struct ExportFile {
+    file: tokio::fs::File,
+    path: std::path::PathBuf,
+}
+
+impl tokio::io::AsyncRead for ExportFile {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        tokio::io::AsyncRead::poll_read(std::pin::Pin::new(&mut self.file), cx, buf)
+    }
+}
+
+impl Drop for ExportFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove export file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Running queries
+
+// Callers see and cancel their own queries, global admins see everyone's
+pub async fn list_running_queries(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Json<Vec<RunningQueryInfo>> {
+    let is_admin = caller.can(ALL_SUBJECTS, Role::Admin);
+    Json(
+        state
+            .running_queries
+            .list()
+            .into_iter()
+            .filter(|q| is_admin || q.user.as_deref() == caller.user())
+            .collect(),
+    )
+}
+
+pub async fn cancel_query(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<StatusCode, AppError> {
+    let not_running = || AppError::NotFound("Query is not running".to_string());
+
+    let query = state.running_queries.get(&path.0).ok_or_else(not_running)?;
+    if query.user.as_deref() != caller.user() {
+        caller.require_global(Role::Admin)?;
+    }
+
+    if state.running_queries.cancel(&path.0) {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(not_running())
+    }
+}
+
+// LLM providers, switching takes effect for the next query without a restart
+pub async fn get_llm(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<LlmSettings>, AppError> {
+    caller.require_global(Role::Read)?;
+    Ok(Json(llm_settings(&state).await))
+}
+
+pub async fn switch_llm(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    Json(payload): Json<SwitchLlmRequest>,
+) -> Result<Json<LlmSettings>, AppError> {
+    caller.require_global(Role::Admin)?;
+
+    let config = state
+        .config
+        .llm
+        .with_active(&payload.backend, &payload.model)
+        .ok_or_else(|| {
+            AppError::BadRequest(format!("No configured provider uses the '{}' backend", payload.backend))
+        })?;
+
+    state
+        .llm_manager
+        .write()
+        .await
+        .switch(&config)
+        .map_err(|error| AppError::Llm {
+            provider: payload.backend.clone(),
+            error,
+        })?;
+
+    Ok(Json(llm_settings(&state).await))
+}
+
+async fn llm_settings(state: &AppState) -> LlmSettings {
+    let active = state
+        .llm_manager
+        .read()
+        .await
+        .config()
+        .providers()
+        .into_iter()
+        .map(|provider| LlmProviderInfo {
+            backend: provider.backend,
+            model: provider.model,
+            api_url: provider.api_url,
+            installed_models: None,
+        })
+        .collect();
+
+    let mut configured = Vec::new();
+    for provider in state.config.llm.providers() {
+        let installed_models = if provider.backend == "ollama" {
+            let api_url = provider.api_url.as_deref().unwrap_or(ollama::DEFAULT_API_URL);
+            match ollama::installed_models(api_url).await {
+                Ok(models) => Some(models),
+                Err(e) => {
+                    warn!("Failed to list Ollama models at {}: {}", api_url, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        configured.push(LlmProviderInfo {
+            backend: provider.backend,
+            model: provider.model,
+            api_url: provider.api_url,
+            installed_models,
+        });
+    }
+
+    LlmSettings { active, configured }
+}
+
+// Saved reports on the subject, shown to the model as examples of answered questions
+fn prompt_examples(state: &AppState, subject: &str) -> Vec<PromptExample> {
+    const MAX_EXAMPLES: usize = 3;
+
+    let mut reports = match state.report_store.list(Some(subject), None) {
+        Ok(reports) => reports,
+        Err(e) => {
+            warn!("Failed to load example reports for subject '{}': {}", subject, e);
+            return Vec::new();
+        }
+    };
+    reports.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+
+    reports
+        .into_iter()
+        .filter_map(|report| {
+            Some(PromptExample {
+                question: report.question?,
+                sql: report.sql,
+            })
+        })
+        .take(MAX_EXAMPLES)
+        .collect()
+}
+
+// Prompt templates, edits apply to the next query
+pub async fn list_prompts(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<Vec<PromptTemplate>>, AppError> {
+    caller.require_global(Role::Read)?;
+    Ok(Json(state.prompts.list()))
+}
+
+pub async fn get_prompt(
+    state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<(StatusCode, String), (StatusCode, String)> {
-    // This is a placeholder - in a real implementation, we would:
-    // 1. Accept query parameters to determine what to export
-    // 2. Generate the appropriate export format (CSV, JSON, Parquet)
-    // 3. Stream the result back to the client
+) -> Result<Json<PromptTemplate>, AppError> {
+    caller.require_global(Role::Read)?;
+    state
+        .prompts
+        .get(&path.0)
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound("Prompt template not found".to_string()))
+}
 
-    let format = path.0;
-    match format.as_str() {
-        "csv" | "json" | "parquet" => {
-            Err((StatusCode::NOT_IMPLEMENTED, format!("Export to {} not yet implemented", format)))
+pub async fn save_prompt(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+    Json(payload): Json<SavePromptRequest>,
+) -> Result<Json<PromptTemplate>, AppError> {
+    caller.require_global(Role::Admin)?;
+
+    let name = path.0;
+    if !prompts::is_valid_name(&name) {
+        return Err(AppError::BadRequest(
+            "Prompt templates are named default, provider.<backend> or subject.<subject>".to_string(),
+        ));
+    }
+    prompts::validate(&payload.template)
+        .map_err(|e| AppError::BadRequest(format!("Invalid template: {}", e)))?;
+
+    let template = state.prompts.save(&name, &payload.template).map_err(|e| {
+        error!("Failed to save prompt template {}: {}", name, e);
+        AppError::Internal("Failed to save prompt template".to_string())
+    })?;
+    forget_cached_sql(&state).await;
+    Ok(Json(template))
+}
+
+// Goes back to the file or built-in version of the template
+pub async fn delete_prompt(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<StatusCode, AppError> {
+    caller.require_global(Role::Admin)?;
+
+    match state.prompts.remove(&path.0) {
+        Ok(true) => {
+            forget_cached_sql(&state).await;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err(AppError::NotFound("Prompt template has no edits".to_string())),
+        Err(e) => {
+            error!("Failed to remove prompt template {}: {}", path.0, e);
+            Err(AppError::Internal("Failed to remove prompt template".to_string()))
         }
-        _ => Err((StatusCode::BAD_REQUEST, "Unsupported export format".to_string())),
     }
 }
 
+// SQL cached under the old template would otherwise keep being served as if it came from the new one
+async fn forget_cached_sql(state: &AppState) {
+    if let Some(cache) = state.llm_manager.read().await.cache()
+        && let Err(e) = cache.purge()
+    {
+        warn!("Failed to purge SQL cache after a prompt template change: {}", e);
+    }
+}
+
+// The prompt a question would be sent with, without calling the LLM
+pub async fn preview_prompt(
+    State(app_state): State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
+    Json(payload): Json<PromptPreviewRequest>,
+) -> Result<Json<PromptPreview>, AppError> {
+    let requested = selected.or_explicit(payload.subject.clone())?;
+    let subject = determine_query_subject(&app_state, &caller, requested).await?;
+    caller.require(&subject, Role::Read)?;
+
+    let schema = app_state
+        .get_table_metadata(Some(&subject))
+        .await
+        .map_err(|e| AppError::Database(format!("Failed to read the schema: {}", e)))?;
+    let backend = match payload.backend {
+        Some(backend) => backend,
+        None => app_state.llm_manager.read().await.config().backend.clone(),
+    };
+
+    let context = PromptContext {
+        question: payload.question,
+        schema,
+        examples: prompt_examples(&app_state, &subject),
+        subject: Some(subject),
+        ..PromptContext::default()
+    };
+
+    let (name, template) = match payload.template {
+        Some(template) => (None, template),
+        None => {
+            let resolved = app_state.prompts.resolve(context.subject.as_deref(), &backend);
+            (Some(resolved.name), resolved.template)
+        }
+    };
+
+    let prompt = prompts::render_template(&template, &context)
+        .map_err(|e| AppError::BadRequest(format!("Template failed to render: {}", e)))?;
+    Ok(Json(PromptPreview { name, prompt }))
+}
+
+// Forget every cached NL-to-SQL translation
+pub async fn purge_sql_cache(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<serde_json::Value>, AppError> {
+    caller.require_global(Role::Admin)?;
+
+    let llm = state.llm_manager.read().await;
+    let Some(cache) = llm.cache() else {
+        return Ok(Json(serde_json::json!({ "purged": 0 })));
+    };
+
+    let purged = cache.purge().map_err(|e| {
+        error!("Failed to purge SQL cache: {}", e);
+        AppError::Internal("Failed to purge SQL cache".to_string())
+    })?;
+
+    Ok(Json(serde_json::json!({ "purged": purged })))
+}
+
+// Query history
+
+pub async fn list_history(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    Query(params): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, AppError> {
+    // Callers limited to some subjects only see the queries run against those
+    let visible_subjects = if caller.can(ALL_SUBJECTS, Role::Query) {
+        None
+    } else {
+        Some(
+            state
+                .subjects
+                .read()
+                .await
+                .iter()
+                .filter(|s| caller.can(s, Role::Query))
+                .cloned()
+                .collect(),
+        )
+    };
+
+    let filter = HistoryFilter {
+        search: params.q.filter(|q| !q.trim().is_empty()),
+        subject: params.subject,
+        user: params.user,
+        visible_subjects,
+    };
+    let limit = params.limit.unwrap_or(50).clamp(1, MAX_HISTORY_PAGE);
+    let offset = params.offset.unwrap_or(0);
+
+    let (entries, total) = state.history_store.list(&filter, limit, offset).map_err(|e| {
+        error!("Failed to list query history: {}", e);
+        AppError::Internal("Failed to list query history".to_string())
+    })?;
+
+    Ok(Json(HistoryPage {
+        entries,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+fn load_history_entry(
+    state: &AppState,
+    caller: &Caller,
+    id: &str,
+) -> Result<QueryHistoryItem, AppError> {
+    let entry = match state.history_store.get(id) {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return Err(AppError::NotFound("History entry not found".to_string())),
+        Err(e) => {
+            error!("Failed to load history entry {}: {}", id, e);
+            return Err(AppError::Internal("Failed to load history entry".to_string()));
+        }
+    };
+
+    caller.require(entry.subject.as_deref().unwrap_or(ALL_SUBJECTS), Role::Query)?;
+    Ok(entry)
+}
+
+pub async fn get_history_entry(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<Json<QueryHistoryItem>, AppError> {
+    Ok(Json(load_history_entry(&state, &caller, &path.0)?))
+}
+
+// Runs the entry's SQL again against the same subject, the new run gets its own history entry
+pub async fn replay_history_entry(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<Response, AppError> {
+    let entry = load_history_entry(&state, &caller, &path.0)?;
+
+    let Some(sql) = entry.sql else {
+        return Err(AppError::BadRequest("This entry never produced SQL, ask the question again instead".to_string()));
+    };
+
+    let payload = ExecuteQueryRequest {
+        query: sql,
+        subject: entry.subject,
+        limit: None,
+        offset: None,
+    };
+    execute_query(
+        state,
+        caller,
+        SelectedSubject::default(),
+        QueryId::generate(),
+        HeaderMap::new(),
+        Json(payload),
+    )
+    .await
+}
+
 // Reports
-#[allow(unused)]
+
+// Reports without a subject aren't tied to one database, so they need access to all of them
+fn report_scope(report: &Report) -> &str {
+    report.subject.as_deref().unwrap_or(ALL_SUBJECTS)
+}
+
+fn load_report(state: &AppState, id: &str) -> Result<Report, AppError> {
+    match state.report_store.get(id) {
+        Ok(Some(report)) => Ok(report),
+        Ok(None) => Err(AppError::NotFound("Report not found".to_string())),
+        Err(e) => {
+            error!("Failed to load report {}: {}", id, e);
+            Err(AppError::Internal("Failed to load report".to_string()))
+        }
+    }
+}
+
 pub async fn list_reports(
     state: State<Arc<AppState>>,
-) -> Result<Json<Vec<Report>>, (StatusCode, String)> {
-    // Placeholder - in a real app, load from database
-    let reports: Vec<Report> = Vec::new();
-    Ok(Json(reports))
+    caller: Caller,
+    Query(filter): Query<ReportFilter>,
+) -> Result<Json<Vec<Report>>, AppError> {
+    let reports = state
+        .report_store
+        .list(filter.subject.as_deref(), filter.category.as_deref())
+        .map_err(|e| {
+            error!("Failed to list reports: {}", e);
+            AppError::Internal("Failed to list reports".to_string())
+        })?;
+
+    Ok(Json(
+        reports
+            .into_iter()
+            .filter(|r| caller.can(report_scope(r), Role::Read))
+            .collect(),
+    ))
 }
 
-#[allow(unused)]
 pub async fn get_report(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
-) -> Result<Json<Report>, (StatusCode, String)> {
-    // Placeholder - in a real app, load from database
-    Err((StatusCode::NOT_FOUND, "Report not found".to_string()))
+) -> Result<Json<Report>, AppError> {
+    let report = load_report(&state, &path.0)?;
+    caller.require(report_scope(&report), Role::Read)?;
+    Ok(Json(report))
 }
 
-#[allow(unused)]
 pub async fn save_report(
     state: State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
     Json(payload): Json<SaveReportRequest>,
-) -> Result<Json<Report>, (StatusCode, String)> {
-    // Placeholder - in a real app, save to database
-    let id = format!("report-{}", chrono::Utc::now().timestamp());
-    let now = chrono::Utc::now().to_rfc3339();
+) -> Result<(StatusCode, Json<Report>), AppError> {
+    if payload.name.trim().is_empty() || payload.category.trim().is_empty() {
+        return Err(AppError::BadRequest("Name and category are required".to_string()));
+    }
 
-    Ok(Json(Report {
-        id,
+    // Reports belong to the subject they were run against unless one is given
+    let subject = selected.or_explicit(payload.subject)?;
+    caller.require(subject.as_deref().unwrap_or(ALL_SUBJECTS), Role::Query)?;
+
+    let report = state
+        .report_store
+        .create(NewReport {
+            name: payload.name,
+            category: payload.category,
+            question: payload.question,
+            sql: payload.sql,
+            config: payload.config,
+            subject,
+        })
+        .map_err(|e| {
+            error!("Failed to save report: {}", e);
+            AppError::Internal("Failed to save report".to_string())
+        })?;
+
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn update_report(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+    Json(payload): Json<UpdateReportRequest>,
+) -> Result<Json<Report>, AppError> {
+    let id = path.0;
+
+    // Both the report's current subject and any it moves to must be queryable
+    let existing = load_report(&state, &id)?;
+    caller.require(report_scope(&existing), Role::Query)?;
+    if let Some(subject) = &payload.subject {
+        caller.require(subject, Role::Query)?;
+    }
+
+    if payload.name.as_deref().is_some_and(|n| n.trim().is_empty())
+        || payload.category.as_deref().is_some_and(|c| c.trim().is_empty())
+    {
+        return Err(AppError::BadRequest("Name and category can't be empty".to_string()));
+    }
+
+    let changes = ReportChanges {
         name: payload.name,
         category: payload.category,
         question: payload.question,
         sql: payload.sql,
         config: payload.config,
-        created_at: now.clone(),
-        updated_at: now,
-    }))
+        subject: payload.subject,
+    };
+
+    match state.report_store.update(&id, changes) {
+        Ok(Some(report)) => Ok(Json(report)),
+        Ok(None) => Err(AppError::NotFound("Report not found".to_string())),
+        Err(e) => {
+            error!("Failed to update report {}: {}", id, e);
+            Err(AppError::Internal("Failed to update report".to_string()))
+        }
+    }
 }
 
-pub async fn delete_report() -> Result<StatusCode, (StatusCode, String)> {
-    // Placeholder - in a real app, delete from database
-    Err((StatusCode::NOT_FOUND, "Report not found".to_string()))
+pub async fn delete_report(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<StatusCode, AppError> {
+    let id = path.0;
+
+    let existing = load_report(&state, &id)?;
+    caller.require(report_scope(&existing), Role::Query)?;
+
+    match state.report_store.delete(&id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(AppError::NotFound("Report not found".to_string())),
+        Err(e) => {
+            error!("Failed to delete report {}: {}", id, e);
+            Err(AppError::Internal("Failed to delete report".to_string()))
+        }
+    }
+}
+
+// Ingest jobs
+pub async fn list_jobs(state: State<Arc<AppState>>, caller: Caller) -> Json<Vec<IngestJob>> {
+    Json(
+        state
+            .ingest_jobs
+            .list()
+            .into_iter()
+            .filter(|job| caller.can(&job.subject, Role::Read))
+            .collect(),
+    )
+}
+
+pub async fn get_job(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<Json<IngestJob>, AppError> {
+    let job = state
+        .ingest_jobs
+        .get(&path.0)
+        .ok_or(AppError::NotFound("Job not found".to_string()))?;
+    caller.require(&job.subject, Role::Read)?;
+    Ok(Json(job))
+}
+
+// Server-sent events with the job's state after every change, ending once it has finished
+pub async fn job_events(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, AppError> {
+    let id = path.0;
+
+    // Subscribe before taking the snapshot so no change falls in between
+    let updates = state.ingest_jobs.subscribe();
+    let job = state
+        .ingest_jobs
+        .get(&id)
+        .ok_or(AppError::NotFound("Job not found".to_string()))?;
+    caller.require(&job.subject, Role::Read)?;
+
+    let state = Arc::clone(&state);
+    let events = stream::unfold((Some(job), updates, false), move |(next, mut updates, finished)| {
+        let state = Arc::clone(&state);
+        let id = id.clone();
+        async move {
+            if finished {
+                return None;
+            }
+
+            let job = match next {
+                Some(job) => job,
+                None => loop {
+                    match updates.recv().await {
+                        Ok(job) if job.id == id => break job,
+                        Ok(_) => continue,
+                        // Missed some updates, the registry has the latest state
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                            break state.ingest_jobs.get(&id)?
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                    }
+                },
+            };
+
+            let finished = job.state.is_finished();
+            Some((Event::default().event("job").json_data(&job), (None, updates, finished)))
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// API keys
+pub async fn list_api_keys(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    caller.require_global(Role::Admin)?;
+
+    let keys = state.api_keys.list().map_err(|e| {
+        error!("Failed to list API keys: {}", e);
+        AppError::Internal("Failed to list API keys".to_string())
+    })?;
+
+    Ok(Json(keys))
+}
+
+pub async fn create_api_key(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), AppError> {
+    caller.require_global(Role::Admin)?;
+
+    if payload.name.trim().is_empty() {
+        return Err(AppError::BadRequest("Name is required".to_string()));
+    }
+    if let Some(subject) = payload
+        .roles
+        .keys()
+        .find(|s| s.as_str() != ALL_SUBJECTS && !is_valid_subject_name(s))
+    {
+        return Err(AppError::BadRequest(format!("Invalid subject name '{}'", subject)));
+    }
+
+    let (key, secret) = state
+        .api_keys
+        .create(payload.name.trim(), payload.roles)
+        .map_err(|e| {
+            error!("Failed to create API key: {}", e);
+            AppError::Internal("Failed to create API key".to_string())
+        })?;
+
+    Ok((StatusCode::CREATED, Json(CreatedApiKey { key, secret })))
+}
+
+pub async fn delete_api_key(
+    state: State<Arc<AppState>>,
+    caller: Caller,
+    path: Path<String>,
+) -> Result<StatusCode, AppError> {
+    caller.require_global(Role::Admin)?;
+    let id = path.0;
+
+    match state.api_keys.delete(&id) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Err(AppError::NotFound("API key not found".to_string())),
+        Err(e) => {
+            error!("Failed to delete API key {}: {}", id, e);
+            Err(AppError::Internal("Failed to delete API key".to_string()))
+        }
+    }
 }
 
 // System status
 pub async fn system_status(
     state: State<Arc<AppState>>,
-) -> Result<Json<SystemStatus>, (StatusCode, String)> {
+) -> Result<Json<SystemStatus>, AppError> {
     let now = chrono::Utc::now();
     let uptime = now.signed_duration_since(state.startup_time).num_seconds();
 
     let subject_count = state.subjects.read().await.len();
+    let llm_providers = state.llm_manager.read().await.provider_status();
 
     // Get table count from database (across all schemas)
     let conn = state.db_pool.get().map_err(|e| {
         error!("Failed to get DB connection: {}", e);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Database connection error".to_string(),
-        )
+        AppError::Database("Database connection error".to_string())
     })?;
 
     let mut stmt = conn.prepare("
@@ -879,12 +2130,17 @@ pub async fn system_status(
         WHERE table_schema NOT IN ('information_schema', 'pg_catalog', 'main')
     ").map_err(|e| {
         error!("Failed to prepare query: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
+        AppError::Database("Database error".to_string())
     })?;
 
     let table_count: i64 = stmt.query_row([], |row| row.get(0)).map_err(|e| {
         error!("Failed to get table count: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "Database error".to_string())
+        AppError::Database("Database error".to_string())
+    })?;
+
+    let report_count = state.report_store.count().map_err(|e| {
+        error!("Failed to get report count: {}", e);
+        AppError::Database("Database error".to_string())
     })?;
 
     Ok(Json(SystemStatus {
@@ -892,6 +2148,123 @@ pub async fn system_status(
         uptime_seconds: uptime,
         subject_count,
         table_count: table_count as usize,
-        report_count: 0, // Placeholder
+        report_count,
+        llm_providers,
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::db::db_pool::DuckDBConnectionManager;
+    use crate::db::multi_db_pool::MultiDbConnectionManager;
+    use crate::llm::LlmManager;
+    use r2d2::Pool;
+
+    // State over a fresh data directory holding the `sales` and `hr` subjects
+    fn test_state() -> Arc<AppState> {
+        let dir = std::env::temp_dir().join(format!("nl-cube-api-{}", uuid::Uuid::new_v4()));
+        for (subject, table) in [("sales", "orders"), ("hr", "salaries")] {
+            let subject_dir = dir.join(subject);
+            fs::create_dir_all(&subject_dir).unwrap();
+            let conn = duckdb::Connection::open(subject_dir.join(format!("{}.duckdb", subject))).unwrap();
+            conn.execute_batch(&format!("CREATE TABLE {} AS SELECT range AS id FROM range(3)", table))
+                .unwrap();
+        }
+
+        let mut config = AppConfig {
+            data_dir: dir.display().to_string(),
+            ..AppConfig::default()
+        };
+        config.database.connection_string = dir.join("nl-cube.db").display().to_string();
+        config.llm.backend = "ollama".to_string();
+
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(DuckDBConnectionManager::new(config.database.connection_string.clone()))
+            .unwrap();
+        let multi_db_manager = Arc::new(MultiDbConnectionManager::new(
+            config.database.connection_string.clone(),
+            dir.clone(),
+        ));
+        let llm_manager = LlmManager::new(&config.llm).unwrap();
+
+        let state = AppState::new_with_multi_db(config, pool, multi_db_manager, llm_manager, dir);
+        state.report_store.init().unwrap();
+        Arc::new(state)
+    }
+
+    fn save_report(state: &AppState, subject: Option<&str>) -> String {
+        let report = state
+            .report_store
+            .create(NewReport {
+                name: "Salaries".to_string(),
+                category: "hr".to_string(),
+                question: None,
+                sql: "SELECT * FROM salaries".to_string(),
+                config: serde_json::Value::Null,
+                subject: subject.map(str::to_string),
+            })
+            .unwrap();
+        report.id
+    }
+
+    async fn export_report(
+        state: &Arc<AppState>,
+        caller: Caller,
+        report: &str,
+        subject: Option<&str>,
+    ) -> Result<Response, AppError> {
+        export_data(
+            State(Arc::clone(state)),
+            caller,
+            SelectedSubject(None),
+            Path("csv".to_string()),
+            Query(ExportRequest {
+                report: Some(report.to_string()),
+                sql: None,
+                table: None,
+                subject: subject.map(str::to_string),
+            }),
+        )
+        .await
+    }
+
+    fn reader_of(subject: &str) -> Caller {
+        Caller::with_roles("reader", HashMap::from([(subject.to_string(), Role::Read)]))
+    }
+
+    #[tokio::test]
+    async fn exports_a_report_only_against_its_own_subject() {
+        let state = test_state();
+        let report = save_report(&state, Some("hr"));
+
+        // Reading sales doesn't allow running the hr report's SQL there
+        let moved = export_report(&state, reader_of("sales"), &report, Some("sales")).await;
+        assert!(matches!(moved, Err(AppError::Forbidden(_))), "{:?}", moved.err());
+        let unreadable = export_report(&state, reader_of("sales"), &report, None).await;
+        assert!(matches!(unreadable, Err(AppError::Forbidden(_))), "{:?}", unreadable.err());
+
+        // Even a reader of hr can't point it elsewhere
+        let moved = export_report(&state, reader_of("hr"), &report, Some("sales")).await;
+        assert!(matches!(moved, Err(AppError::BadRequest(_))), "{:?}", moved.err());
+
+        let exported = export_report(&state, reader_of("hr"), &report, Some("hr")).await.unwrap();
+        assert_eq!(exported.status(), StatusCode::OK);
+        let exported = export_report(&state, reader_of("hr"), &report, None).await.unwrap();
+        assert_eq!(exported.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn exports_reports_for_every_subject_only_to_readers_of_every_subject() {
+        let state = test_state();
+        let report = save_report(&state, None);
+
+        let denied = export_report(&state, reader_of("hr"), &report, Some("hr")).await;
+        assert!(matches!(denied, Err(AppError::Forbidden(_))), "{:?}", denied.err());
+
+        let exported = export_report(&state, reader_of(ALL_SUBJECTS), &report, Some("hr")).await.unwrap();
+        assert_eq!(exported.status(), StatusCode::OK);
+    }
+}
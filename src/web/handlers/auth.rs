@@ -0,0 +1,104 @@
+use crate::web::error::AppError;
+use crate::web::extract::Query;
+use crate::web::oidc::{cookie_value, OidcAuth, LOGIN_COOKIE, SESSION_COOKIE};
+use crate::web::state::AppState;
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::response::{AppendHeaders, Html, IntoResponse, Redirect, Response};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+fn oidc(state: &AppState) -> Result<&OidcAuth, AppError> {
+    state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound("Sign-in is not configured on this server".to_string()))
+}
+
+// Send the browser to the identity provider
+pub async fn login(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let oidc = oidc(&state)?;
+
+    let (auth_url, login_state) = oidc.begin_login().await.map_err(|e| {
+        error!("Failed to start sign-in: {}", e);
+        AppError::IdentityProvider(e)
+    })?;
+
+    Ok((
+        [(
+            header::SET_COOKIE,
+            oidc.cookie(LOGIN_COOKIE, &login_state, oidc.login_seconds()),
+        )],
+        Redirect::to(&auth_url),
+    )
+        .into_response())
+}
+
+// The provider redirects back here with an authorization code
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, AppError> {
+    let oidc = oidc(&state)?;
+
+    if let Some(error) = params.error {
+        warn!("Identity provider refused sign-in: {}", error);
+        return Err(AppError::Unauthorized(format!(
+            "Sign-in failed: {}",
+            params.error_description.unwrap_or(error)
+        )));
+    }
+
+    let (Some(code), Some(login_state)) = (params.code, params.state) else {
+        return Err(AppError::BadRequest("Missing code or state".to_string()));
+    };
+
+    // The state must match the one handed to this browser, or someone else started the sign-in
+    if cookie_value(&headers, LOGIN_COOKIE).as_deref() != Some(login_state.as_str()) {
+        return Err(AppError::BadRequest(
+            "Sign-in was not started from this browser".to_string(),
+        ));
+    }
+
+    let (session_id, _) = oidc.complete_login(&code, &login_state).await.map_err(|e| {
+        warn!("Sign-in failed: {}", e);
+        AppError::Unauthorized(format!("Sign-in failed: {}", e))
+    })?;
+
+    Ok((
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                oidc.cookie(SESSION_COOKIE, &session_id, oidc.session_seconds()),
+            ),
+            (header::SET_COOKIE, oidc.cookie(LOGIN_COOKIE, "", 0)),
+        ]),
+        Redirect::to("/"),
+    )
+        .into_response())
+}
+
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let oidc = oidc(&state)?;
+    oidc.end_session(&headers);
+
+    // Not redirecting to / here, the provider would just sign the user straight back in
+    Ok((
+        [(header::SET_COOKIE, oidc.cookie(SESSION_COOKIE, "", 0))],
+        Html("<html><body><h1>NL-Cube</h1><p>You have been signed out.</p><p><a href=\"/auth/login\">Sign in again</a></p></body></html>"),
+    )
+        .into_response())
+}
@@ -1,2 +1,3 @@
 pub mod api;
-pub mod ui;
+pub mod auth;
+pub mod ui;
\ No newline at end of file
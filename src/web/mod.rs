@@ -1,35 +1,42 @@
+pub mod auth;
+pub mod error;
+pub mod extract;
 pub mod handlers;
+pub mod oidc;
+pub mod query_id;
 pub mod routes;
 pub mod templates;
 pub mod static_files;
 pub mod state;
+pub mod subject;
 
 
 use crate::config::WebConfig;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
 use axum::Router;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tracing::info;
 
 use self::routes::api_routes;
+use self::routes::auth_routes;
 use self::routes::ui_routes;
+use self::error::AppError;
 use self::state::AppState;
 
 pub async fn run_server(config: WebConfig, app_state: Arc<AppState>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
-    // Create a specific multipart configuration with larger limits
-    let multipart_config = axum::extract::DefaultBodyLimit::max(250 * 1024 * 1024); // 250 MB limit
-
-    // Build the router with increased body limit for multipart forms
     let app = Router::new()
-        .merge(ui_routes())
-        .merge(api_routes())
+        .merge(ui_routes().route_layer(axum::middleware::from_fn_with_state(
+            Arc::clone(&app_state),
+            auth::require_login,
+        )))
+        .merge(auth_routes())
+        .merge(api_routes(config.max_upload_mb).route_layer(axum::middleware::from_fn_with_state(
+            Arc::clone(&app_state),
+            auth::require_api_key,
+        )))
         .fallback(fallback_handler)
-        .with_state(app_state)
-        .layer(tower_http::limit::RequestBodyLimitLayer::new(100 * 1024 * 1024)) // 100 MB global limit
-        .layer(multipart_config);
+        .with_state(app_state);
 
     // Parse the socket address
     let addr: SocketAddr = format!("{}:{}", config.host, config.port)
@@ -46,9 +53,6 @@ pub async fn run_server(config: WebConfig, app_state: Arc<AppState>) -> Result<(
 }
 
 // Fallback handler for unmatched routes
-async fn fallback_handler() -> impl IntoResponse {
-    (
-        StatusCode::NOT_FOUND,
-        "The requested resource was not found",
-    )
+async fn fallback_handler() -> AppError {
+    AppError::NotFound("The requested resource was not found".to_string())
 }
\ No newline at end of file
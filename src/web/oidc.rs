@@ -0,0 +1,508 @@
+use crate::config::OidcConfig;
+use crate::roles::Role;
+use axum::http::{header, HeaderMap, HeaderValue};
+use chrono::{DateTime, Duration, Utc};
+use openidconnect::core::{
+    CoreAuthenticationFlow, CoreClient, CoreGenderClaim, CoreProviderMetadata,
+};
+use openidconnect::{
+    AdditionalClaims, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet,
+    EndpointNotSet, EndpointSet, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse, UserInfoClaims,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+/// Cookie holding the id of a signed-in browser session
+pub const SESSION_COOKIE: &str = "nl_cube_session";
+
+// Ties the provider's redirect back to the browser that started the sign-in
+pub const LOGIN_COOKIE: &str = "nl_cube_login";
+
+// How long a user has to finish signing in at the provider
+const LOGIN_TIMEOUT_MINUTES: i64 = 10;
+
+// Client built from the provider's discovery document
+type OidcClient = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+// Most providers (Keycloak, Entra, Authentik, the usual mock servers) send group membership here
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct GroupClaims {
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl AdditionalClaims for GroupClaims {}
+
+/// A browser session created after signing in with the identity provider
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user: String,
+    pub roles: HashMap<String, Role>,
+    expires_at: DateTime<Utc>,
+}
+
+// State kept between sending the user to the provider and them coming back
+struct PendingLogin {
+    pkce_verifier: PkceCodeVerifier,
+    nonce: Nonce,
+    started_at: DateTime<Utc>,
+}
+
+/// Authorization-code sign-in against an OpenID Connect provider, with sessions kept in memory
+pub struct OidcAuth {
+    config: OidcConfig,
+    http: reqwest::Client,
+    client: OnceCell<OidcClient>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    sessions: RwLock<HashMap<String, Session>>,
+}
+
+impl OidcAuth {
+    pub fn new(config: OidcConfig) -> Self {
+        // Following redirects while talking to the provider would open the door to SSRF
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            config,
+            http,
+            client: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Provider URL to send the browser to, along with the state value to remember in a cookie
+    pub async fn begin_login(&self) -> Result<(String, String), String> {
+        let client = self.client().await?;
+
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = client
+            .authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .set_pkce_challenge(pkce_challenge);
+        for scope in &self.config.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (auth_url, csrf_token, nonce) = request.url();
+
+        let now = Utc::now();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| now - login.started_at < Duration::minutes(LOGIN_TIMEOUT_MINUTES));
+        pending.insert(
+            csrf_token.secret().clone(),
+            PendingLogin {
+                pkce_verifier,
+                nonce,
+                started_at: now,
+            },
+        );
+
+        Ok((auth_url.to_string(), csrf_token.secret().clone()))
+    }
+
+    /// Trade the code the provider redirected back with for a new session, returning its id
+    pub async fn complete_login(&self, code: &str, state: &str) -> Result<(String, Session), String> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| Utc::now() - login.started_at < Duration::minutes(LOGIN_TIMEOUT_MINUTES))
+            .ok_or("Sign-in expired or was not started here")?;
+
+        let client = self.client().await?;
+
+        let token_response = client
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .map_err(|e| format!("Provider has no token endpoint: {}", e))?
+            .set_pkce_verifier(pending.pkce_verifier)
+            .request_async(&self.http)
+            .await
+            .map_err(|e| format!("Failed to exchange authorization code: {}", e))?;
+
+        let id_token = token_response
+            .id_token()
+            .ok_or("Provider did not return an ID token")?;
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &pending.nonce)
+            .map_err(|e| format!("Invalid ID token: {}", e))?;
+
+        let email = claims.email().map(|e| e.as_str().to_string());
+        let user = claims
+            .preferred_username()
+            .map(|u| u.as_str().to_string())
+            .or_else(|| email.clone())
+            .unwrap_or_else(|| claims.subject().as_str().to_string());
+
+        // Groups aren't a standard ID token claim, so they come from the userinfo endpoint
+        let groups = match client.user_info(token_response.access_token().clone(), None) {
+            Ok(request) => {
+                let user_info: Result<UserInfoClaims<GroupClaims, CoreGenderClaim>, _> =
+                    request.request_async(&self.http).await;
+                match user_info {
+                    Ok(user_info) => user_info.additional_claims().groups.clone(),
+                    Err(e) => {
+                        warn!("Failed to fetch userinfo for {}: {}", user, e);
+                        Vec::new()
+                    }
+                }
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let session = Session {
+            roles: self.roles_for(email.as_deref(), &groups),
+            user,
+            expires_at: Utc::now() + Duration::hours(self.config.session_hours as i64),
+        };
+
+        if session.roles.is_empty() {
+            warn!("{} signed in but no roles are mapped to them", session.user);
+        }
+        info!("{} signed in", session.user);
+
+        let id = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let mut sessions = self.sessions.write().unwrap();
+        let now = Utc::now();
+        sessions.retain(|_, s| s.expires_at > now);
+        sessions.insert(id.clone(), session.clone());
+
+        Ok((id, session))
+    }
+
+    /// The live session whose cookie came with this request
+    pub fn session(&self, headers: &HeaderMap) -> Option<Session> {
+        let id = cookie_value(headers, SESSION_COOKIE)?;
+        let session = self.sessions.read().unwrap().get(&id).cloned()?;
+        (session.expires_at > Utc::now()).then_some(session)
+    }
+
+    pub fn end_session(&self, headers: &HeaderMap) {
+        if let Some(id) = cookie_value(headers, SESSION_COOKIE)
+            && let Some(session) = self.sessions.write().unwrap().remove(&id)
+        {
+            info!("{} signed out", session.user);
+        }
+    }
+
+    /// Set-Cookie value for `name`, an empty value with no lifetime clears it
+    pub fn cookie(&self, name: &str, value: &str, max_age_seconds: i64) -> HeaderValue {
+        // Only mark cookies Secure when the server is reached over https, so local setups work
+        let secure = if self.config.redirect_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+
+        HeaderValue::from_str(&format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+            name, value, max_age_seconds, secure
+        ))
+        .unwrap_or_else(|_| HeaderValue::from_static(""))
+    }
+
+    pub fn session_seconds(&self) -> i64 {
+        self.config.session_hours as i64 * 3600
+    }
+
+    pub fn login_seconds(&self) -> i64 {
+        LOGIN_TIMEOUT_MINUTES * 60
+    }
+
+    // Discovery happens on first use so the server can start before the provider is up
+    async fn client(&self) -> Result<&OidcClient, String> {
+        self.client
+            .get_or_try_init(|| async {
+                let issuer = IssuerUrl::new(self.config.issuer_url.clone())
+                    .map_err(|e| format!("Invalid issuer URL: {}", e))?;
+                let redirect = RedirectUrl::new(self.config.redirect_url.clone())
+                    .map_err(|e| format!("Invalid redirect URL: {}", e))?;
+
+                let metadata = CoreProviderMetadata::discover_async(issuer, &self.http)
+                    .await
+                    .map_err(|e| format!("OpenID Connect discovery failed: {}", e))?;
+
+                info!("Discovered OpenID Connect provider {}", self.config.issuer_url);
+
+                Ok(CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(self.config.client_id.clone()),
+                    self.config.client_secret.clone().map(ClientSecret::new),
+                )
+                .set_redirect_uri(redirect))
+            })
+            .await
+    }
+
+    // Start from the default roles and keep the highest role granted per subject
+    fn roles_for(&self, email: Option<&str>, groups: &[String]) -> HashMap<String, Role> {
+        let mut roles = self.config.default_roles.clone();
+
+        let identities = groups.iter().map(String::as_str).chain(email);
+        for identity in identities {
+            let Some(mapped) = self.config.role_mappings.get(identity) else {
+                continue;
+            };
+            for (subject, role) in mapped {
+                let granted = roles.entry(subject.clone()).or_insert(*role);
+                *granted = (*granted).max(*role);
+            }
+        }
+
+        roles
+    }
+}
+
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Form, Query, State};
+    use axum::http::StatusCode;
+    use axum::response::{IntoResponse, Redirect, Response};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use openidconnect::core::{CoreHmacKey, CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm};
+    use openidconnect::url::Url;
+    use openidconnect::{
+        Audience, EmptyAdditionalClaims, EndUserEmail, EndUserUsername, StandardClaims,
+        SubjectIdentifier,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    const CLIENT_ID: &str = "nl-cube";
+    // ID tokens are signed with HS256, which uses the client secret as the key
+    const CLIENT_SECRET: &str = "mock-idp-client-secret-of-reasonable-length";
+    const CODE: &str = "mock-authorization-code";
+
+    // A provider with a single user, ada, who is in the analysts group
+    #[derive(Default)]
+    struct MockIdp {
+        issuer: String,
+        nonce: Mutex<Option<String>>,
+    }
+
+    async fn start_idp() -> Arc<MockIdp> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let idp = Arc::new(MockIdp {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            ..MockIdp::default()
+        });
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(|| async { Json(json!({ "keys": [] })) }))
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .with_state(Arc::clone(&idp));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        idp
+    }
+
+    async fn discovery(State(idp): State<Arc<MockIdp>>) -> Json<serde_json::Value> {
+        Json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "userinfo_endpoint": format!("{}/userinfo", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["HS256"],
+        }))
+    }
+
+    // Signs the user straight in and sends the browser back with a code
+    async fn authorize(
+        State(idp): State<Arc<MockIdp>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Redirect {
+        *idp.nonce.lock().unwrap() = params.get("nonce").cloned();
+        Redirect::to(&format!(
+            "{}?code={}&state={}",
+            params["redirect_uri"], CODE, params["state"]
+        ))
+    }
+
+    async fn token(
+        State(idp): State<Arc<MockIdp>>,
+        Form(params): Form<HashMap<String, String>>,
+    ) -> Response {
+        if params.get("code").map(String::as_str) != Some(CODE) || !params.contains_key("code_verifier") {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))).into_response();
+        }
+
+        let claims = CoreIdTokenClaims::new(
+            IssuerUrl::new(idp.issuer.clone()).unwrap(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            Utc::now() + Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new("user-1".to_string()))
+                .set_preferred_username(Some(EndUserUsername::new("ada".to_string())))
+                .set_email(Some(EndUserEmail::new("ada@example.com".to_string()))),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(idp.nonce.lock().unwrap().clone().map(Nonce::new));
+        let id_token = CoreIdToken::new(
+            claims,
+            &CoreHmacKey::new(CLIENT_SECRET),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            None,
+            None,
+        )
+        .unwrap();
+
+        Json(json!({
+            "access_token": "mock-access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token.to_string(),
+        }))
+        .into_response()
+    }
+
+    async fn userinfo() -> Json<serde_json::Value> {
+        Json(json!({ "sub": "user-1", "groups": ["analysts"] }))
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer_url: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            redirect_url: "http://localhost:3000/auth/callback".to_string(),
+            scopes: vec!["email".to_string(), "profile".to_string()],
+            session_hours: 8,
+            default_roles: HashMap::from([("*".to_string(), Role::Read)]),
+            role_mappings: HashMap::from([
+                ("analysts".to_string(), HashMap::from([("sales".to_string(), Role::Query)])),
+                (
+                    "ada@example.com".to_string(),
+                    HashMap::from([("sales".to_string(), Role::Upload), ("hr".to_string(), Role::Query)]),
+                ),
+                ("admins".to_string(), HashMap::from([("*".to_string(), Role::Admin)])),
+            ]),
+        }
+    }
+
+    fn session_cookie(id: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; {}={}", SESSION_COOKIE, id)).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn signs_in_with_the_provider_and_out_again() {
+        let idp = start_idp().await;
+        let auth = OidcAuth::new(config(&idp.issuer));
+
+        let (auth_url, state) = auth.begin_login().await.unwrap();
+        assert!(auth_url.starts_with(&format!("{}/authorize?", idp.issuer)));
+
+        // Follow the browser to the provider and back to the callback
+        let redirect = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+            .get(&auth_url)
+            .send()
+            .await
+            .unwrap();
+        let callback = Url::parse(redirect.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+        let params: HashMap<String, String> = callback.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], state);
+
+        let (id, session) = auth.complete_login(&params["code"], &params["state"]).await.unwrap();
+        assert_eq!(session.user, "ada");
+        assert_eq!(
+            session.roles,
+            HashMap::from([
+                ("*".to_string(), Role::Read),
+                ("sales".to_string(), Role::Upload),
+                ("hr".to_string(), Role::Query),
+            ])
+        );
+
+        let headers = session_cookie(&id);
+        assert_eq!(auth.session(&headers).map(|s| s.user), Some("ada".to_string()));
+
+        auth.end_session(&headers);
+        assert!(auth.session(&headers).is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_callback_it_did_not_start() {
+        let idp = start_idp().await;
+        let auth = OidcAuth::new(config(&idp.issuer));
+
+        let (_, state) = auth.begin_login().await.unwrap();
+        assert!(auth.complete_login(CODE, "forged-state").await.is_err());
+
+        // Without the provider's redirect there is no nonce, so the ID token doesn't verify
+        let error = auth.complete_login(CODE, &state).await.unwrap_err();
+        assert!(error.contains("Invalid ID token"), "{}", error);
+
+        // The state can't be used twice
+        assert!(auth.complete_login(CODE, &state).await.is_err());
+    }
+
+    #[test]
+    fn maps_groups_and_email_to_the_highest_role_per_subject() {
+        let auth = OidcAuth::new(config("http://localhost"));
+
+        assert_eq!(
+            auth.roles_for(None, &[]),
+            HashMap::from([("*".to_string(), Role::Read)])
+        );
+        assert_eq!(
+            auth.roles_for(None, &["analysts".to_string(), "unknown".to_string()]),
+            HashMap::from([("*".to_string(), Role::Read), ("sales".to_string(), Role::Query)])
+        );
+        assert_eq!(
+            auth.roles_for(Some("someone@example.com"), &["admins".to_string()]),
+            HashMap::from([("*".to_string(), Role::Admin)])
+        );
+    }
+
+    #[test]
+    fn reads_cookies_by_name() {
+        let headers = session_cookie("abc");
+        assert_eq!(cookie_value(&headers, SESSION_COOKIE), Some("abc".to_string()));
+        assert_eq!(cookie_value(&headers, "theme"), Some("dark".to_string()));
+        assert_eq!(cookie_value(&headers, "missing"), None);
+    }
+}
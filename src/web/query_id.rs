@@ -0,0 +1,36 @@
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use std::convert::Infallible;
+
+/// Header carrying the id a query can be cancelled by, clients may pick their own
+pub const QUERY_ID_HEADER: &str = "x-query-id";
+
+/// Id for the query made by this request, from the query id header or newly generated
+#[derive(Debug, Clone)]
+pub struct QueryId(pub String);
+
+impl QueryId {
+    pub fn generate() -> Self {
+        QueryId(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for QueryId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Client ids show up in logs and URLs, so anything unusual is replaced
+        let from_header = parts
+            .headers
+            .get(QUERY_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+
+        Ok(from_header.map(QueryId).unwrap_or_else(QueryId::generate))
+    }
+}
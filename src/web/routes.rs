@@ -1,147 +1,200 @@
 use super::handlers;
 use super::state::AppState;
 use super::static_files::static_handler;
+use crate::ingest::jobs::{IngestJob, LoadedTable, StagedFile};
+use crate::ingest::{IngestOptions, WriteMode};
+use crate::roles::Role;
+use crate::web::auth::Caller;
+use crate::web::error::AppError;
+use crate::web::extract::{Json, Multipart, Path};
 use crate::web::handlers::api::NlQueryRequest;
+use crate::web::query_id::QueryId;
+use crate::web::subject::{is_valid_subject_name, SelectedSubject};
 use axum::response::IntoResponse;
 use axum::{
-    extract::{Multipart, Path, State},
-    http::StatusCode,
-    routing::{delete, get, post},
-    Json,
+    extract::{DefaultBodyLimit, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post, put},
     Router,
 };
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::oneshot;
 use tracing::{debug, error, info, warn};
 
-// This is a special handler that spawns a new task to handle file uploads
-// This avoids Send/Sync issues with DuckDB
+// Uploads are streamed into the subject directory and handed to a background ingest job,
+// the response carries the job so the client can follow its progress at /api/jobs/{id}
 async fn sync_upload_handler(
     state: State<Arc<AppState>>,
+    caller: Caller,
     path: Path<String>,
     multipart: Multipart,
-) -> Result<Json<Vec<String>>, (StatusCode, String)> {
-    info!("Starting file upload to subject: {}", path.0);
+) -> Result<(StatusCode, Json<IngestJob>), AppError> {
+    let subject = path.0;
+    info!("Starting file upload to subject: {}", subject);
 
-    // Create a oneshot channel for the result
-    let (tx, rx) = oneshot::channel();
+    if !is_valid_subject_name(&subject) {
+        return Err(AppError::BadRequest("Invalid subject name".to_string()));
+    }
+    caller.require(&subject, Role::Upload)?;
 
-    // Clone state since we need to move it into the new task
-    let state_clone = Arc::clone(&state);
-    let path_str = path.0.clone();
+    let subject_dir = state.data_dir.join(&subject);
+    if !subject_dir.exists() {
+        return Err(AppError::SubjectNotFound(subject));
+    }
 
     // Process the multipart form in the current thread
-    let mut multipart_data = multipart;
-
-    // Add boundaries and debug information to multipart extraction
-    info!("Extracting files from multipart form");
-    let result = try_extract_multipart(&mut multipart_data).await;
+    let Multipart(mut multipart_data) = multipart;
+    let max_bytes = state.config.web.max_upload_mb * 1024 * 1024;
 
-    match result {
-        Ok(extracted_files) => {
-            if extracted_files.is_empty() {
-                return Err((StatusCode::BAD_REQUEST, "No valid files found in upload".to_string()));
+    info!("Streaming files from multipart form");
+    let mut staged = Vec::new();
+    let options = match stream_multipart(&mut multipart_data, &subject_dir, max_bytes, &mut staged).await {
+        Ok(options) => options,
+        Err(e) => {
+            error!("Failed to extract multipart form: {}", e);
+            // Don't leave half-written uploads behind
+            for file in &staged {
+                let _ = tokio::fs::remove_file(&file.path).await;
             }
+            return Err(e);
+        }
+    };
 
-            info!("Successfully extracted {} files from multipart form", extracted_files.len());
+    if staged.is_empty() {
+        return Err(AppError::BadRequest("No valid files found in upload".to_string()));
+    }
 
-            // Spawn a blocking task to handle the upload with the extracted files
-            tokio::task::spawn_blocking(move || {
-                let rt = tokio::runtime::Handle::current();
+    // Only replace files already in the subject once the whole upload has arrived
+    move_into_place(&subject_dir, &mut staged).await?;
 
-                // Process the files in the blocking task
-                let result = rt.block_on(async {
-                    // Create a temporary directory to store the files
-                    let temp_dir = std::env::temp_dir().join("nl-cube-uploads");
-                    if let Err(e) = std::fs::create_dir_all(&temp_dir) {
-                        error!("Failed to create temp directory: {}", e);
-                        return Err((StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("Failed to create temporary directory: {}", e)));
-                    }
-                    info!("Created temporary directory at {}", temp_dir.display());
-
-                    let mut file_paths = Vec::new();
-
-                    // Save files to disk
-                    for (file_name, content) in &extracted_files {
-                        let file_path = temp_dir.join(file_name);
-                        info!("Saving file to {}", file_path.display());
-
-                        match tokio::fs::write(&file_path, content).await {
-                            Ok(_) => {
-                                info!("Successfully saved file: {}", file_name);
-                                file_paths.push(file_path);
-                            }
-                            Err(e) => {
-                                error!("Failed to save file {}: {}", file_name, e);
-                                return Err((StatusCode::INTERNAL_SERVER_ERROR,
-                                            format!("Failed to save file {}: {}", file_name, e)));
-                            }
-                        }
-                    }
+    info!("Successfully received {} files from multipart form", staged.len());
 
-                    if file_paths.is_empty() {
-                        return Err((StatusCode::BAD_REQUEST, "No files were saved".to_string()));
-                    }
+    let job = state.ingest_jobs.create(&subject, &staged);
 
-                    // Now call the API handler with the saved files
-                    info!("Processing {} saved files", file_paths.len());
-                    let uploaded_files = match process_uploaded_files(state_clone, &path_str, &file_paths).await {
-                        Ok(files) => files,
-                        Err(e) => {
-                            error!("Failed to process uploaded files: {:?}", e);
-                            return Err(e);
-                        }
-                    };
-
-                    // Clean up temp files
-                    for path in file_paths {
-                        if let Err(e) = tokio::fs::remove_file(&path).await {
-                            warn!("Failed to remove temporary file {}: {}", path.display(), e);
-                        } else {
-                            debug!("Removed temporary file: {}", path.display());
-                        }
-                    }
+    // DuckDB connections aren't Send, so the job runs on a blocking thread
+    let state_clone = Arc::clone(&state);
+    let job_id = job.id.clone();
+    tokio::task::spawn_blocking(move || {
+        let rt = tokio::runtime::Handle::current();
+        rt.block_on(run_ingest_job(state_clone, &job_id, &subject, &staged, &options));
+    });
 
-                    info!("Upload processing completed successfully");
-                    Ok(Json(uploaded_files))
-                });
-
-                // Send the result back through the channel
-                let _ = tx.send(result);
-            });
-
-            // Wait for the result from the channel
-            match rx.await {
-                Ok(result) => result,
-                Err(e) => {
-                    error!("Channel error during upload processing: {}", e);
-                    Err((StatusCode::INTERNAL_SERVER_ERROR,
-                         "Failed to process upload: channel error".to_string()))
-                }
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+// Move every staged file over its final name in `dir`, all or nothing. Files being replaced are
+// kept aside until the last one is in place, so a failed move puts the subject back as it was.
+async fn move_into_place(dir: &std::path::Path, staged: &mut [StagedFile]) -> Result<(), AppError> {
+    // (index, final path, where the file it replaced was kept) for each file moved so far
+    let mut moved: Vec<(usize, std::path::PathBuf, Option<std::path::PathBuf>)> = Vec::new();
+    let mut failure = None;
+
+    for (index, file) in staged.iter().enumerate() {
+        let final_path = dir.join(&file.name);
+        let backup = dir.join(format!(".{}.{}.old", file.name, uuid::Uuid::new_v4()));
+
+        let replaced = match tokio::fs::rename(&final_path, &backup).await {
+            Ok(()) => Some(backup),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                failure = Some((file.name.clone(), e));
+                break;
             }
+        };
+
+        if let Err(e) = tokio::fs::rename(&file.path, &final_path).await {
+            if let Some(backup) = &replaced {
+                let _ = tokio::fs::rename(backup, &final_path).await;
+            }
+            failure = Some((file.name.clone(), e));
+            break;
         }
-        Err(e) => {
-            error!("Failed to extract multipart form: {}", e);
-            Err((StatusCode::BAD_REQUEST, format!("Failed to parse upload: {}", e)))
+        moved.push((index, final_path, replaced));
+    }
+
+    let Some((name, e)) = failure else {
+        for (index, final_path, replaced) in moved {
+            if let Some(backup) = replaced
+                && let Err(e) = tokio::fs::remove_file(&backup).await
+            {
+                warn!("Failed to remove replaced file {}: {}", backup.display(), e);
+            }
+            staged[index].path = final_path;
+        }
+        return Ok(());
+    };
+
+    error!("Failed to move upload {} into place, restoring the previous files: {}", name, e);
+    for (_, final_path, replaced) in moved.into_iter().rev() {
+        let restored = match &replaced {
+            Some(backup) => tokio::fs::rename(backup, &final_path).await,
+            None => tokio::fs::remove_file(&final_path).await,
+        };
+        if let Err(e) = restored {
+            error!("Failed to restore {}: {}", final_path.display(), e);
         }
     }
+    // Files that weren't moved are still partial files, the rest are already gone
+    for file in staged.iter() {
+        let _ = tokio::fs::remove_file(&file.path).await;
+    }
+
+    Err(AppError::Internal(format!("Failed to save file {}: {}", name, e)))
 }
 
-async fn try_extract_multipart(multipart: &mut Multipart) -> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut files = Vec::new();
+// Write each file field to a partial file in `dir` as it arrives, hashing it on the way.
+// Files are pushed to `staged` before they are written so the caller can clean up on error.
+async fn stream_multipart(
+    multipart: &mut axum::extract::Multipart,
+    dir: &std::path::Path,
+    max_bytes: u64,
+    staged: &mut Vec<StagedFile>,
+) -> Result<IngestOptions, AppError> {
+    let mut options = IngestOptions::default();
+    let mut total_bytes = 0u64;
+
+    let bad_upload = |e: axum::extract::multipart::MultipartError| {
+        let message = format!("Failed to parse upload: {}", e.body_text());
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(message)
+        } else {
+            AppError::BadRequest(message)
+        }
+    };
+    let io_error = |e: std::io::Error| {
+        AppError::Internal(format!("Failed to save upload: {}", e))
+    };
 
     // Process each field in the multipart form
-    while let Some(field) = multipart.next_field().await? {
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_upload)? {
         // Log the field name and content-type for debugging
         let name = field.name().unwrap_or("unnamed").to_string();
         let content_type = field.content_type().unwrap_or("").to_string();
         debug!("Processing field: {}, content-type: {}", name, content_type);
 
-        let file_name = match field.file_name() {
-            Some(name) => name.to_string(),
+        let file_name = match field.file_name().map(|n| n.to_string()) {
+            Some(name) => name,
             None => {
-                debug!("Skipping field without filename: {}", name);
+                // Plain form fields carry the upload options
+                let value = field.text().await.map_err(bad_upload)?;
+                match name.as_str() {
+                    "sheet" if !value.trim().is_empty() => options.sheet = Some(value.trim().to_string()),
+                    "mode" if !value.trim().is_empty() => {
+                        options.mode = value
+                            .parse::<WriteMode>()
+                            .map_err(AppError::Ingest)?
+                    }
+                    "key_columns" => {
+                        options.key_columns = value
+                            .split(',')
+                            .map(|k| k.trim().to_string())
+                            .filter(|k| !k.is_empty())
+                            .collect()
+                    }
+                    _ => debug!("Skipping field without filename: {}", name),
+                }
                 continue;
             }
         };
@@ -152,127 +205,93 @@ async fn try_extract_multipart(multipart: &mut Multipart) -> Result<Vec<(String,
             .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
             .collect::<String>();
 
-        debug!("Reading content for file: {}", safe_name);
+        let part_path = dir.join(format!(".{}.{}.part", safe_name, uuid::Uuid::new_v4()));
+        debug!("Streaming file {} to {}", safe_name, part_path.display());
 
-        // Read the field content with a more resilient approach
-        // Instead of using bytes(), let's build a buffered reader
-        // We'll use a temporary file to handle potentially large uploads
-        let temp_dir = std::env::temp_dir();
-        let temp_file_path = temp_dir.join(format!("nl-cube-upload-{}", safe_name));
-
-        // Create a file
-        let mut temp_file = tokio::fs::File::create(&temp_file_path).await?;
+        let mut file = tokio::fs::File::create(&part_path).await.map_err(io_error)?;
+        staged.push(StagedFile {
+            name: safe_name.clone(),
+            path: part_path,
+            bytes: 0,
+            sha256: String::new(),
+        });
 
-        // Read and write the data in chunks
-        let data = field.bytes().await?;
-        tokio::io::copy(&mut std::io::Cursor::new(data), &mut temp_file).await?;
+        let mut hasher = Sha256::new();
+        let mut bytes = 0u64;
 
-        // Now read the complete file back
-        let content = tokio::fs::read(&temp_file_path).await?;
+        while let Some(chunk) = field.chunk().await.map_err(bad_upload)? {
+            total_bytes += chunk.len() as u64;
+            if total_bytes > max_bytes {
+                return Err(AppError::PayloadTooLarge(format!(
+                    "Upload is larger than the {} MB limit",
+                    max_bytes / (1024 * 1024)
+                )));
+            }
 
-        // Clean up
-        let _ = tokio::fs::remove_file(&temp_file_path).await;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await.map_err(io_error)?;
+            bytes += chunk.len() as u64;
+        }
+        file.flush().await.map_err(io_error)?;
 
-        if content.is_empty() {
-            warn!("Extracted empty content for file: {}", safe_name);
-        } else {
-            debug!("Successfully read {} bytes for file {}", content.len(), safe_name);
+        if bytes == 0 {
+            warn!("Received empty file: {}", safe_name);
         }
 
-        // Store the file data
-        files.push((safe_name, content));
-    }
+        let sha256 = format!("{:x}", hasher.finalize());
+        debug!("Received {} bytes for file {} (sha256 {})", bytes, safe_name, sha256);
 
-    if files.is_empty() {
-        debug!("No files extracted from multipart form");
-    } else {
-        debug!("Extracted {} files from multipart form", files.len());
+        if let Some(staged_file) = staged.last_mut() {
+            staged_file.bytes = bytes;
+            staged_file.sha256 = sha256;
+        }
     }
 
-    Ok(files)
+    Ok(options)
 }
 
-async fn process_uploaded_files(
+async fn run_ingest_job(
     state: Arc<AppState>,
+    job_id: &str,
     subject: &str,
-    file_paths: &[std::path::PathBuf],
-) -> Result<Vec<String>, (StatusCode, String)> {
-    use tracing::{error, info};
-
-    // Verify the subject exists
-    let subject_path = state.data_dir.join(subject);
-    if !subject_path.exists() {
-        return Err((StatusCode::NOT_FOUND, "Subject not found".to_string()));
-    }
-
-    // Process all files
-    let mut uploaded_files: Vec<String> = Vec::new();
-    let ingest_manager = crate::ingest::IngestManager::with_connection_string();
+    files: &[StagedFile],
+    options: &IngestOptions,
+) {
+    let jobs = &state.ingest_jobs;
+    jobs.start(job_id);
 
-    for file_path in file_paths {
-        // Generate a table name based on file name only (not including subject prefix)
-        let table_name = file_path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let ingest_manager = crate::ingest::IngestManager::with_config(&state.config.ingest);
 
-        // Copy to the destination in the subject directory
-        let dest_path = subject_path.join(file_path.file_name().unwrap_or_default());
+    for (index, file) in files.iter().enumerate() {
+        jobs.start_file(job_id, index);
 
-        // Copy the file to the subject directory
-        if let Err(e) = tokio::fs::copy(file_path, &dest_path).await {
-            error!("Failed to copy file to subject directory: {}", e);
-            continue;
-        }
+        // Generate a table name based on file name only (not including subject prefix)
+        let table_name = file.path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let dest_path = &file.path;
 
         info!("Ingesting file to DuckDB. Subject: {}, Table: {}, File: {}",
               subject, table_name, dest_path.display());
 
-        // Use the ingest manager to create the table in the appropriate schema
-        match ingest_manager.ingest_file(&dest_path, &table_name, &subject) {
-            Ok(_) => {
-                info!("Successfully ingested table {}.{}", subject, table_name);
-                uploaded_files.push(table_name);
+        // Continue with other files even if one fails
+        match ingest_manager.ingest_file(dest_path, &table_name, subject, options) {
+            Ok(schemas) => {
+                let tables = schemas
+                    .into_iter()
+                    .map(|schema| {
+                        info!("Successfully ingested table {}.{}", subject, schema.name);
+                        LoadedTable { name: schema.name, rows: schema.rows_written }
+                    })
+                    .collect();
+                jobs.complete_file(job_id, index, tables);
             }
             Err(e) => {
                 error!("Failed to ingest file {}: {}", dest_path.display(), e);
-                // Continue with other files even if one fails
+                jobs.fail_file(job_id, index, e.to_string());
             }
         }
-    }
 
-    // Add a significant delay before running any diagnostics to allow DuckDB to stabilize
-    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-
-    // Run direct database diagnostic to check table existence
-    {
-        info!("Running database diagnostic...");
-
-        // Get a direct database connection
-        let conn = state.db_pool.get().map_err(|e| {
-            error!("Failed to get DB connection: {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error".to_string())
-        })?;
-
-        // Check tables in the specific schema
-        let check_sql = format!(
-            "SELECT table_name FROM information_schema.tables WHERE table_schema = '{}'",
-            subject
-        );
-
-        match conn.prepare(&check_sql) {
-            Ok(mut stmt) => {
-                match stmt.query_map([], |row| row.get::<_, String>(0)) {
-                    Ok(rows) => {
-                        let tables: Vec<String> = rows.filter_map(Result::ok).collect();
-                        info!("Found {} tables in schema {}: {:?}", tables.len(), subject, tables);
-                    }
-                    Err(e) => {
-                        error!("Error executing schema tables query: {}", e);
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Error preparing schema tables query: {}", e);
-            }
-        }
+        // Even a failed file may have replaced a table, so cached results can't be trusted
+        state.result_cache.bump_version(subject);
     }
 
     // Refresh the schema cache to make sure new tables are detected
@@ -280,16 +299,20 @@ async fn process_uploaded_files(
         error!("Error refreshing schema cache: {}", e);
     }
 
-    // Return the list of successfully uploaded and ingested files
-    Ok(uploaded_files)
+    jobs.finish(job_id);
+    info!("Ingest job {} finished", job_id);
 }
 
 // This is a special handler that spawns a blocking task to handle NL queries
 // This avoids Send/Sync issues with DuckDB connections
 async fn sync_nl_query_handler(
     state: State<Arc<AppState>>,
+    caller: Caller,
+    selected: SelectedSubject,
+    query_id: QueryId,
+    request_headers: HeaderMap,
     payload: Json<NlQueryRequest>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
+) -> Result<impl IntoResponse, AppError> {
     // Create a oneshot channel for the result
     let (tx, rx) = oneshot::channel();
 
@@ -304,7 +327,15 @@ async fn sync_nl_query_handler(
 
         // Run the nl_query handler in the blocking task
         let result = rt.block_on(async {
-            handlers::api::nl_query(State(state_clone), Json(payload_clone)).await
+            handlers::api::nl_query(
+                State(state_clone),
+                caller,
+                selected,
+                query_id,
+                request_headers,
+                Json(payload_clone),
+            )
+            .await
         });
 
         // Send the result back through the channel
@@ -314,10 +345,7 @@ async fn sync_nl_query_handler(
     // Wait for the result from the channel and convert to appropriate response
     match rx.await {
         Ok(result) => result,
-        Err(_) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to process natural language query".to_string()
-        ))
+        Err(_) => Err(AppError::Internal("Failed to process natural language query".to_string()))
     }
 }
 
@@ -328,8 +356,20 @@ pub fn ui_routes() -> Router<Arc<AppState>> {
         .route("/static/{*path}", get(static_handler))
 }
 
+// Sign-in with the configured OpenID Connect provider
+pub fn auth_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/auth/login", get(handlers::auth::login))
+        .route("/auth/callback", get(handlers::auth::callback))
+        .route("/auth/logout", get(handlers::auth::logout))
+}
+
 // API Routes - REST API for programmatic access
-pub fn api_routes() -> Router<Arc<AppState>> {
+pub fn api_routes(max_upload_mb: u64) -> Router<Arc<AppState>> {
+    // Only uploads may be large, every other body keeps axum's default limit. The upload's own
+    // limit replaces axum's so the two can't disagree.
+    let upload_limit = (max_upload_mb * 1024 * 1024) as usize;
+
     Router::new()
         .nest(
             "/api",
@@ -338,6 +378,21 @@ pub fn api_routes() -> Router<Arc<AppState>> {
                 .route("/query", post(handlers::api::execute_query))
                 // Use the sync handler for nl-query
                 .route("/nl-query", post(sync_nl_query_handler))
+                .route("/history", get(handlers::api::list_history))
+                .route("/history/{id}", get(handlers::api::get_history_entry))
+                .route("/history/{id}/replay", post(handlers::api::replay_history_entry))
+                .route("/llm", get(handlers::api::get_llm).put(handlers::api::switch_llm))
+                .route("/llm/cache", delete(handlers::api::purge_sql_cache))
+                .route("/prompts", get(handlers::api::list_prompts))
+                .route("/prompts/preview", post(handlers::api::preview_prompt))
+                .route(
+                    "/prompts/{name}",
+                    get(handlers::api::get_prompt)
+                        .put(handlers::api::save_prompt)
+                        .delete(handlers::api::delete_prompt),
+                )
+                .route("/queries", get(handlers::api::list_running_queries))
+                .route("/queries/{id}", delete(handlers::api::cancel_query))
 
                 // Data management
                 .route("/subjects", get(handlers::api::list_subjects))
@@ -347,7 +402,15 @@ pub fn api_routes() -> Router<Arc<AppState>> {
                 .route("/subjects/select/{subject}", post(handlers::api::select_subject))
 
                 // File upload and processing - using sync handler to avoid send issues
-                .route("/upload/{subject}", post(sync_upload_handler))
+                .route(
+                    "/upload/{subject}",
+                    post(sync_upload_handler)
+                        .route_layer(tower_http::limit::RequestBodyLimitLayer::new(upload_limit))
+                        .layer(DefaultBodyLimit::disable()),
+                )
+                .route("/jobs", get(handlers::api::list_jobs))
+                .route("/jobs/{id}", get(handlers::api::get_job))
+                .route("/jobs/{id}/events", get(handlers::api::job_events))
 
                 // Schema management
                 .route("/schema", get(handlers::api::get_schema))
@@ -359,9 +422,78 @@ pub fn api_routes() -> Router<Arc<AppState>> {
                 .route("/reports", get(handlers::api::list_reports))
                 .route("/reports/{id}", get(handlers::api::get_report))
                 .route("/reports", post(handlers::api::save_report))
+                .route("/reports/{id}", put(handlers::api::update_report))
                 .route("/reports/{id}", delete(handlers::api::delete_report))
 
+                // API key administration
+                .route("/admin/keys", get(handlers::api::list_api_keys))
+                .route("/admin/keys", post(handlers::api::create_api_key))
+                .route("/admin/keys/{id}", delete(handlers::api::delete_api_key))
+
                 // System status
                 .route("/status", get(handlers::api::system_status)),
         )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staged_file(dir: &std::path::Path, name: &str, contents: Option<&str>) -> StagedFile {
+        let path = dir.join(format!(".{}.part", name));
+        if let Some(contents) = contents {
+            std::fs::write(&path, contents).unwrap();
+        }
+        StagedFile {
+            name: name.to_string(),
+            path,
+            bytes: 0,
+            sha256: String::new(),
+        }
+    }
+
+    fn file_names(dir: &std::path::Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn moves_uploads_over_existing_files() {
+        let dir = std::env::temp_dir().join(format!("nl-cube-upload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("orders.csv"), "old").unwrap();
+
+        let mut staged = vec![
+            staged_file(&dir, "orders.csv", Some("new")),
+            staged_file(&dir, "regions.csv", Some("regions")),
+        ];
+        move_into_place(&dir, &mut staged).await.unwrap();
+
+        assert_eq!(file_names(&dir), ["orders.csv", "regions.csv"]);
+        assert_eq!(std::fs::read_to_string(dir.join("orders.csv")).unwrap(), "new");
+        assert_eq!(staged[0].path, dir.join("orders.csv"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restores_the_subject_when_a_move_fails() {
+        let dir = std::env::temp_dir().join(format!("nl-cube-upload-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("orders.csv"), "old").unwrap();
+
+        // The second file's partial file has gone missing, so it can't be moved
+        let mut staged = vec![
+            staged_file(&dir, "orders.csv", Some("new")),
+            staged_file(&dir, "products.csv", Some("products")),
+            staged_file(&dir, "regions.csv", None),
+        ];
+        assert!(move_into_place(&dir, &mut staged).await.is_err());
+
+        assert_eq!(file_names(&dir), ["orders.csv"]);
+        assert_eq!(std::fs::read_to_string(dir.join("orders.csv")).unwrap(), "old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
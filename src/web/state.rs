@@ -1,14 +1,21 @@
 use crate::config::AppConfig;
+use crate::db::api_key_store::ApiKeyStore;
 use crate::db::db_pool::DuckDBConnectionManager;
+use crate::db::history_store::HistoryStore;
 use crate::db::multi_db_pool::MultiDbConnectionManager;
+use crate::db::report_store::ReportStore;
+use crate::db::result_cache::ResultCache;
+use crate::db::running_queries::RunningQueries;
 use crate::db::schema_manager::SchemaManager;
+use crate::ingest::jobs::JobRegistry;
 // Add the new import
+use crate::llm::prompts::PromptTemplates;
 use crate::llm::LlmManager;
+use crate::web::oidc::OidcAuth;
 use minijinja::Environment;
 use r2d2::Pool;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::sync::RwLock;
 use tracing::{error, info};
 
@@ -16,13 +23,22 @@ use tracing::{error, info};
 pub struct AppState {
     pub config: AppConfig,
     pub db_pool: Pool<DuckDBConnectionManager>,
-    pub llm_manager: Arc<Mutex<LlmManager>>,
+    // Read locked while generating, so the status page doesn't wait for a running query
+    pub llm_manager: Arc<RwLock<LlmManager>>,
+    // Shared with the LLM manager, so edits apply to the next query without locking it
+    pub prompts: Arc<PromptTemplates>,
     pub data_dir: PathBuf,
     pub subjects: RwLock<Vec<String>>,
     pub startup_time: chrono::DateTime<chrono::Utc>,
-    pub current_subject: RwLock<Option<String>>,
     pub schema_manager: SchemaManager,
     pub multi_db_manager: Arc<MultiDbConnectionManager>, // Add this field
+    pub report_store: ReportStore,
+    pub history_store: HistoryStore,
+    pub result_cache: ResultCache,
+    pub running_queries: RunningQueries,
+    pub ingest_jobs: JobRegistry,
+    pub api_keys: ApiKeyStore,
+    pub oidc: Option<OidcAuth>,
 }
 
 impl AppState {
@@ -48,16 +64,29 @@ impl AppState {
             data_dir.clone(),
         );
 
+        let report_store = ReportStore::new(db_pool.clone());
+        let history_store = HistoryStore::new(db_pool.clone());
+        let api_keys = ApiKeyStore::new(db_pool.clone());
+        let oidc = config.auth.oidc.clone().map(OidcAuth::new);
+        let prompts = llm_manager.prompts();
+
         Self {
             config: config.clone(),
             db_pool,
-            llm_manager: Arc::new(Mutex::new(llm_manager)),
+            llm_manager: Arc::new(RwLock::new(llm_manager)),
+            prompts,
             data_dir,
             subjects: RwLock::new(Vec::new()),
             startup_time: chrono::Utc::now(),
-            current_subject: RwLock::new(None), // Initialize as None
             schema_manager,
             multi_db_manager, // Store the reference
+            report_store,
+            history_store,
+            result_cache: ResultCache::new(config.database.result_cache_mb),
+            running_queries: RunningQueries::new(config.database.query_timeout_secs),
+            ingest_jobs: JobRegistry::new(),
+            api_keys,
+            oidc,
         }
     }
 
@@ -65,41 +94,6 @@ impl AppState {
         &self.multi_db_manager
     }
 
-    pub async fn set_current_subject(&self, subject_name: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Check if the subject directory and database file exist, regardless of what's in the cache
-        let subject_dir = self.data_dir.join(subject_name);
-        let db_path = subject_dir.join(format!("{}.duckdb", subject_name));
-
-        if !db_path.exists() {
-            return Err(format!("Subject '{}' does not exist (database file not found)", subject_name).into());
-        }
-
-        info!("Setting current subject to: {} (database at {})", subject_name, db_path.display());
-
-        // Register with multi_db_manager
-        self.multi_db_manager.register_subject_db(
-            subject_name,
-            db_path.to_string_lossy().as_ref()
-        );
-        info!("Registered subject {} with multi-db manager", subject_name);
-
-        // Set as current subject
-        let mut current = self.current_subject.write().await;
-        *current = Some(subject_name.to_string());
-
-        // Ensure the subject is in the cached list as well
-        {
-            let mut subjects = self.subjects.write().await;
-            if !subjects.contains(&subject_name.to_string()) {
-                subjects.push(subject_name.to_string());
-                info!("Added subject {} to cached list", subject_name);
-            }
-        }
-
-        info!("Successfully set current subject to: {}", subject_name);
-        Ok(())
-    }
-
     // Refreshes available subjects (data directories)
     pub async fn refresh_subjects(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         // Scan the data directory for subject folders (which will be our databases)
@@ -111,13 +105,13 @@ impl AppState {
 
         while let Some(entry) = entries.next_entry().await? {
             let path = entry.path();
-            if path.is_dir() {
-                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                    // Check if this subject has a database file
-                    let db_path = path.join(format!("{}.duckdb", name));
-                    if db_path.exists() {
-                        subjects.push(name.to_string());
-                    }
+            if path.is_dir()
+                && let Some(name) = path.file_name().and_then(|n| n.to_str())
+            {
+                // Check if this subject has a database file
+                let db_path = path.join(format!("{}.duckdb", name));
+                if db_path.exists() {
+                    subjects.push(name.to_string());
                 }
             }
         }
@@ -164,15 +158,15 @@ impl AppState {
                     let mut create_table = format!("CREATE TABLE \"{}\".\"{}\" (\n", schema_name, table_name);
 
                     // Get column info
-                    let column_query = format!("
+                    let column_query = "
                         SELECT column_name, data_type, is_nullable
                         FROM information_schema.columns
                         WHERE table_schema = ? AND table_name = ?
                         ORDER BY ordinal_position
-                    ");
+                    ";
 
-                    let mut columns_stmt = conn.prepare(&column_query)?;
-                    let columns_iter = columns_stmt.query_map(&[schema_name, table_name], |row| {
+                    let mut columns_stmt = conn.prepare(column_query)?;
+                    let columns_iter = columns_stmt.query_map([schema_name, table_name], |row| {
                         Ok((
                             row.get::<_, String>(0)?, // column_name
                             row.get::<_, String>(1)?, // data_type
@@ -190,7 +184,7 @@ impl AppState {
                         if i < columns.len() - 1 {
                             create_table.push_str(",\n");
                         } else {
-                            create_table.push_str("\n");
+                            create_table.push('\n');
                         }
                     }
 
@@ -297,9 +291,9 @@ impl AppState {
                                                                data_type,
                                                                if *nullable { "" } else { " NOT NULL" }
                                     ));
-                                    metadata.push_str("\n");
+                                    metadata.push('\n');
                                 }
-                                metadata.push_str("\n");
+                                metadata.push('\n');
 
                                 // No need to add sample data - it's causing the panic
                                 // We'll just omit this feature for now
@@ -315,10 +309,10 @@ impl AppState {
                                         for i in 0..column_count {
                                             if let Ok(name) = stmt.column_name(i) {
                                                 metadata.push_str(&format!("- {} (UNKNOWN)", name));
-                                                metadata.push_str("\n");
+                                                metadata.push('\n');
                                             }
                                         }
-                                        metadata.push_str("\n");
+                                        metadata.push('\n');
                                     }
                                     Err(_) => {
                                         // Last resort - fall back to the default schema
@@ -358,10 +352,11 @@ fn get_tables_from_connection(conn: &duckdb::Connection) -> Result<Vec<String>,
             };
 
             for row in rows {
-                if let Ok(table_name) = row {
-                    if !table_name.starts_with("sqlite_") && !table_name.starts_with("duck_") {
-                        tables.push(table_name);
-                    }
+                if let Ok(table_name) = row
+                    && !table_name.starts_with("sqlite_")
+                    && !table_name.starts_with("duck_")
+                {
+                    tables.push(table_name);
                 }
             }
         }
@@ -375,10 +370,8 @@ fn get_tables_from_connection(conn: &duckdb::Connection) -> Result<Vec<String>,
                         Err(_) => return Ok(Vec::new()),
                     };
 
-                    for row in rows {
-                        if let Ok(table_name) = row {
-                            tables.push(table_name);
-                        }
+                    for table_name in rows.flatten() {
+                        tables.push(table_name);
                     }
                 }
                 Err(_) => {
@@ -390,10 +383,8 @@ fn get_tables_from_connection(conn: &duckdb::Connection) -> Result<Vec<String>,
                                 Err(_) => return Ok(Vec::new()),
                             };
 
-                            for row in rows {
-                                if let Ok(table_name) = row {
-                                    tables.push(table_name);
-                                }
+                            for table_name in rows.flatten() {
+                                tables.push(table_name);
                             }
                         }
                         Err(_) => { /* No more fallbacks */ }
@@ -406,8 +397,11 @@ fn get_tables_from_connection(conn: &duckdb::Connection) -> Result<Vec<String>,
     Ok(tables)
 }
 
+// Column name, data type and whether it is nullable
+type ColumnInfo = (String, String, bool);
+
 // Helper function to get column information
-fn get_column_info(conn: &duckdb::Connection, table_name: &str) -> Result<Vec<(String, String, bool)>, Box<dyn std::error::Error + Send + Sync>> {
+fn get_column_info(conn: &duckdb::Connection, table_name: &str) -> Result<Vec<ColumnInfo>, Box<dyn std::error::Error + Send + Sync>> {
     let mut columns = Vec::new();
 
     // Try with information_schema first
@@ -429,10 +423,8 @@ fn get_column_info(conn: &duckdb::Connection, table_name: &str) -> Result<Vec<(S
                 Err(_) => return Ok(Vec::new()),
             };
 
-            for row in rows {
-                if let Ok(column_info) = row {
-                    columns.push(column_info);
-                }
+            for column_info in rows.flatten() {
+                columns.push(column_info);
             }
         }
         Err(_) => {
@@ -452,10 +444,8 @@ fn get_column_info(conn: &duckdb::Connection, table_name: &str) -> Result<Vec<(S
                         Err(_) => return Ok(Vec::new()),
                     };
 
-                    for row in rows {
-                        if let Ok(column_info) = row {
-                            columns.push(column_info);
-                        }
+                    for column_info in rows.flatten() {
+                        columns.push(column_info);
                     }
                 }
                 Err(_) => {
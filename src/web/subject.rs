@@ -0,0 +1,95 @@
+use crate::web::error::AppError;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, HeaderValue};
+use std::convert::Infallible;
+
+/// Header API clients can use to pick the subject for a single request
+pub const SUBJECT_HEADER: &str = "x-nl-cube-subject";
+
+/// Cookie holding the subject selected in this browser session
+pub const SUBJECT_COOKIE: &str = "nl_cube_subject";
+
+/// The subject this request was made against, from the subject header or the session cookie
+#[derive(Debug, Clone, Default)]
+pub struct SelectedSubject(pub Option<String>);
+
+impl SelectedSubject {
+    /// A subject named in the request body wins over the header and session
+    pub fn or_explicit(self, explicit: Option<String>) -> Result<Option<String>, AppError> {
+        match explicit {
+            // Falling back to another subject would run the request somewhere the caller didn't ask for
+            Some(subject) if !is_valid_subject_name(&subject) => {
+                Err(AppError::BadRequest(format!("Invalid subject name '{}'", subject)))
+            }
+            Some(subject) => Ok(Some(subject)),
+            None => Ok(self.0),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for SelectedSubject {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let from_header = parts
+            .headers
+            .get(SUBJECT_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string());
+
+        let from_cookie = || {
+            parts
+                .headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| *name == SUBJECT_COOKIE)
+                .map(|(_, value)| value.trim().to_string())
+        };
+
+        let subject = from_header
+            .or_else(from_cookie)
+            .filter(|s| is_valid_subject_name(s));
+
+        Ok(SelectedSubject(subject))
+    }
+}
+
+/// Set-Cookie value that remembers `subject` for this browser session
+pub fn subject_cookie(subject: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax",
+        SUBJECT_COOKIE, subject
+    ))
+    .unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+// Subject names become directory names, so anything that could leave the data dir is ignored
+pub fn is_valid_subject_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_subject_wins_over_the_session() {
+        let selected = SelectedSubject(Some("sales".to_string()));
+        assert_eq!(selected.or_explicit(Some("hr".to_string())).unwrap().as_deref(), Some("hr"));
+
+        let selected = SelectedSubject(Some("sales".to_string()));
+        assert_eq!(selected.or_explicit(None).unwrap().as_deref(), Some("sales"));
+    }
+
+    #[test]
+    fn rejects_an_invalid_explicit_subject() {
+        let selected = SelectedSubject(Some("sales".to_string()));
+        assert!(matches!(
+            selected.or_explicit(Some("../hr".to_string())),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+}
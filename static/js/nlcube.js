@@ -37,6 +37,30 @@ let queryManager;
 let uploadManager;
 let reportsManager;
 
+// Ask for an API key when the server requires one. The key is kept in a cookie so
+// uploads and job event streams are authenticated along with regular fetches.
+// Servers with single sign-on send the browser back to the login page instead.
+const originalFetch = window.fetch.bind(window);
+window.fetch = async (url, options) => {
+    const response = await originalFetch(url, options);
+    if (response.status !== 401 || !String(url).startsWith(API_BASE_URL)) {
+        return response;
+    }
+
+    const loginUrl = response.headers.get('X-NL-Cube-Login');
+    if (loginUrl) {
+        window.location.href = loginUrl;
+        return response;
+    }
+
+    const key = window.prompt('This NL-Cube server requires an API key:');
+    if (!key) {
+        return response;
+    }
+    document.cookie = `nl_cube_api_key=${key.trim()}; path=/; SameSite=Strict`;
+    return originalFetch(url, options);
+};
+
 // Initialize app when DOM is ready
 document.addEventListener('DOMContentLoaded', initApp);
 
@@ -126,7 +150,9 @@ function initManagers() {
             const statusContainer = document.getElementById('uploadStatusMessages');
             const fileElement = document.createElement('div');
             fileElement.className = 'alert alert-success';
-            fileElement.textContent = `Successfully uploaded ${fileInfo.file}`;
+            const tables = fileInfo.response.files.flatMap(f => f.tables);
+            const rows = tables.reduce((sum, t) => sum + (t.rows || 0), 0);
+            fileElement.textContent = `Successfully uploaded ${fileInfo.file} (${tables.length} table(s), ${rows} rows written)`;
             statusContainer.appendChild(fileElement);
         },
         onError: (errorInfo) => {
@@ -355,6 +381,90 @@ async function fetchSubjectDetails(subjectName) {
     }
 }
 
+function showCancelButton(queryId) {
+    const cancelButton = document.getElementById('cancelQueryBtn');
+    if (!cancelButton) return;
+
+    cancelButton.classList.remove('d-none');
+    cancelButton.onclick = async () => {
+        const response = await fetch(`${API_BASE_URL}/queries/${queryId}`, { method: 'DELETE' });
+        if (response.status === 404) {
+            showToast('The query is still being generated and can\'t be cancelled yet', 'info');
+        }
+    };
+}
+
+function hideCancelButton() {
+    const cancelButton = document.getElementById('cancelQueryBtn');
+    if (!cancelButton) return;
+
+    cancelButton.classList.add('d-none');
+    cancelButton.onclick = null;
+}
+
+/**
+ * Read the message out of an API error response
+ * @param {Response} response - Response with a non-2xx status
+ * @returns {Promise<string>} The error message, or the raw body if it isn't an API error
+ */
+async function readErrorMessage(response) {
+    const text = await response.text();
+    try {
+        const error = JSON.parse(text);
+        return error.message || text;
+    } catch {
+        return text;
+    }
+}
+
+/**
+ * Split a multipart query response into its JSON metadata and Arrow data
+ * @param {Response} response - Response to a request sent with Accept: multipart/mixed
+ * @returns {Promise<{metadata: Object, arrowBuffer: ArrayBuffer}>}
+ */
+async function readQueryResult(response) {
+    const contentType = response.headers.get('content-type') || '';
+    const boundaryMatch = contentType.match(/boundary=([^;]+)/);
+    const body = new Uint8Array(await response.arrayBuffer());
+
+    // Older servers send the metadata in headers and the Arrow data as the whole body
+    if (!contentType.startsWith('multipart/mixed') || !boundaryMatch) {
+        return {
+            metadata: {
+                sql: response.headers.get('x-generated-sql') || '',
+                row_count: parseInt(response.headers.get('x-page-rows') || response.headers.get('x-total-count') || '0', 10),
+                execution_time_ms: parseInt(response.headers.get('x-execution-time') || '0', 10),
+                attempts: JSON.parse(response.headers.get('x-sql-attempts') || '[]'),
+                warnings: []
+            },
+            arrowBuffer: body.buffer
+        };
+    }
+
+    const encoder = new TextEncoder();
+    const delimiter = encoder.encode(`\r\n--${boundaryMatch[1]}`);
+    const headerEnd = encoder.encode('\r\n\r\n');
+
+    const indexOf = (needle, from) => {
+        outer: for (let i = from; i <= body.length - needle.length; i++) {
+            for (let j = 0; j < needle.length; j++) {
+                if (body[i + j] !== needle[j]) continue outer;
+            }
+            return i;
+        }
+        return -1;
+    };
+
+    // The JSON part comes first, the Arrow part runs up to the closing delimiter
+    const jsonStart = indexOf(headerEnd, 0) + headerEnd.length;
+    const jsonEnd = indexOf(delimiter, jsonStart);
+    const arrowStart = indexOf(headerEnd, jsonEnd + delimiter.length) + headerEnd.length;
+    const arrowEnd = body.length - (delimiter.length + '--\r\n'.length);
+
+    const metadata = JSON.parse(new TextDecoder().decode(body.subarray(jsonStart, jsonEnd)));
+    return { metadata, arrowBuffer: body.slice(arrowStart, arrowEnd).buffer };
+}
+
 async function handleNlQuery(e) {
     e.preventDefault();
 
@@ -367,29 +477,43 @@ async function handleNlQuery(e) {
         runButton.disabled = true;
         runButton.innerHTML = '<span class="spinner-border spinner-border-sm" role="status" aria-hidden="true"></span> Running...';
 
-        // Execute the query
+        // Pick the query id up front so the query can be cancelled while it runs
+        const queryId = crypto.randomUUID();
+        showCancelButton(queryId);
+
+        // Execute the query, asking for the metadata as JSON so multi-line SQL arrives intact
         const response = await fetch(`${API_BASE_URL}/nl-query`, {
             method: 'POST',
             headers: {
-                'Content-Type': 'application/json'
+                'Content-Type': 'application/json',
+                'Accept': 'multipart/mixed',
+                'X-Query-Id': queryId
             },
-            body: JSON.stringify({ question })
-        });
+            body: JSON.stringify({ question, subject: appState.currentSubject })
+        }).finally(hideCancelButton);
 
         if (!response.ok) {
-            const errorText = await response.text();
+            const errorText = await readErrorMessage(response);
             throw new Error(errorText || `Query failed with status: ${response.status}`);
         }
 
-        // Get metadata from headers
-        const generatedSql = response.headers.get('x-generated-sql') || '';
-        const totalCount = parseInt(response.headers.get('x-total-count') || '0', 10);
+        const { metadata, arrowBuffer } = await readQueryResult(response);
+        console.log('Received Arrow data:', arrowBuffer.byteLength, 'bytes');
+
+        const generatedSql = metadata.sql || '';
+        const totalCount = metadata.row_count || 0;
+        const executionTime = metadata.execution_time_ms || 0;
 
         // Update SQL display
         document.getElementById('generatedSqlDisplay').textContent = generatedSql;
 
-        // Get query execution time
-        const executionTime = parseInt(response.headers.get('x-execution-time') || '0', 10);
+        // Repaired SQL, truncated results and the like
+        if (metadata.attempts && metadata.attempts.length > 1) {
+            console.log('SQL generation attempts:', metadata.attempts);
+        }
+        for (const warning of metadata.warnings || []) {
+            showToast(warning, 'warning');
+        }
 
         // Update query history - now in the dropdown
         addToQueryHistory(question, generatedSql, executionTime, totalCount);
@@ -402,15 +526,7 @@ async function handleNlQuery(e) {
             rowCount: totalCount
         };
 
-        // Get the Arrow data
-        const arrowBuffer = await response.arrayBuffer();
-        console.log('Received Arrow data:', arrowBuffer.byteLength, 'bytes');
-
-        // Check content type to confirm it's Arrow data
-        const contentType = response.headers.get('content-type');
-        console.log('Content type:', contentType);
-
-        if (contentType === 'application/vnd.apache.arrow.file' && arrowBuffer.byteLength > 0) {
+        if (arrowBuffer.byteLength > 0) {
             try {
                 const success = await loadArrowData(arrowBuffer);
                 if (success) {
@@ -574,7 +690,7 @@ async function handleCreateSubject() {
         });
 
         if (!response.ok) {
-            const errorText = await response.text();
+            const errorText = await readErrorMessage(response);
             throw new Error(errorText || `Failed to create subject: ${response.statusText}`);
         }
 
@@ -630,7 +746,10 @@ async function handleFileUpload() {
         document.getElementById('uploadFilesSubmitBtn').disabled = true;
 
         // Use the upload manager to upload files
-        uploadManager.addToQueue(appState.currentSubject, fileInput.files);
+        const sheet = document.getElementById('sheetNameInput').value.trim();
+        const mode = document.getElementById('uploadModeSelect').value;
+        const key_columns = document.getElementById('keyColumnsInput').value.trim();
+        uploadManager.addToQueue(appState.currentSubject, fileInput.files, { sheet, mode, key_columns });
     } catch (error) {
         console.error('Upload error:', error);
 
@@ -663,7 +782,7 @@ async function selectSubject(subjectName) {
         });
 
         if (!response.ok) {
-            const errorText = await response.text();
+            const errorText = await readErrorMessage(response);
             throw new Error(errorText || `Failed to select subject: ${response.statusText}`);
         }
 
@@ -711,7 +830,8 @@ async function viewTable(tableName) {
                 'Content-Type': 'application/json'
             },
             body: JSON.stringify({
-                query: simpleQuery
+                query: simpleQuery,
+                subject: appState.currentSubject
             })
         });
 
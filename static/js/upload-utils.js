@@ -24,8 +24,9 @@ class UploadManager {
      * Add files to the upload queue for a specific subject
      * @param {string} subjectName - The subject to upload to
      * @param {FileList|Array} files - Files to upload
+     * @param {Object} options - Upload options sent with each file (sheet, mode, key_columns)
      */
-    addToQueue(subjectName, files) {
+    addToQueue(subjectName, files, options = {}) {
         this.fileCount = files.length;
         this.completedFiles = 0;
         this.totalProgress = 0;
@@ -37,7 +38,7 @@ class UploadManager {
             if (!this.isValidFileType(file)) {
                 this.onError({
                     file: file.name,
-                    error: 'Unsupported file type. Only CSV and Parquet are allowed.'
+                    error: 'Unsupported file type. Only CSV, Parquet, JSON and Excel are allowed.'
                 });
                 continue;
             }
@@ -45,6 +46,7 @@ class UploadManager {
             this.queue.push({
                 file,
                 subject: subjectName,
+                options,
                 progress: 0,
                 status: 'queued'
             });
@@ -84,6 +86,11 @@ class UploadManager {
     async uploadFile(uploadItem) {
         try {
             const formData = new FormData();
+            for (const [key, value] of Object.entries(uploadItem.options || {})) {
+                if (value) {
+                    formData.append(key, value);
+                }
+            }
             formData.append('file', uploadItem.file);
 
             // Create XMLHttpRequest for progress tracking
@@ -119,8 +126,8 @@ class UploadManager {
                         }
                     } else {
                         uploadItem.status = 'error';
-                        uploadItem.error = xhr.statusText;
-                        reject(new Error(`Upload failed: ${xhr.statusText}`));
+                        uploadItem.error = this.errorMessage(xhr);
+                        reject(new Error(`Upload failed: ${uploadItem.error}`));
                     }
                 };
 
@@ -133,13 +140,21 @@ class UploadManager {
                 xhr.send(formData);
             });
 
+            // The server loads the file in the background, wait for its ingest job to finish
+            uploadItem.status = 'ingesting';
+            const job = await this.waitForJob(result.id);
+            const failures = job.files.filter(f => f.state === 'failed');
+            if (failures.length > 0) {
+                throw new Error(failures.map(f => f.error).join('; '));
+            }
+
             // Call success callback
             this.onSuccess({
                 file: uploadItem.file.name,
-                response: result
+                response: job
             });
 
-            return result;
+            return job;
         } catch (error) {
             // Call error callback
             this.onError({
@@ -151,6 +166,36 @@ class UploadManager {
         }
     }
 
+    /**
+     * Follow an ingest job's events until it finishes
+     * @param {string} jobId - The job returned by the upload
+     * @returns {Promise<Object>} - The finished job
+     */
+    waitForJob(jobId) {
+        return new Promise((resolve, reject) => {
+            const events = new EventSource(`${this.baseUrl}/jobs/${jobId}/events`);
+
+            events.addEventListener('job', (event) => {
+                const job = JSON.parse(event.data);
+                if (job.state === 'completed' || job.state === 'failed') {
+                    events.close();
+                    resolve(job);
+                }
+            });
+
+            events.onerror = () => {
+                events.close();
+                // The stream closes once the job is done, check where it ended up
+                fetch(`${this.baseUrl}/jobs/${jobId}`)
+                    .then(response => response.ok ? response.json() : Promise.reject(new Error('Lost track of ingest job')))
+                    .then(job => (job.state === 'completed' || job.state === 'failed')
+                        ? resolve(job)
+                        : reject(new Error('Lost connection while the file was loading')))
+                    .catch(reject);
+            };
+        });
+    }
+
     /**
      * Update the total progress across all files
      */
@@ -176,6 +221,19 @@ class UploadManager {
         });
     }
 
+    /**
+     * Get the message from a failed upload's JSON error body
+     * @param {XMLHttpRequest} xhr - The finished request
+     * @returns {string} - The error message
+     */
+    errorMessage(xhr) {
+        try {
+            return JSON.parse(xhr.responseText).message || xhr.statusText;
+        } catch {
+            return xhr.responseText || xhr.statusText;
+        }
+    }
+
     /**
      * Check if the file type is supported
      * @param {File} file - The file to check
@@ -187,6 +245,10 @@ class UploadManager {
             'application/csv',
             'application/parquet',
             'application/vnd.apache.parquet',
+            'application/json',
+            'application/x-ndjson',
+            'application/vnd.openxmlformats-officedocument.spreadsheetml.sheet',
+            'application/vnd.ms-excel',
             // Allow files without MIME type but with correct extension
             ''
         ];
@@ -196,7 +258,7 @@ class UploadManager {
 
         // Also check extension
         const extension = file.name.split('.').pop().toLowerCase();
-        const isValidExtension = ['csv', 'parquet', 'pqt'].includes(extension);
+        const isValidExtension = ['csv', 'parquet', 'pqt', 'json', 'ndjson', 'jsonl', 'xlsx', 'xlsm', 'xls'].includes(extension);
 
         return isValidMime || isValidExtension;
     }